@@ -1,23 +1,92 @@
 struct pnctx* pollnet_init();
 struct pnctx* pollnet_get_or_init_static();
 void pollnet_shutdown(struct pnctx* ctx);
+void pollnet_add_tls_ca_pem(struct pnctx* ctx, const char* pem);
+void pollnet_set_tls_client_cert(struct pnctx* ctx, const char* cert_pem, const char* key_pem);
+// TCP and websocket clients only: HTTP requests always verify the url host
+void pollnet_set_tls_server_name(struct pnctx* ctx, const char* server_name);
+void pollnet_set_tls_insecure(struct pnctx* ctx, unsigned int insecure);
+void pollnet_clear_tls_options(struct pnctx* ctx);
 unsigned int pollnet_open_tcp(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_open_unix(struct pnctx* ctx, const char* path);
+unsigned int pollnet_listen_unix(struct pnctx* ctx, const char* path);
+unsigned int pollnet_resolve(struct pnctx* ctx, const char* hostname);
+unsigned int pollnet_resolve_records(struct pnctx* ctx, const char* name, const char* record_type);
+unsigned int pollnet_set_dns_server(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_set_proxy(struct pnctx* ctx, const char* url);
+void pollnet_set_proxy_from_env(struct pnctx* ctx, unsigned int enabled);
+unsigned int pollnet_timer(struct pnctx* ctx, unsigned int ms, unsigned int repeat);
+unsigned int pollnet_spawn_process(struct pnctx* ctx, const char* program, const char* args, const char* cwd);
+unsigned int pollnet_open_udp(struct pnctx* ctx, const char* bind_addr);
+unsigned int pollnet_open_udp_connected(struct pnctx* ctx, const char* bind_addr, const char* peer_addr);
 unsigned int pollnet_listen_tcp(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_open_tls(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_listen_tls(struct pnctx* ctx, const char* addr, const char* cert_pem, const char* key_pem);
 unsigned int pollnet_open_ws(struct pnctx* ctx, const char* url);
+unsigned int pollnet_open_ws_with_headers(struct pnctx* ctx, const char* url, const char* headers, const char* protocols);
 unsigned int pollnet_simple_http_get(struct pnctx* ctx, const char* url);
 unsigned int pollnet_simple_http_post(struct pnctx* ctx, const char* url, const char* content_type, const char* data, unsigned int datasize);
 void pollnet_close(struct pnctx* ctx, unsigned int handle);
+void pollnet_close_with_reason(struct pnctx* ctx, unsigned int handle, unsigned int code, const char* reason);
+void pollnet_close_graceful(struct pnctx* ctx, unsigned int handle, unsigned int timeout_ms);
+void pollnet_shutdown_write(struct pnctx* ctx, unsigned int handle);
 void pollnet_close_all(struct pnctx* ctx);
 void pollnet_send(struct pnctx* ctx, unsigned int handle, const char* msg);
 void pollnet_send_binary(struct pnctx* ctx, unsigned int handle, const unsigned char* msg, unsigned int msgsize);
+void pollnet_send_to(struct pnctx* ctx, unsigned int handle, const char* addr, const unsigned char* msg, unsigned int msgsize);
+void pollnet_broadcast(struct pnctx* ctx, unsigned int handle, const char* msg);
+void pollnet_broadcast_binary(struct pnctx* ctx, unsigned int handle, const unsigned char* msg, unsigned int msgsize);
+void pollnet_send_to_group(struct pnctx* ctx, unsigned int handle, const char* group, const char* msg);
+void pollnet_send_binary_to_group(struct pnctx* ctx, unsigned int handle, const char* group, const unsigned char* msg, unsigned int msgsize);
+void pollnet_join_group(struct pnctx* ctx, unsigned int handle, const char* group);
+void pollnet_leave_group(struct pnctx* ctx, unsigned int handle, const char* group);
 unsigned int pollnet_update(struct pnctx* ctx, unsigned int handle);
 unsigned int pollnet_update_blocking(struct pnctx* ctx, unsigned int handle);
+// getters with a dest buffer return the number of bytes copied, 0 if there's nothing,
+// -1 for an unknown handle and -2 if dest is too small; pollnet_get and pollnet_get_error
+// keep a value that didn't fit, so it can be fetched again with a bigger buffer
 int pollnet_get(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
 int pollnet_get_error(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_local_addr(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_peer_addr(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_message_source(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_path(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_query(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_headers(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_protocol(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+unsigned int pollnet_get_message_type(struct pnctx* ctx, unsigned int handle);
+int pollnet_get_exit_code(struct pnctx* ctx, unsigned int handle);
+unsigned int pollnet_get_close_code(struct pnctx* ctx, unsigned int handle);
+int pollnet_get_close_reason(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
 unsigned int pollnet_get_connected_client_handle(struct pnctx* ctx, unsigned int handle);
 unsigned int pollnet_listen_ws(struct pnctx* ctx, const char* addr);
 unsigned int pollnet_serve_static_http(struct pnctx* ctx, const char* addr, const char* serve_dir);
 unsigned int pollnet_serve_http(struct pnctx* ctx, const char* addr);
 void pollnet_add_virtual_file(struct pnctx* ctx, unsigned int handle, const char* filename, const char* filedata, unsigned int filesize);
 void pollnet_remove_virtual_file(struct pnctx* ctx, unsigned int handle, const char* filename);
+// only errors reconnect, a clean close from the peer doesn't; up to 100 messages sent while
+// reconnecting are queued, past that the oldest are dropped with a warning (12)
+void pollnet_set_reconnect(struct pnctx* ctx, unsigned int handle, unsigned int initial_delay_ms, unsigned int max_delay_ms, unsigned int max_attempts, double jitter);
+void pollnet_add_hello_message(struct pnctx* ctx, unsigned int handle, const char* msg);
+void pollnet_set_default_tcp_options(struct pnctx* ctx, unsigned int nodelay, unsigned int keepalive_ms, unsigned int keepalive_interval_ms, unsigned int keepalive_count, unsigned int send_buffer_size, unsigned int recv_buffer_size, int linger_secs, unsigned int reuse_addr, unsigned int reuse_port);
+void pollnet_set_tcp_options(struct pnctx* ctx, unsigned int handle, unsigned int nodelay, unsigned int keepalive_ms, unsigned int keepalive_interval_ms, unsigned int keepalive_count, unsigned int send_buffer_size, unsigned int recv_buffer_size, int linger_secs);
+void pollnet_set_default_udp_options(struct pnctx* ctx, unsigned int broadcast, unsigned int ttl, unsigned int multicast_ttl, unsigned int multicast_loop, unsigned int reuse_addr, unsigned int reuse_port);
+void pollnet_set_udp_options(struct pnctx* ctx, unsigned int handle, unsigned int broadcast, unsigned int ttl, unsigned int multicast_ttl, unsigned int multicast_loop);
+// failures come back from pollnet_update as a warning (12), with the reason as the message
+void pollnet_join_multicast(struct pnctx* ctx, unsigned int handle, const char* group, const char* iface);
+void pollnet_leave_multicast(struct pnctx* ctx, unsigned int handle, const char* group, const char* iface);
+// mode: 0 raw, 1 newline, 2/3 u16 BE/LE length prefix, 4/5 u32 BE/LE length prefix, 6 netstring, 7 delimiter
+void pollnet_set_framing(struct pnctx* ctx, unsigned int handle, unsigned int mode, const unsigned char* delimiter, unsigned int delimiter_size);
+void pollnet_set_default_ws_limits(struct pnctx* ctx, unsigned int max_message_size, unsigned int max_frame_size, unsigned int max_send_queue);
+void pollnet_set_ws_limits(struct pnctx* ctx, unsigned int handle, unsigned int max_message_size, unsigned int max_frame_size, unsigned int max_send_queue);
+// window_bits: 9-15, or 0 for 15
+void pollnet_set_default_ws_compression(struct pnctx* ctx, unsigned int enabled, unsigned int window_bits);
+void pollnet_set_ws_compression(struct pnctx* ctx, unsigned int handle, unsigned int enabled, unsigned int window_bits);
+void pollnet_set_heartbeat(struct pnctx* ctx, unsigned int handle, unsigned int interval_ms, unsigned int timeout_ms);
+void pollnet_ping(struct pnctx* ctx, unsigned int handle, const char* payload, unsigned int payloadsize);
+double pollnet_get_rtt(struct pnctx* ctx, unsigned int handle);
+void pollnet_set_ws_policy(struct pnctx* ctx, unsigned int handle, const char* origins, const char* token_name, const char* token, const char* paths, const char* protocols);
+void pollnet_set_listener_limits(struct pnctx* ctx, unsigned int handle, unsigned int max_clients, unsigned int max_per_ip, double rate, unsigned int burst);
+// -1 if the interfaces can't be listed, -2 if they don't fit in dest
+int pollnet_get_interfaces(char* dest, unsigned int dest_size);
 int pollnet_get_nanoid(char* dest, unsigned int dest_size);
\ No newline at end of file
@@ -54,25 +54,84 @@ ffi.cdef[[
 struct pnctx* pollnet_init();
 struct pnctx* pollnet_get_or_init_static();
 void pollnet_shutdown(struct pnctx* ctx);
+void pollnet_add_tls_ca_pem(struct pnctx* ctx, const char* pem);
+void pollnet_set_tls_client_cert(struct pnctx* ctx, const char* cert_pem, const char* key_pem);
+void pollnet_set_tls_server_name(struct pnctx* ctx, const char* server_name);
+void pollnet_set_tls_insecure(struct pnctx* ctx, unsigned int insecure);
+void pollnet_clear_tls_options(struct pnctx* ctx);
 unsigned int pollnet_open_tcp(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_open_unix(struct pnctx* ctx, const char* path);
+unsigned int pollnet_listen_unix(struct pnctx* ctx, const char* path);
+unsigned int pollnet_resolve(struct pnctx* ctx, const char* hostname);
+unsigned int pollnet_resolve_records(struct pnctx* ctx, const char* name, const char* record_type);
+unsigned int pollnet_set_dns_server(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_set_proxy(struct pnctx* ctx, const char* url);
+void pollnet_set_proxy_from_env(struct pnctx* ctx, unsigned int enabled);
+unsigned int pollnet_timer(struct pnctx* ctx, unsigned int ms, unsigned int repeat);
+unsigned int pollnet_spawn_process(struct pnctx* ctx, const char* program, const char* args, const char* cwd);
+unsigned int pollnet_open_udp(struct pnctx* ctx, const char* bind_addr);
+unsigned int pollnet_open_udp_connected(struct pnctx* ctx, const char* bind_addr, const char* peer_addr);
 unsigned int pollnet_listen_tcp(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_open_tls(struct pnctx* ctx, const char* addr);
+unsigned int pollnet_listen_tls(struct pnctx* ctx, const char* addr, const char* cert_pem, const char* key_pem);
 unsigned int pollnet_open_ws(struct pnctx* ctx, const char* url);
+unsigned int pollnet_open_ws_with_headers(struct pnctx* ctx, const char* url, const char* headers, const char* protocols);
 unsigned int pollnet_simple_http_get(struct pnctx* ctx, const char* url);
 unsigned int pollnet_simple_http_post(struct pnctx* ctx, const char* url, const char* content_type, const char* data, unsigned int datasize);
 void pollnet_close(struct pnctx* ctx, unsigned int handle);
+void pollnet_close_with_reason(struct pnctx* ctx, unsigned int handle, unsigned int code, const char* reason);
+void pollnet_close_graceful(struct pnctx* ctx, unsigned int handle, unsigned int timeout_ms);
+void pollnet_shutdown_write(struct pnctx* ctx, unsigned int handle);
 void pollnet_close_all(struct pnctx* ctx);
 void pollnet_send(struct pnctx* ctx, unsigned int handle, const char* msg);
 void pollnet_send_binary(struct pnctx* ctx, unsigned int handle, const char* msg, unsigned int msgsize);
+void pollnet_send_to(struct pnctx* ctx, unsigned int handle, const char* addr, const char* msg, unsigned int msgsize);
+void pollnet_broadcast(struct pnctx* ctx, unsigned int handle, const char* msg);
+void pollnet_broadcast_binary(struct pnctx* ctx, unsigned int handle, const char* msg, unsigned int msgsize);
+void pollnet_send_to_group(struct pnctx* ctx, unsigned int handle, const char* group, const char* msg);
+void pollnet_send_binary_to_group(struct pnctx* ctx, unsigned int handle, const char* group, const char* msg, unsigned int msgsize);
+void pollnet_join_group(struct pnctx* ctx, unsigned int handle, const char* group);
+void pollnet_leave_group(struct pnctx* ctx, unsigned int handle, const char* group);
 unsigned int pollnet_update(struct pnctx* ctx, unsigned int handle);
 unsigned int pollnet_update_blocking(struct pnctx* ctx, unsigned int handle);
 int pollnet_get(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
 int pollnet_get_error(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_local_addr(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_peer_addr(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_message_source(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_path(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_query(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_headers(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+int pollnet_get_handshake_protocol(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
+unsigned int pollnet_get_message_type(struct pnctx* ctx, unsigned int handle);
+int pollnet_get_exit_code(struct pnctx* ctx, unsigned int handle);
+unsigned int pollnet_get_close_code(struct pnctx* ctx, unsigned int handle);
+int pollnet_get_close_reason(struct pnctx* ctx, unsigned int handle, char* dest, unsigned int dest_size);
 unsigned int pollnet_get_connected_client_handle(struct pnctx* ctx, unsigned int handle);
 unsigned int pollnet_listen_ws(struct pnctx* ctx, const char* addr);
 unsigned int pollnet_serve_static_http(struct pnctx* ctx, const char* addr, const char* serve_dir);
 unsigned int pollnet_serve_http(struct pnctx* ctx, const char* addr);
 void pollnet_add_virtual_file(struct pnctx* ctx, unsigned int handle, const char* filename, const char* filedata, unsigned int filesize);
 void pollnet_remove_virtual_file(struct pnctx* ctx, unsigned int handle, const char* filename);
+void pollnet_set_reconnect(struct pnctx* ctx, unsigned int handle, unsigned int initial_delay_ms, unsigned int max_delay_ms, unsigned int max_attempts, double jitter);
+void pollnet_add_hello_message(struct pnctx* ctx, unsigned int handle, const char* msg);
+void pollnet_set_default_tcp_options(struct pnctx* ctx, unsigned int nodelay, unsigned int keepalive_ms, unsigned int keepalive_interval_ms, unsigned int keepalive_count, unsigned int send_buffer_size, unsigned int recv_buffer_size, int linger_secs, unsigned int reuse_addr, unsigned int reuse_port);
+void pollnet_set_tcp_options(struct pnctx* ctx, unsigned int handle, unsigned int nodelay, unsigned int keepalive_ms, unsigned int keepalive_interval_ms, unsigned int keepalive_count, unsigned int send_buffer_size, unsigned int recv_buffer_size, int linger_secs);
+void pollnet_set_default_udp_options(struct pnctx* ctx, unsigned int broadcast, unsigned int ttl, unsigned int multicast_ttl, unsigned int multicast_loop, unsigned int reuse_addr, unsigned int reuse_port);
+void pollnet_set_udp_options(struct pnctx* ctx, unsigned int handle, unsigned int broadcast, unsigned int ttl, unsigned int multicast_ttl, unsigned int multicast_loop);
+void pollnet_join_multicast(struct pnctx* ctx, unsigned int handle, const char* group, const char* iface);
+void pollnet_leave_multicast(struct pnctx* ctx, unsigned int handle, const char* group, const char* iface);
+void pollnet_set_framing(struct pnctx* ctx, unsigned int handle, unsigned int mode, const char* delimiter, unsigned int delimiter_size);
+void pollnet_set_default_ws_limits(struct pnctx* ctx, unsigned int max_message_size, unsigned int max_frame_size, unsigned int max_send_queue);
+void pollnet_set_ws_limits(struct pnctx* ctx, unsigned int handle, unsigned int max_message_size, unsigned int max_frame_size, unsigned int max_send_queue);
+void pollnet_set_default_ws_compression(struct pnctx* ctx, unsigned int enabled, unsigned int window_bits);
+void pollnet_set_ws_compression(struct pnctx* ctx, unsigned int handle, unsigned int enabled, unsigned int window_bits);
+void pollnet_set_heartbeat(struct pnctx* ctx, unsigned int handle, unsigned int interval_ms, unsigned int timeout_ms);
+void pollnet_ping(struct pnctx* ctx, unsigned int handle, const char* payload, unsigned int payloadsize);
+void pollnet_set_ws_policy(struct pnctx* ctx, unsigned int handle, const char* origins, const char* token_name, const char* token, const char* paths, const char* protocols);
+double pollnet_get_rtt(struct pnctx* ctx, unsigned int handle);
+void pollnet_set_listener_limits(struct pnctx* ctx, unsigned int handle, unsigned int max_clients, unsigned int max_per_ip, double rate, unsigned int burst);
+int pollnet_get_interfaces(char* dest, unsigned int dest_size);
 int pollnet_get_nanoid(char* dest, unsigned int dest_size);
 ]]
 
@@ -83,7 +142,31 @@ local POLLNET_RESULT_CODES = {
   [3] = "nodata",
   [4] = "hasdata",
   [5] = "error",
-  [6] = "newclient"
+  [6] = "newclient",
+  [7] = "rejected",
+  [8] = "reconnecting",
+  [9] = "reconnected",
+  [10] = "eof",
+  [11] = "pong",
+  [12] = "warning"
+}
+
+local POLLNET_MESSAGE_TYPES = {
+  [0] = nil,
+  [1] = "text",
+  [2] = "binary",
+  [3] = "close"
+}
+
+local POLLNET_FRAMING_MODES = {
+  raw = 0,
+  newline = 1,
+  u16be = 2,
+  u16le = 3,
+  u32be = 4,
+  u32le = 5,
+  netstring = 6,
+  delimiter = 7
 }
 
 local pollnet = ffi.load("pollnet")
@@ -142,10 +225,125 @@ function socket_mt:open_ws(url, scratch_size)
   return self:_open(scratch_size, pollnet.pollnet_open_ws, url)
 end
 
+-- headers: either a table of {name = value} or a string of "Name: value" lines
+-- protocols: either a list of subprotocol names or a comma separated string
+function socket_mt:open_ws_with_headers(url, headers, protocols, scratch_size)
+  if type(headers) == "table" then
+    local lines = {}
+    for name, value in pairs(headers) do
+      table.insert(lines, name .. ": " .. value)
+    end
+    headers = table.concat(lines, "\n")
+  end
+  if type(protocols) == "table" then
+    protocols = table.concat(protocols, ",")
+  end
+  return self:_open(scratch_size, pollnet.pollnet_open_ws_with_headers, url, headers or "", protocols or "")
+end
+
 function socket_mt:open_tcp(addr, scratch_size)
   return self:_open(scratch_size, pollnet.pollnet_open_tcp, addr)
 end
 
+-- polls with one message per record, then closes; record_type defaults to
+-- both "A" and "AAAA", anything else ("SRV", "TXT", ...) is asked of DNS directly.
+-- SRV records come as "priority weight port target".
+function socket_mt:resolve(name, record_type, scratch_size)
+  if record_type then
+    return self:_open(scratch_size, pollnet.pollnet_resolve_records, name, record_type)
+  end
+  return self:_open(scratch_size, pollnet.pollnet_resolve, name)
+end
+
+-- polls with a message once ms have passed (every ms if repeat is true); the
+-- message is the number of ticks since the last poll that returned one, so
+-- anything above 1 means ticks were missed. A one-shot timer closes after firing.
+function socket_mt:timer(ms, repeat_, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_timer, ms, repeat_ and 1 or 0)
+end
+
+-- stdout and stderr arrive as binary messages (see last_source), send()
+-- writes to stdin and shutdown_write() closes it; args is a list of strings.
+-- Closing the socket kills the process, close_graceful waits for it first.
+function socket_mt:spawn_process(program, args, cwd, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_spawn_process, program, table.concat(args or {}, "\n"), cwd or "")
+end
+
+-- a path starting with "@" is in the Linux abstract namespace; listeners on
+-- one only accept clients running as the same user
+function socket_mt:open_unix(path, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_open_unix, path)
+end
+
+function socket_mt:listen_unix(path, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_listen_unix, path)
+end
+
+-- every datagram arrives as a binary message, see last_source; with a
+-- peer_addr the socket is connected and send() goes to the peer
+function socket_mt:open_udp(bind_addr, peer_addr, scratch_size)
+  if peer_addr then
+    return self:_open(scratch_size, pollnet.pollnet_open_udp_connected, bind_addr, peer_addr)
+  end
+  return self:_open(scratch_size, pollnet.pollnet_open_udp, bind_addr)
+end
+
+-- udp only; opts fields (all optional): broadcast, ttl, multicast_ttl (hop
+-- limits for IPv6), multicast_loop (defaults to true). Unset TTLs keep the
+-- OS defaults.
+function socket_mt:set_udp_options(opts)
+  assert(self._socket)
+  pollnet.pollnet_set_udp_options(_ctx, self._socket, opts.broadcast and 1 or 0,
+    opts.ttl or 0, opts.multicast_ttl or 0, opts.multicast_loop == false and 0 or 1)
+  return self
+end
+
+-- udp only: iface is the local IPv4 address for IPv4 groups, or the interface
+-- index for IPv6 groups; nil lets the OS pick. A failed join or leave is
+-- reported through on_warning.
+function socket_mt:join_multicast(group, iface)
+  assert(self._socket)
+  pollnet.pollnet_join_multicast(_ctx, self._socket, group, iface and tostring(iface) or "")
+  return self
+end
+
+function socket_mt:leave_multicast(group, iface)
+  assert(self._socket)
+  pollnet.pollnet_leave_multicast(_ctx, self._socket, group, iface and tostring(iface) or "")
+  return self
+end
+
+-- tcp and tls sockets only: mode is one of "raw", "newline", "u16be", "u16le",
+-- "u32be", "u32le", "netstring" or "delimiter" (which needs the delimiter
+-- string). Received data then arrives as whole frames, and sends get framed.
+-- When the peer stops sending, a last unterminated line still arrives, but a
+-- cut off length prefixed frame or netstring is an error.
+-- On a listener this applies to clients accepted afterwards.
+function socket_mt:set_framing(mode, delimiter)
+  assert(self._socket)
+  local mode_id = assert(POLLNET_FRAMING_MODES[mode], "unknown framing mode")
+  delimiter = delimiter or ""
+  pollnet.pollnet_set_framing(_ctx, self._socket, mode_id, delimiter, #delimiter)
+  return self
+end
+
+-- tcp and tls sockets only; opts fields (all optional): nodelay, keepalive_ms,
+-- keepalive_interval_ms, keepalive_count, send_buffer_size, recv_buffer_size,
+-- linger_secs. Unset fields keep the OS defaults. On a listener this applies
+-- to clients accepted afterwards.
+function socket_mt:set_tcp_options(opts)
+  assert(self._socket)
+  pollnet.pollnet_set_tcp_options(_ctx, self._socket, opts.nodelay and 1 or 0,
+    opts.keepalive_ms or 0, opts.keepalive_interval_ms or 0, opts.keepalive_count or 0,
+    opts.send_buffer_size or 0, opts.recv_buffer_size or 0, opts.linger_secs or -1)
+  return self
+end
+
+-- verified according to set_tls_options
+function socket_mt:open_tls(addr, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_open_tls, addr)
+end
+
 function socket_mt:serve_http(addr, dir, scratch_size)
   self.is_http_server = true
   if dir and dir ~= "" then
@@ -173,18 +371,123 @@ function socket_mt:listen_tcp(addr, scratch_size)
   return self:_open(scratch_size, pollnet.pollnet_listen_tcp, addr)
 end
 
+-- cert_pem is the certificate chain, key_pem its PKCS#8 private key
+function socket_mt:listen_tls(addr, cert_pem, key_pem, scratch_size)
+  return self:_open(scratch_size, pollnet.pollnet_listen_tls, addr, cert_pem, key_pem)
+end
+
 function socket_mt:on_connection(f)
   self._on_connection = f
   return self
 end
 
-function socket_mt:_get_message()
-  local msg_size = pollnet.pollnet_get(_ctx, self._socket, self._scratch, self._scratch_size)
-  if msg_size > 0 then
-    return ffi.string(self._scratch, msg_size)
-  else
-    return nil
+-- only applies to open_ws, open_tcp and open_tls; max_attempts of 0 retries forever,
+-- jitter (0 to 1) is the fraction of each delay that may be randomly skipped.
+-- Only errors reconnect: a close frame or a TCP end of stream from the peer is
+-- final. Up to 100 messages sent while reconnecting are queued; past that the
+-- oldest are dropped and on_warning hears about it.
+function socket_mt:set_reconnect(initial_delay_ms, max_delay_ms, max_attempts, jitter)
+  assert(self._socket)
+  initial_delay_ms = initial_delay_ms or 1000
+  pollnet.pollnet_set_reconnect(_ctx, self._socket, initial_delay_ms, max_delay_ms or initial_delay_ms * 30, max_attempts or 0, jitter or 0.5)
+  return self
+end
+
+-- sent now, and again first thing after every reconnect
+function socket_mt:add_hello(msg)
+  assert(self._socket)
+  pollnet.pollnet_add_hello_message(_ctx, self._socket, msg)
+  return self
+end
+
+-- sizes in bytes, nil or 0 keeps the default (64 MiB messages, 16 MiB frames,
+-- unbounded send queue). Open connections pick up the message and frame sizes
+-- right away and the send queue from their next (re)connection; on a listener
+-- this applies to clients accepted afterwards. A peer exceeding the limits is
+-- disconnected with close code 1009 and an error.
+function socket_mt:set_ws_limits(max_message_size, max_frame_size, max_send_queue)
+  assert(self._socket)
+  pollnet.pollnet_set_ws_limits(_ctx, self._socket, max_message_size or 0, max_frame_size or 0, max_send_queue or 0)
+  return self
+end
+
+-- permessage-deflate; window_bits (9-15, default 15) caps the window either
+-- side compresses with. On a listener this applies to clients accepted
+-- afterwards and on a client from its next (re)connection. Turning it off on
+-- a connection that agreed to compression sends the following messages as is.
+function socket_mt:set_ws_compression(enabled, window_bits)
+  assert(self._socket)
+  pollnet.pollnet_set_ws_compression(_ctx, self._socket, enabled == false and 0 or 1, window_bits or 0)
+  return self
+end
+
+-- websockets only; on a listener this applies to clients accepted afterwards.
+-- If a ping goes unanswered for timeout_ms the socket errors out.
+function socket_mt:set_heartbeat(interval_ms, timeout_ms)
+  assert(self._socket)
+  pollnet.pollnet_set_heartbeat(_ctx, self._socket, interval_ms or 0, timeout_ms or 0)
+  return self
+end
+
+-- ws listeners only; upgrades that fail the policy are refused with an HTTP
+-- error and reported through on_rejected. Every field is optional, lists can
+-- be tables or comma separated strings:
+--   origins, paths, protocols (in order of preference), token_name, token
+function socket_mt:set_ws_policy(policy)
+  assert(self._socket)
+  local function list(l)
+    if type(l) == "table" then return table.concat(l, ",") end
+    return l or ""
   end
+  pollnet.pollnet_set_ws_policy(_ctx, self._socket, list(policy.origins),
+    policy.token_name or "Authorization", policy.token or "", list(policy.paths), list(policy.protocols))
+  return self
+end
+
+-- payloads longer than 125 bytes are truncated; on_pong is called with the
+-- round trip time once the matching pong arrives
+function socket_mt:ping(payload)
+  assert(self._socket)
+  payload = payload or ""
+  pollnet.pollnet_ping(_ctx, self._socket, payload, #payload)
+end
+
+-- round trip time in milliseconds of the most recently answered ping, or nil
+function socket_mt:rtt()
+  if not self._socket then return nil end
+  local rtt = pollnet.pollnet_get_rtt(_ctx, self._socket)
+  if rtt < 0 then return nil end
+  return rtt
+end
+
+function socket_mt:on_pong(f)
+  self._on_pong = f
+  return self
+end
+
+-- called with the reason when a request like joining a multicast group fails;
+-- unlike an error the socket stays open
+function socket_mt:on_warning(f)
+  self._on_warning = f
+  return self
+end
+
+function socket_mt:on_rejected(f)
+  self._on_rejected = f
+  return self
+end
+
+-- any limit left as nil/0 is unlimited; rate is in connections (or HTTP requests)
+-- per second per client address, with bursts of up to `burst`. Unix socket
+-- clients have no address, so only max_clients applies to them.
+function socket_mt:set_limits(max_clients, max_per_ip, rate, burst)
+  assert(self._socket)
+  pollnet.pollnet_set_listener_limits(_ctx, self._socket, max_clients or 0, max_per_ip or 0, rate or 0, burst or 0)
+  return self
+end
+
+function socket_mt:_get_message()
+  return self:_get_string(pollnet.pollnet_get)
 end
 
 function socket_mt:poll()
@@ -195,6 +498,7 @@ function socket_mt:poll()
   local res = POLLNET_RESULT_CODES[pollnet.pollnet_update(_ctx, self._socket)] or "error"
   self._status = res
   self._last_message = nil
+  self._last_message_type = POLLNET_MESSAGE_TYPES[pollnet.pollnet_get_message_type(_ctx, self._socket)]
   if res == "hasdata" then
     self._status = "open"
     self._last_message = self:_get_message()
@@ -211,6 +515,12 @@ function socket_mt:poll()
     return false, self._last_message
   elseif res == "closed" then
     self._status = "closed"
+    if self._last_message_type == "close" then
+      self._close_code = pollnet.pollnet_get_close_code(_ctx, self._socket)
+      self._close_reason = self:_get_string(pollnet.pollnet_get_close_reason) or ""
+      local exit_code = pollnet.pollnet_get_exit_code(_ctx, self._socket)
+      if exit_code >= 0 then self._exit_code = exit_code end
+    end
     return false, "closed"
   elseif res == "newclient" then
     self._status = "open"
@@ -227,12 +537,68 @@ function socket_mt:poll()
       client_sock:close()
     end
     return true
+  elseif res == "reconnecting" then
+    self._status = "reconnecting"
+    self._last_message = self:_get_message()
+    return true
+  elseif res == "reconnected" then
+    self._status = "open"
+    return true
+  elseif res == "eof" then
+    -- the peer won't send anything more, but we can still send
+    self._status = "eof"
+    return true
+  elseif res == "pong" then
+    self._status = "open"
+    if self._on_pong then
+      self._on_pong(pollnet.pollnet_get_rtt(_ctx, self._socket))
+    end
+    return true
+  elseif res == "warning" then
+    self._status = "open"
+    local reason = self:_get_message()
+    if self._on_warning then
+      self._on_warning(reason)
+    end
+    return true
+  elseif res == "rejected" then
+    self._status = "open"
+    local reason = self:_get_message()
+    if self._on_rejected then
+      self._on_rejected(reason)
+    end
+    return true
   end
 end
 
 function socket_mt:last_message()
   return self._last_message
 end
+-- "text", "binary", "close" or nil
+function socket_mt:last_message_type()
+  return self._last_message_type
+end
+-- the code and reason from the peer's close frame, if it sent one
+function socket_mt:close_info()
+  return self._close_code, self._close_reason
+end
+-- "ip:port" (or a unix socket path) once open; nil where there's no such
+-- address, while reconnecting, or if it doesn't fit in the scratch buffer.
+-- Through a proxy, peer_addr is the proxy's address.
+function socket_mt:local_addr()
+  return self:_get_string(pollnet.pollnet_get_local_addr)
+end
+function socket_mt:peer_addr()
+  return self:_get_string(pollnet.pollnet_get_peer_addr)
+end
+-- processes only: nil while running or if killed by a signal (see close_info)
+function socket_mt:exit_code()
+  return self._exit_code
+end
+-- udp: "ip:port" the last datagram came from; processes: "stdout" or "stderr"
+function socket_mt:last_source()
+  return self:_get_string(pollnet.pollnet_get_message_source)
+end
 function socket_mt:status()
   return self._status
 end
@@ -240,26 +606,168 @@ function socket_mt:send(msg)
   assert(self._socket)
   pollnet.pollnet_send(_ctx, self._socket, msg)
 end
-function socket_mt:close()
+
+-- udp only
+function socket_mt:send_to(addr, msg)
+  assert(self._socket)
+  pollnet.pollnet_send_to(_ctx, self._socket, addr, msg, #msg)
+end
+
+-- on a listener: sends to every connected client
+function socket_mt:broadcast(msg)
+  assert(self._socket)
+  pollnet.pollnet_broadcast(_ctx, self._socket, msg)
+end
+
+-- on a listener: sends to the clients that have joined the group
+function socket_mt:send_to_group(group, msg)
+  assert(self._socket)
+  pollnet.pollnet_send_to_group(_ctx, self._socket, group, msg)
+end
+
+-- on a client accepted by a listener; groups are dropped when the client closes
+function socket_mt:join(group)
+  assert(self._socket)
+  pollnet.pollnet_join_group(_ctx, self._socket, group)
+  return self
+end
+
+function socket_mt:leave(group)
+  assert(self._socket)
+  pollnet.pollnet_leave_group(_ctx, self._socket, group)
+  return self
+end
+-- code and reason are only sent by websockets (code defaults to 1000, "normal closure");
+-- codes that may not be sent become 1000 and the reason is cut to 123 bytes
+function socket_mt:close(code, reason)
   assert(self._socket)
-  pollnet.pollnet_close(_ctx, self._socket)
+  if code or reason then
+    pollnet.pollnet_close_with_reason(_ctx, self._socket, code or 1000, reason or "")
+  else
+    pollnet.pollnet_close(_ctx, self._socket)
+  end
   self._socket = nil
 end
-function socket_mt:error_msg()
-  if not self._socket then return "No socket!" end
-  local msg_size = pollnet.pollnet_get_error(_ctx, self._socket, self._scratch, self._scratch_size)
+-- tcp, tls, unix and processes: sends everything queued, half-closes and
+-- waits up to timeout_ms for the other end to finish before closing
+function socket_mt:close_graceful(timeout_ms)
+  assert(self._socket)
+  pollnet.pollnet_close_graceful(_ctx, self._socket, timeout_ms or 5000)
+  self._socket = nil
+end
+-- tcp, tls, unix and processes: tells the other end we're done sending
+-- while still receiving
+function socket_mt:shutdown_write()
+  assert(self._socket)
+  pollnet.pollnet_shutdown_write(_ctx, self._socket)
+end
+function socket_mt:_get_string(getter)
+  if not self._socket then return nil end
+  local msg_size = getter(_ctx, self._socket, self._scratch, self._scratch_size)
+  -- -2 means it didn't fit: grow the scratch buffer until it does
+  while msg_size == -2 do
+    self._scratch_size = math.max(self._scratch_size * 2, 64)
+    self._scratch = ffi.new("int8_t[?]", self._scratch_size)
+    msg_size = getter(_ctx, self._socket, self._scratch, self._scratch_size)
+  end
   if msg_size > 0 then
-    local smsg = ffi.string(self._scratch, msg_size)
-    return smsg
+    return ffi.string(self._scratch, msg_size)
   else
     return nil
   end
 end
+-- for clients accepted by listen_ws: the path and query string they requested
+function socket_mt:handshake_path()
+  return self:_get_string(pollnet.pollnet_get_handshake_path)
+end
+function socket_mt:handshake_query()
+  return self:_get_string(pollnet.pollnet_get_handshake_query)
+end
+function socket_mt:handshake_protocol()
+  return self:_get_string(pollnet.pollnet_get_handshake_protocol)
+end
+-- the server's response headers for open_ws, the client's request headers for listen_ws;
+-- header names are lowercase and repeated headers are joined with ", "
+function socket_mt:handshake_headers()
+  local raw = self:_get_string(pollnet.pollnet_get_handshake_headers)
+  if not raw then return nil end
+  local headers = {}
+  for name, value in raw:gmatch("([^:\n]+): ([^\n]*)") do
+    if headers[name] then
+      headers[name] = headers[name] .. ", " .. value
+    else
+      headers[name] = value
+    end
+  end
+  return headers
+end
+function socket_mt:error_msg()
+  if not self._socket then return "No socket!" end
+  return self:_get_string(pollnet.pollnet_get_error)
+end
 
 local function open_ws(url, scratch_size)
   return Socket():open_ws(url, scratch_size)
 end
 
+-- TLS settings for wss:// and https:// handles opened from now on:
+--   ca_pems: list of extra trusted CA certificates (PEM, bundles are fine)
+--   client_cert, client_key: PEM certificate chain and PKCS#8 key for mutual TLS
+--   server_name: name to verify the server against instead of the url host
+--     (tls and wss only: https requests always verify the url host)
+--   insecure: skip certificate verification entirely (local development only!)
+-- Each call replaces all previous settings.
+local function set_tls_options(opts)
+  init_ctx()
+  opts = opts or {}
+  pollnet.pollnet_clear_tls_options(_ctx)
+  for _, pem in ipairs(opts.ca_pems or {}) do
+    pollnet.pollnet_add_tls_ca_pem(_ctx, pem)
+  end
+  if opts.client_cert then
+    pollnet.pollnet_set_tls_client_cert(_ctx, opts.client_cert, opts.client_key or "")
+  end
+  pollnet.pollnet_set_tls_server_name(_ctx, opts.server_name or "")
+  pollnet.pollnet_set_tls_insecure(_ctx, opts.insecure and 1 or 0)
+end
+
+-- socket options for tcp and tls handles opened from now on, see
+-- socket_mt:set_tcp_options; listeners also take reuse_addr and reuse_port
+local function set_default_tcp_options(opts)
+  init_ctx()
+  opts = opts or {}
+  pollnet.pollnet_set_default_tcp_options(_ctx, opts.nodelay and 1 or 0,
+    opts.keepalive_ms or 0, opts.keepalive_interval_ms or 0, opts.keepalive_count or 0,
+    opts.send_buffer_size or 0, opts.recv_buffer_size or 0, opts.linger_secs or -1,
+    opts.reuse_addr and 1 or 0, opts.reuse_port and 1 or 0)
+end
+
+-- options for udp handles opened from now on, see socket_mt:set_udp_options;
+-- also takes reuse_addr and reuse_port so several instances can share a port
+local function set_default_udp_options(opts)
+  init_ctx()
+  opts = opts or {}
+  pollnet.pollnet_set_default_udp_options(_ctx, opts.broadcast and 1 or 0,
+    opts.ttl or 0, opts.multicast_ttl or 0, opts.multicast_loop == false and 0 or 1,
+    opts.reuse_addr and 1 or 0, opts.reuse_port and 1 or 0)
+end
+
+-- limits for websocket handles opened from now on, see socket_mt:set_ws_limits
+local function set_default_ws_limits(max_message_size, max_frame_size, max_send_queue)
+  init_ctx()
+  pollnet.pollnet_set_default_ws_limits(_ctx, max_message_size or 0, max_frame_size or 0, max_send_queue or 0)
+end
+
+-- compression for websocket handles opened from now on, see socket_mt:set_ws_compression
+local function set_default_ws_compression(enabled, window_bits)
+  init_ctx()
+  pollnet.pollnet_set_default_ws_compression(_ctx, enabled == false and 0 or 1, window_bits or 0)
+end
+
+local function open_ws_with_headers(url, headers, protocols, scratch_size)
+  return Socket():open_ws_with_headers(url, headers, protocols, scratch_size)
+end
+
 local function listen_ws(addr, scratch_size)
   return Socket():listen_ws(addr, scratch_size)
 end
@@ -268,10 +776,62 @@ local function open_tcp(addr, scratch_size)
   return Socket():open_tcp(addr, scratch_size)
 end
 
+local function open_unix(path, scratch_size)
+  return Socket():open_unix(path, scratch_size)
+end
+
+local function listen_unix(path, scratch_size)
+  return Socket():listen_unix(path, scratch_size)
+end
+
+local function resolve(name, record_type, scratch_size)
+  return Socket():resolve(name, record_type, scratch_size)
+end
+
+-- "ip" or "ip:port"; nil goes back to the system configuration. Returns
+-- false and keeps the current server if addr isn't a valid address.
+local function set_dns_server(addr)
+  init_ctx()
+  return pollnet.pollnet_set_dns_server(_ctx, addr or "") ~= 0
+end
+
+-- proxy for tcp, tls and ws clients opened from now on: "socks5://host:port"
+-- or "http://host:port" (HTTP CONNECT), optionally with "user:pass@"; nil
+-- connects directly, or through HTTP(S)_PROXY/ALL_PROXY unless NO_PROXY
+-- matches when from_env is set. Returns false for an invalid url, in which
+-- case clients fail to connect until the proxy is set again; a user name or
+-- password with reserved characters must be percent-encoded.
+local function set_proxy(url, from_env)
+  init_ctx()
+  local ok = pollnet.pollnet_set_proxy(_ctx, url or "") ~= 0
+  pollnet.pollnet_set_proxy_from_env(_ctx, from_env and 1 or 0)
+  return ok
+end
+
+local function timer(ms, repeat_, scratch_size)
+  return Socket():timer(ms, repeat_, scratch_size)
+end
+
+local function spawn_process(program, args, cwd, scratch_size)
+  return Socket():spawn_process(program, args, cwd, scratch_size)
+end
+
+local function open_udp(bind_addr, peer_addr, scratch_size)
+  return Socket():open_udp(bind_addr, peer_addr, scratch_size)
+end
+
 local function listen_tcp(addr, scratch_size)
   return Socket():listen_tcp(addr, scratch_size)
 end
 
+local function open_tls(addr, scratch_size)
+  return Socket():open_tls(addr, scratch_size)
+end
+
+local function listen_tls(addr, cert_pem, key_pem, scratch_size)
+  return Socket():listen_tls(addr, cert_pem, key_pem, scratch_size)
+end
+
 local function serve_http(addr, dir, scratch_size)
   return Socket():serve_http(addr, dir, scratch_size)
 end
@@ -284,6 +844,43 @@ local function http_post(url, body, content_type, scratch_size)
   return Socket():http_post(url, body, content_type, scratch_size)
 end
 
+-- a list of {name, index, family ("ipv4"/"ipv6"), addr, netmask, prefix_len,
+-- broadcast, up, loopback, link_local, p2p}, one entry per address; nil and
+-- an error if the interfaces can't be listed
+local function interfaces()
+  local scratch_size = 65536
+  local scratch = ffi.new("int8_t[?]", scratch_size)
+  local size = pollnet.pollnet_get_interfaces(scratch, scratch_size)
+  -- machines with lots of addresses can outgrow the buffer
+  while size == -2 do
+    scratch_size = scratch_size * 2
+    scratch = ffi.new("int8_t[?]", scratch_size)
+    size = pollnet.pollnet_get_interfaces(scratch, scratch_size)
+  end
+  if size < 0 then return nil, "could not list interfaces" end
+  local list = {}
+  for line in ffi.string(scratch, size):gmatch("[^\n]+") do
+    local name, index, family, addr, netmask, prefix_len, broadcast, flags =
+      line:match("^([^\t]*)\t([^\t]*)\t([^\t]*)\t([^\t]*)\t([^\t]*)\t([^\t]*)\t([^\t]*)\t([^\t]*)$")
+    if name then
+      local entry = {
+        name = name,
+        index = tonumber(index),
+        family = family,
+        addr = addr,
+        netmask = netmask,
+        prefix_len = tonumber(prefix_len),
+        broadcast = broadcast ~= "" and broadcast or nil,
+      }
+      for flag in flags:gmatch("[^,]+") do
+        entry[flag] = true
+      end
+      table.insert(list, entry)
+    end
+  end
+  return list
+end
+
 local function get_nanoid()
   local _id_scratch = ffi.new("int8_t[?]", 128)
   local msg_size = pollnet.pollnet_get_nanoid(_id_scratch, 128)
@@ -295,13 +892,30 @@ return {
   init_hack_static = init_ctx_hack_static,
   shutdown = shutdown_ctx, 
   open_ws = open_ws, 
+  open_ws_with_headers = open_ws_with_headers,
   listen_ws = listen_ws,
+  set_default_ws_limits = set_default_ws_limits,
+  set_default_ws_compression = set_default_ws_compression,
+  set_tls_options = set_tls_options,
+  set_default_tcp_options = set_default_tcp_options,
   open_tcp = open_tcp,
   listen_tcp = listen_tcp,
+  open_unix = open_unix,
+  spawn_process = spawn_process,
+  timer = timer,
+  resolve = resolve,
+  set_dns_server = set_dns_server,
+  set_proxy = set_proxy,
+  listen_unix = listen_unix,
+  open_udp = open_udp,
+  set_default_udp_options = set_default_udp_options,
+  open_tls = open_tls,
+  listen_tls = listen_tls,
   serve_http = serve_http,
   http_get = http_get,
   http_post = http_post,
   Socket = Socket,
   pollnet = pollnet,
   nanoid = get_nanoid,
+  interfaces = interfaces,
 }
\ No newline at end of file
@@ -1,29 +1,45 @@
+// The C API trusts its caller: every ctx must come from pollnet_init (or
+// pollnet_get_or_init_static) and not yet be shut down, strings must be NUL
+// terminated and every buffer must be valid for the size passed with it.
+// Clippy would have each of those functions be an unsafe fn, which means nothing to C.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 extern crate url;
 
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 use std::sync::RwLock;
+use std::sync::Mutex;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
-use std::net::SocketAddr;
+use std::time::Instant;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
 use std::io::Error as IoError;
 use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::process::Stdio;
 use std::os::raw::c_char;
 use std::ffi::CStr;
 use log::{error, warn, info};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
 use tokio::runtime;
-use tokio::io::AsyncWriteExt;
-use tokio_tungstenite::{connect_async, accept_async};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::{client_async_with_config, accept_hdr_async_with_config};
+use tungstenite::client::IntoClientRequest;
 use futures::executor::block_on;
 use futures_util::{SinkExt, StreamExt, future};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper_staticfile::Static;
 
 extern crate nanoid;
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SocketResult {
     INVALIDHANDLE,
     CLOSED,
@@ -32,6 +48,21 @@ pub enum SocketResult {
     HASDATA,
     ERROR,
     NEWCLIENT,
+    REJECTED,
+    RECONNECTING,
+    RECONNECTED,
+    EOF,
+    PONG,
+    WARNING,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub enum MessageType {
+    NONE,
+    TEXT,
+    BINARY,
+    CLOSE,
 }
 
 #[repr(C)]
@@ -49,6 +80,10 @@ struct ClientConn {
     tx: tokio::sync::mpsc::Sender<SocketMessage>, 
     rx: std::sync::mpsc::Receiver<SocketMessage>, 
     id: String,
+    handshake: Option<HandshakeInfo>,
+    membership: Option<GroupMembership>,
+    local_addr: Option<String>,
+    peer_addr: Option<String>,
 }
 
 
@@ -61,1016 +96,6542 @@ enum SocketMessage {
     NewClient(ClientConn),
     FileAdd(String, Vec<u8>),
     FileRemove(String),
+    SetLimits(ListenerLimits),
+    Rejected(String),
+    Warning(String), // a request that failed without closing the socket
+    Handshake(HandshakeInfo),
+    Close(u16, String),
+    SetReconnect(ReconnectPolicy),
+    AddHello(String),
+    Reconnecting(String),
+    Reconnected,
+    SetHeartbeat(HeartbeatConfig),
+    Ping(Vec<u8>),
+    RoundTrip(f64),
+    SetPolicy(WsPolicy),
+    Broadcast(Option<String>, Box<SocketMessage>),
+    SetWsLimits(WsLimits),
+    SetWsCompression(WsCompression),
+    SetFraming(Framing),
+    SetTcpOptions(TcpOptions),
+    ShutdownWrite,
+    Finish(u32),
+    Eof,
+    SendTo(String, Vec<u8>),
+    Datagram(SocketAddr, Vec<u8>),
+    SetUdpOptions(UdpOptions),
+    JoinMulticast(String, String),
+    LeaveMulticast(String, String),
+    ProcessOutput(&'static str, Vec<u8>),
+    Exited(Option<i32>, String),
+    Tick(Arc<AtomicU64>),
+    Addresses(Option<String>, Option<String>),
 }
 
+impl SocketMessage {
+    // For logging settings a handle has no use for
+    fn kind(&self) -> &'static str {
+        match self {
+            SocketMessage::Connect => "connect",
+            SocketMessage::Disconnect => "disconnect",
+            SocketMessage::Message(_) => "text message",
+            SocketMessage::BinaryMessage(_) => "binary message",
+            SocketMessage::Error(_) => "error",
+            SocketMessage::NewClient(_) => "new client",
+            SocketMessage::FileAdd(_, _) => "virtual file",
+            SocketMessage::FileRemove(_) => "virtual file removal",
+            SocketMessage::SetLimits(_) => "listener limits",
+            SocketMessage::Rejected(_) => "rejection",
+            SocketMessage::Warning(_) => "warning",
+            SocketMessage::Handshake(_) => "handshake",
+            SocketMessage::Close(_, _) => "close",
+            SocketMessage::SetReconnect(_) => "reconnect policy",
+            SocketMessage::AddHello(_) => "hello message",
+            SocketMessage::Reconnecting(_) => "reconnecting",
+            SocketMessage::Reconnected => "reconnected",
+            SocketMessage::SetHeartbeat(_) => "heartbeat",
+            SocketMessage::Ping(_) => "ping",
+            SocketMessage::RoundTrip(_) => "round trip",
+            SocketMessage::SetPolicy(_) => "handshake policy",
+            SocketMessage::Broadcast(_, _) => "broadcast",
+            SocketMessage::SetWsLimits(_) => "websocket limits",
+            SocketMessage::SetWsCompression(_) => "websocket compression",
+            SocketMessage::SetFraming(_) => "framing",
+            SocketMessage::SetTcpOptions(_) => "TCP options",
+            SocketMessage::ShutdownWrite => "write shutdown",
+            SocketMessage::Finish(_) => "graceful close",
+            SocketMessage::Eof => "eof",
+            SocketMessage::SendTo(_, _) => "send_to",
+            SocketMessage::Datagram(_, _) => "datagram",
+            SocketMessage::SetUdpOptions(_) => "UDP options",
+            SocketMessage::JoinMulticast(_, _) => "multicast join",
+            SocketMessage::LeaveMulticast(_, _) => "multicast leave",
+            SocketMessage::ProcessOutput(_, _) => "process output",
+            SocketMessage::Exited(_, _) => "exit",
+            SocketMessage::Tick(_) => "tick",
+            SocketMessage::Addresses(_, _) => "addresses",
+        }
+    }
 
-pub struct PollnetSocket {
-    status: SocketStatus,
-    tx: tokio::sync::mpsc::Sender<SocketMessage>,
-    rx: std::sync::mpsc::Receiver<SocketMessage>,
-    message: Option<Vec<u8>>,
-    error: Option<String>,
-    last_client_handle: u32,
+    // Only payloads can be fanned out to several clients
+    fn duplicate(&self) -> Option<SocketMessage> {
+        match self {
+            SocketMessage::Message(msg) => Some(SocketMessage::Message(msg.clone())),
+            SocketMessage::BinaryMessage(msg) => Some(SocketMessage::BinaryMessage(msg.clone())),
+            _ => None,
+        }
+    }
 }
 
-pub struct PollnetContext {
-    sockets: HashMap<u32, Box<PollnetSocket>>,
-    next_handle: u32,
-    thread: Option<thread::JoinHandle<()>>,
-    rt_handle: tokio::runtime::Handle,
-    shutdown_tx: Option<tokio::sync::oneshot::Sender<i32>>,
+// Zero interval disables heartbeats, zero timeout never gives up on a peer
+#[derive(Copy, Clone, Default)]
+struct HeartbeatConfig {
+    interval_ms: u32,
+    timeout_ms: u32,
 }
 
-#[derive(Debug)]
-enum RecvError {
-    Empty,
-    Disconnected,
+// Settings a WS listener passes on to the clients it accepts
+#[derive(Clone, Default)]
+struct WsSettings {
+    heartbeat: HeartbeatConfig,
+    policy: WsPolicy,
+    limits: WsLimits,
+    compression: WsCompression,
 }
 
-async fn accept_ws(tcp_stream: TcpStream, addr: SocketAddr, outer_tx: std::sync::mpsc::Sender<SocketMessage>) {//rx_to_sock: tokio::sync::mpsc::Receiver<SocketMessage>, tx_from_sock: std::sync::mpsc::Sender<SocketMessage>) {
-    let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-    let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+// Sizes in bytes; zero keeps tungstenite's default (64 MiB messages,
+// 16 MiB frames, unbounded send queue)
+#[derive(Copy, Clone, Default)]
+struct WsLimits {
+    max_message_size: u32,
+    max_frame_size: u32,
+    max_send_queue: u32,
+}
 
-    outer_tx.send(SocketMessage::NewClient(ClientConn{
-        tx: tx_to_sock,
-        rx: rx_from_sock,
-        id: addr.to_string(), //"BLURGH".to_string(),
-    })).expect("this shouldn't ever break?");
+impl WsLimits {
+    fn config(&self) -> tungstenite::protocol::WebSocketConfig {
+        let defaults = tungstenite::protocol::WebSocketConfig::default();
+        let pick = |limit: u32, default: Option<usize>| if limit > 0 { Some(limit as usize) } else { default };
+        tungstenite::protocol::WebSocketConfig{
+            max_send_queue: pick(self.max_send_queue, defaults.max_send_queue),
+            max_message_size: pick(self.max_message_size, defaults.max_message_size),
+            max_frame_size: pick(self.max_frame_size, defaults.max_frame_size),
+            ..defaults
+        }
+    }
 
-    match accept_async(tcp_stream).await {
-        Ok(mut ws_stream) => {
-            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
-            loop {
-                tokio::select! {
-                    from_c_message = rx_to_sock.recv() => {
-                        match from_c_message {
-                            Some(SocketMessage::Message(msg)) => {
-                                ws_stream.send(tungstenite::protocol::Message::Text(msg)).await.expect("WS send error");
-                            },
-                            Some(SocketMessage::BinaryMessage(msg)) => {
-                                ws_stream.send(tungstenite::protocol::Message::Binary(msg)).await.expect("WS send error");
-                            },
-                            _ => break
-                        }
-                    },
-                    from_sock_message = ws_stream.next() => {
-                        match from_sock_message {
-                            Some(Ok(msg)) => {
-                                tx_from_sock.send(SocketMessage::BinaryMessage(msg.into_data())).expect("TX error on socket message");
-                            },
-                            Some(Err(msg)) => {
-                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
-                                break;
-                            },
-                            None => {
-                                tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
-                                break;
-                            }
-                        }
-                    },
-                };
-            }
-        },
-        Err(err) => {
-            error!("connection error: {}", err);
-            tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on connection error");
+    // For tungstenite under WsFrames, which checks the sizes itself
+    fn send_queue_config(&self) -> tungstenite::protocol::WebSocketConfig {
+        tungstenite::protocol::WebSocketConfig{
+            max_message_size: None,
+            max_frame_size: None,
+            ..self.config()
         }
     }
 }
 
-async fn accept_tcp(mut tcp_stream: TcpStream, addr: SocketAddr, outer_tx: Option<std::sync::mpsc::Sender<SocketMessage>>) {
-    let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-    let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+// Empty lists and an empty token let everything through
+#[derive(Clone, Default)]
+struct WsPolicy {
+    origins: Vec<String>,
+    token_name: String,
+    token: String,
+    paths: Vec<String>,
+    protocols: Vec<String>,
+}
 
-    if let Some(tx) = outer_tx {
-        tx.send(SocketMessage::NewClient(ClientConn{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            id: addr.to_string(),
-        })).expect("this shouldn't ever break?");
+impl WsPolicy {
+    // Returns the subprotocol to agree on, or the status and reason to refuse the upgrade with
+    fn check(&self, request: &tungstenite::handshake::server::Request) -> Result<Option<String>, (http::StatusCode, String)> {
+        let headers = request.headers();
+        let path = request.uri().path();
+        if !self.paths.is_empty() && !self.paths.iter().any(|allowed| allowed == path) {
+            return Err((http::StatusCode::NOT_FOUND, format!("path {} not allowed", path)));
+        }
+
+        if !self.origins.is_empty() {
+            let origin = headers.get(http::header::ORIGIN)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            if !self.origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin)) {
+                return Err((http::StatusCode::FORBIDDEN, format!("origin '{}' not allowed", origin)));
+            }
+        }
+
+        if !self.token.is_empty() {
+            // accepted either as a header ("Bearer " optional) or as a query parameter
+            let in_header = headers.get(self.token_name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value == self.token || value.strip_prefix("Bearer ") == Some(self.token.as_str()));
+            let in_query = url::form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+                .any(|(name, value)| name == self.token_name.as_str() && value == self.token.as_str());
+            if !in_header && !in_query {
+                return Err((http::StatusCode::UNAUTHORIZED, format!("missing or wrong {}", self.token_name)));
+            }
+        }
+
+        if self.protocols.is_empty() {
+            return Ok(None);
+        }
+        let offered: Vec<&str> = headers.get_all(http::header::SEC_WEBSOCKET_PROTOCOL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim())
+            .collect();
+        // our own order of preference wins
+        match self.protocols.iter().find(|protocol| offered.contains(&protocol.as_str())) {
+            Some(protocol) => Ok(Some(protocol.clone())),
+            None => Err((http::StatusCode::BAD_REQUEST, format!("no supported subprotocol in '{}'", offered.join(", ")))),
+        }
     }
+}
 
-    tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
-    let mut buf = [0; 65536];
-    loop {
-        tokio::select! {
-            from_c_message = rx_to_sock.recv() => {
-                match from_c_message {
-                    Some(SocketMessage::Message(msg)) => {
-                        tcp_stream.write_all(msg.as_bytes()).await.expect("TCP send error");
-                    },
-                    Some(SocketMessage::BinaryMessage(msg)) => {
-                        tcp_stream.write_all(&msg).await.expect("TCP send error");
-                    },
-                    _ => break
-                }
-            },
-            _ = tcp_stream.readable() => {
-                match tcp_stream.try_read(&mut buf){
-                    Ok(n) => {
-                        // TODO: can we avoid these copies? Does it matter?
-                        let submessage = buf[0..n].to_vec();
-                        tx_from_sock.send(SocketMessage::BinaryMessage(submessage)).expect("TX error on socket message");
-                    }
-                    Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-                        // no effect?
+// Runs the listener's policy on an upgrade request and remembers the outcome
+struct HandshakeCheck<'a> {
+    policy: &'a WsPolicy,
+    compression: &'a WsCompression,
+    handshake: &'a mut Option<HandshakeInfo>,
+    refusal: &'a mut Option<String>,
+}
+
+impl<'a> tungstenite::handshake::server::Callback for HandshakeCheck<'a> {
+    fn on_request(self, request: &tungstenite::handshake::server::Request, mut response: tungstenite::handshake::server::Response) -> Result<tungstenite::handshake::server::Response, tungstenite::handshake::server::ErrorResponse> {
+        match self.policy.check(request) {
+            Ok(protocol) => {
+                let mut info = HandshakeInfo::from_request(request);
+                if let Some(protocol) = protocol {
+                    if let Ok(value) = http::HeaderValue::from_str(&protocol) {
+                        response.headers_mut().insert(http::header::SEC_WEBSOCKET_PROTOCOL, value);
                     }
-                    Err(err) => {
-                        tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
-                        break;
+                    info.protocol = Some(protocol);
+                }
+                if self.compression.enabled {
+                    let values: Vec<&str> = request.headers().get_all(http::header::SEC_WEBSOCKET_EXTENSIONS).iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .collect();
+                    if let Some(value) = self.compression.accept(&deflate_offers(&values)).and_then(|value| http::HeaderValue::from_str(&value).ok()) {
+                        response.headers_mut().insert(http::header::SEC_WEBSOCKET_EXTENSIONS, value);
                     }
                 }
+                *self.handshake = Some(info);
+                Ok(response)
             },
-        };
+            Err((status, reason)) => {
+                let mut error = tungstenite::handshake::server::ErrorResponse::new(Some(reason.clone()));
+                *error.status_mut() = status;
+                *self.refusal = Some(reason);
+                Err(error)
+            },
+        }
     }
-    info!("Closing TCP socket!");
-    tcp_stream.shutdown().await.unwrap_or_default(); // if this errors we don't care
 }
 
-async fn handle_http_request<B>(req: Request<B>, static_: Option<Static>, virtual_files: Arc<RwLock<HashMap<String, Vec<u8>>>>) -> Result<Response<Body>, IoError> {
-    {
-        // Do we need like... more headers???
-        let vfiles = virtual_files.read().expect("RwLock poisoned");
-        if let Some(file_data) = vfiles.get(req.uri().path()) {
-            return Response::builder()
-                    .status(http::StatusCode::OK)
-                    .body(Body::from(file_data.clone()))
-                    .map_err(|_| IoError::new(std::io::ErrorKind::Other, "Rust errors are a pain"))
+enum KeepaliveEvent {
+    PingDue,
+    TimedOut,
+}
+
+struct Keepalive {
+    config: HeartbeatConfig,
+    interval: Option<tokio::time::Interval>,
+    pings: Vec<(Vec<u8>, Instant)>,
+    deadline: Option<tokio::time::Instant>,
+}
+
+impl Keepalive {
+    fn new(config: HeartbeatConfig) -> Keepalive {
+        let mut keepalive = Keepalive{
+            config,
+            interval: None,
+            pings: Vec::new(),
+            deadline: None,
+        };
+        keepalive.configure(config);
+        keepalive
+    }
+
+    fn configure(&mut self, config: HeartbeatConfig) {
+        self.config = config;
+        self.deadline = None;
+        self.interval = if config.interval_ms > 0 {
+            let period = std::time::Duration::from_millis(u64::from(config.interval_ms));
+            Some(tokio::time::interval_at(tokio::time::Instant::now() + period, period))
+        } else {
+            None
+        };
+    }
+
+    // Never resolves if heartbeats are off
+    async fn next_event(&mut self) -> KeepaliveEvent {
+        let deadline = self.deadline;
+        let expired = async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => future::pending::<()>().await,
+            }
+        };
+        let tick = async {
+            match &mut self.interval {
+                Some(interval) => { interval.tick().await; },
+                None => future::pending::<()>().await,
+            }
+        };
+        tokio::select! {
+            _ = expired => KeepaliveEvent::TimedOut,
+            _ = tick => KeepaliveEvent::PingDue,
         }
     }
 
-    match static_ {
-        Some(static_) => static_.clone().serve(req).await,
-        None => {
-            Response::builder().status(http::StatusCode::NOT_FOUND).body(Body::empty()).map_err(|_| IoError::new(std::io::ErrorKind::Other, "Rust errors are a pain"))
+    fn ping_sent(&mut self, mut payload: Vec<u8>) -> Vec<u8> {
+        // control frames can't carry more than 125 bytes
+        payload.truncate(125);
+        if self.pings.len() >= 16 {
+            self.pings.remove(0);
         }
+        self.pings.push((payload.clone(), Instant::now()));
+        if self.config.interval_ms > 0 && self.config.timeout_ms > 0 && self.deadline.is_none() {
+            self.deadline = Some(tokio::time::Instant::now() + std::time::Duration::from_millis(u64::from(self.config.timeout_ms)));
+        }
+        payload
     }
-}
 
-impl PollnetContext {
-    fn new() -> PollnetContext {
-        match env_logger::try_init() {
-            Err(err) => warn!("Multiple contexts created!: {}", err),
-            _ => (),
+    fn pong_received(&mut self, payload: &[u8], tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>) {
+        self.deadline = None;
+        if let Some(idx) = self.pings.iter().position(|(sent, _)| sent == payload) {
+            let (_, sent_at) = self.pings.remove(idx);
+            let rtt = sent_at.elapsed().as_secs_f64() * 1000.0;
+            tx_from_sock.send(SocketMessage::RoundTrip(rtt)).unwrap_or_default();
         }
+    }
 
-        let (handle_tx, handle_rx) = std::sync::mpsc::channel();
-        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-        let shutdown_tx = Some(shutdown_tx);
+    fn timeout_error(&self) -> String {
+        format!("heartbeat timeout: no pong within {} ms", self.config.timeout_ms)
+    }
+}
 
-        let thread = Some(thread::spawn(move || {
-            let rt = runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .expect("Unable to create the runtime");
+#[derive(Copy, Clone)]
+struct ReconnectPolicy {
+    initial_delay_ms: u32,
+    max_delay_ms: u32,
+    max_attempts: u32, // zero retries forever
+    jitter: f64,
+}
 
-            // Send handle back out so we can store it?
-            handle_tx
-                .send(rt.handle().clone())
-                .expect("Unable to give runtime handle to another thread");
+impl ReconnectPolicy {
+    // Exponential backoff, with up to `jitter` of the delay randomly shaved off
+    fn delay(&self, attempt: u32) -> std::time::Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = (f64::from(self.initial_delay_ms) * f64::from(1u32 << exponent)).min(f64::from(self.max_delay_ms.max(self.initial_delay_ms)));
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        std::time::Duration::from_secs_f64(delay * (1.0 - jitter) / 1000.0)
+    }
+}
 
-            // Continue running until notified to shutdown
-            info!("tokio runtime starting");
-            rt.block_on(async {
-                shutdown_rx.await.unwrap();
-                // uh let's just put in a 'safety' delay to shut everything down?
-                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-            });
-            rt.shutdown_timeout(std::time::Duration::from_millis(200));
-            info!("tokio runtime shutdown");
-        }));
+enum ConnectionLoss {
+    Error(String),
+    Disconnect,
+}
 
-        PollnetContext{
-            next_handle: 1,
-            rt_handle: handle_rx.recv().unwrap(),
-            thread: thread,
-            shutdown_tx: shutdown_tx,
-            sockets: HashMap::new()
+// Messages sent while a client is reconnecting wait for the next connection,
+// up to this many; past that the oldest are dropped
+const MAX_RECONNECT_QUEUE: usize = 100;
+
+// Keeps the state that has to survive between connection attempts of a client
+struct Reconnector {
+    policy: Option<ReconnectPolicy>,
+    hellos: Vec<String>,
+    pending: std::collections::VecDeque<SocketMessage>,
+    overflowed: bool,
+    attempts: u32,
+    connected_once: bool,
+}
+
+impl Reconnector {
+    fn new() -> Reconnector {
+        Reconnector{
+            policy: None,
+            hellos: Vec::new(),
+            pending: std::collections::VecDeque::new(),
+            overflowed: false,
+            attempts: 0,
+            connected_once: false,
         }
     }
 
-    fn _next_handle_that_satisfies_the_borrow_checker(next_handle: &mut u32) -> u32 {
-        let new_handle: u32 = *next_handle;
-        *next_handle += 1;
-        new_handle   
+    // Reports the connection and returns the messages to send before anything else
+    fn connected(&mut self, tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>) -> Vec<SocketMessage> {
+        if self.connected_once {
+            tx_from_sock.send(SocketMessage::Reconnected).expect("TX error on reconnect");
+        } else {
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+        }
+        self.connected_once = true;
+        self.attempts = 0;
+        self.overflowed = false;
+        let mut outbox: Vec<SocketMessage> = self.hellos.iter().cloned().map(SocketMessage::Message).collect();
+        outbox.extend(self.pending.drain(..));
+        outbox
     }
 
-    fn _next_handle(&mut self) -> u32 {
-        PollnetContext::_next_handle_that_satisfies_the_borrow_checker(&mut self.next_handle)
+    // Returns false if the host closed the socket; settings the reconnector
+    // doesn't know about are handed to `apply`. The first message dropped
+    // from a full queue is reported as a warning, once per outage.
+    fn queue<F: FnMut(SocketMessage)>(&mut self, msg: SocketMessage, tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>, apply: &mut F) -> bool {
+        match msg {
+            SocketMessage::SetReconnect(policy) => self.policy = Some(policy),
+            SocketMessage::AddHello(msg) => self.hellos.push(msg),
+            SocketMessage::Message(_) | SocketMessage::BinaryMessage(_) => {
+                if self.pending.len() >= MAX_RECONNECT_QUEUE {
+                    self.pending.pop_front();
+                    if !self.overflowed {
+                        warn!("Reconnect queue is full, dropping the oldest messages");
+                        let warning = format!("More than {} messages sent while reconnecting, the oldest are being dropped", MAX_RECONNECT_QUEUE);
+                        tx_from_sock.send(SocketMessage::Warning(warning)).unwrap_or_default();
+                    }
+                    self.overflowed = true;
+                }
+                self.pending.push_back(msg);
+            },
+            SocketMessage::Disconnect | SocketMessage::Close(_, _) | SocketMessage::Finish(_) => return false,
+            other => apply(other),
+        }
+        true
     }
 
-    fn serve_http(&mut self, bind_addr: String, serve_dir: Option<String>) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
-
-        // Spawn a future onto the runtime
-        self.rt_handle.spawn(async move {
-            info!("HTTP server spawned");
-            let addr = bind_addr.parse();
-            if let Err(_) = addr {
-                error!("Invalid TCP address: {}", bind_addr);
-                tx_from_sock.send(SocketMessage::Error("Invalid TCP address".to_string())).unwrap_or_default();
-                return;
+    // Waits out the backoff delay, or reports the loss and returns false if we should give up
+    async fn retry<F: FnMut(SocketMessage)>(&mut self, rx_to_sock: &mut tokio::sync::mpsc::Receiver<SocketMessage>, tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>, loss: ConnectionLoss, mut apply: F) -> bool {
+        // a policy sent right after opening may not have been seen yet if the first connect failed
+        loop {
+            match rx_to_sock.try_recv() {
+                Ok(msg) => {
+                    if !self.queue(msg, tx_from_sock, &mut apply) {
+                        return false;
+                    }
+                },
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break,
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => return false,
             }
-            let addr = addr.unwrap();
-
-            let static_ = match serve_dir {
-                Some(path_string) => Some(Static::new(Path::new(&path_string))),
-                None => None
-            };
+        }
 
-            let virtual_files: HashMap<String, Vec<u8>> = HashMap::new();
-            let virtual_files = Arc::new(RwLock::new(virtual_files));
-            let virtual_files_two_the_clone_wars = virtual_files.clone();
+        let policy = match self.policy {
+            Some(policy) if policy.max_attempts == 0 || self.attempts < policy.max_attempts => policy,
+            _ => {
+                let final_message = match loss {
+                    ConnectionLoss::Error(err) => SocketMessage::Error(err),
+                    ConnectionLoss::Disconnect => SocketMessage::Disconnect,
+                };
+                tx_from_sock.send(final_message).unwrap_or_default();
+                return false;
+            }
+        };
 
-            let make_service = make_service_fn(|_| {
-                // Rust demands all these clones for reasons I don't fully understand
-                // I definitely feel so much safer though!
-                let static_ = static_.clone();
-                let virtual_files = virtual_files.clone();
-                future::ok::<_, hyper::Error>(service_fn(move |req| handle_http_request(req, static_.clone(), virtual_files.clone())))
-            });
+        self.attempts += 1;
+        let delay = policy.delay(self.attempts);
+        let reason = match loss {
+            ConnectionLoss::Error(err) => err,
+            ConnectionLoss::Disconnect => "connection closed".to_string(),
+        };
+        warn!("Connection lost ({}), reconnect attempt {} in {:?}", reason, self.attempts, delay);
+        tx_from_sock.send(SocketMessage::Reconnecting(reason)).unwrap_or_default();
 
-            let server = hyper::Server::try_bind(&addr);
-            if let Err(bind_err) = server {
-                error!("Couldn't bind {}: {}", bind_addr, bind_err);
-                tx_from_sock.send(SocketMessage::Error(bind_err.to_string())).unwrap_or_default();
-                return;
-            }
-            let server = server.unwrap().serve(make_service);
-            let graceful = server.with_graceful_shutdown(async move {
-                let virtual_files = virtual_files_two_the_clone_wars.clone();
-                loop {
-                    match rx_to_sock.recv().await {
-                        Some(SocketMessage::Disconnect) | Some(SocketMessage::Error(_)) | None => {
-                            break
-                        },
-                        Some(SocketMessage::FileAdd(filename, filedata)) => {
-                            let mut vfiles = virtual_files.write().expect("Lock is poisoned");
-                            vfiles.insert(filename, filedata);
-                        },
-                        Some(SocketMessage::FileRemove(filename)) => {
-                            let mut vfiles = virtual_files.write().expect("Lock is poisoned");
-                            vfiles.remove(&filename);
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                from_c_message = rx_to_sock.recv() => {
+                    match from_c_message {
+                        Some(msg) => {
+                            if !self.queue(msg, tx_from_sock, &mut apply) {
+                                return false;
+                            }
                         },
-                        _ => {} // ignore sends?
+                        None => return false,
                     }
-                }
-                info!("HTTP server trying to gracefully exit?");
-            });
-            info!("HTTP server running on http://{}/", addr);
-            if let Err(err) = graceful.await {
-                tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default(); // don't care at this point
+                },
             }
-            info!("HTTP server stopped.");
-        });
+        }
+    }
+}
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
-        });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+// For clients this describes the server's response, for servers the client's request
+struct HandshakeInfo {
+    path: String,
+    query: String,
+    headers: String,
+    protocol: Option<String>,
+}
 
-        new_handle
+fn format_headers(headers: &http::HeaderMap) -> String {
+    headers.iter()
+        .map(|(name, value)| format!("{}: {}\n", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect()
+}
+
+impl HandshakeInfo {
+    fn from_headers(headers: &http::HeaderMap) -> HandshakeInfo {
+        let protocol = headers.get(http::header::SEC_WEBSOCKET_PROTOCOL)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        HandshakeInfo{
+            path: String::new(),
+            query: String::new(),
+            headers: format_headers(headers),
+            protocol,
+        }
     }
 
-    fn listen_ws(&mut self, addr: String) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+    fn from_request(request: &tungstenite::handshake::server::Request) -> HandshakeInfo {
+        HandshakeInfo{
+            path: request.uri().path().to_string(),
+            query: request.uri().query().unwrap_or("").to_string(),
+            headers: format_headers(request.headers()),
+            protocol: None,
+        }
+    }
+}
 
-        self.rt_handle.spawn(async move {
-            info!("WS server spawned");
-            let listener = match TcpListener::bind(&addr).await {
-                Ok(listener) => listener,
-                Err(tcp_err) => {
-                    tx_from_sock.send(SocketMessage::Error(tcp_err.to_string())).unwrap_or_default();
-                    return;
-                }
-            };
-            info!("WS server waiting for connections on {}", addr);
-            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");                    
-            loop {
-                tokio::select! {
-                    from_c_message = rx_to_sock.recv() => {
-                        match from_c_message {
-                            Some(SocketMessage::Message(_msg)) => {}, // server socket ignores sends
-                            _ => break
-                        }
-                    },
-                    new_client = listener.accept() => {
-                        match new_client {
-                            Ok((tcp_stream, addr)) => {
-                                tokio::spawn(accept_ws(tcp_stream, addr, tx_from_sock.clone()));
-                            },
-                            Err(msg) => {
-                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
-                                break;
-                            }
-                        }
-                    },
-                };
-            }
+// Zero means "unlimited" for every field
+#[derive(Copy, Clone, Default)]
+struct ListenerLimits {
+    max_clients: u32,
+    max_per_ip: u32,
+    rate: f64,
+    burst: u32,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Shared between a listener and the guards of the connections it accepted
+struct ConnectionLimiter {
+    limits: ListenerLimits,
+    active: u32,
+    active_per_ip: HashMap<IpAddr, u32>,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    fn new() -> Arc<Mutex<ConnectionLimiter>> {
+        Arc::new(Mutex::new(ConnectionLimiter{
+            limits: ListenerLimits::default(),
+            active: 0,
+            active_per_ip: HashMap::new(),
+            buckets: HashMap::new(),
+        }))
+    }
+
+    fn set_limits(&mut self, limits: ListenerLimits) {
+        self.limits = limits;
+        self.buckets.clear();
+    }
+
+    fn take_token(&mut self, ip: IpAddr) -> bool {
+        if self.limits.rate <= 0.0 {
+            return true;
+        }
+        let capacity = f64::from(self.limits.burst.max(1));
+        let rate = self.limits.rate;
+        let now = Instant::now();
+        if self.buckets.len() > 1024 {
+            // forget about addresses that have been quiet long enough to refill
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * rate < capacity
+            });
+        }
+        let bucket = self.buckets.entry(ip).or_insert(TokenBucket{
+            tokens: capacity,
+            last_refill: now,
         });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
+    // HTTP servers rate limit individual requests rather than connections,
+    // so they admit connections with `count_as_request` set to false
+    // Peers without an address (Unix sockets) only count towards max_clients
+    fn admit(limiter: &Arc<Mutex<ConnectionLimiter>>, ip: Option<IpAddr>, count_as_request: bool) -> Result<ConnectionGuard, String> {
+        let mut inner = limiter.lock().expect("Limiter lock poisoned");
+        let limits = inner.limits;
+        if limits.max_clients > 0 && inner.active >= limits.max_clients {
+            return Err("too many clients".to_string());
+        }
+        if let Some(ip) = ip {
+            let per_ip = inner.active_per_ip.get(&ip).copied().unwrap_or(0);
+            if limits.max_per_ip > 0 && per_ip >= limits.max_per_ip {
+                return Err("too many connections from this address".to_string());
+            }
+            if count_as_request && !inner.take_token(ip) {
+                return Err("rate limited".to_string());
+            }
+            inner.active_per_ip.insert(ip, per_ip + 1);
+        }
+        inner.active += 1;
+        Ok(ConnectionGuard{
+            limiter: limiter.clone(),
+            ip,
+        })
+    }
+
+    fn release(&mut self, ip: Option<IpAddr>) {
+        self.active = self.active.saturating_sub(1);
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        if let Some(count) = self.active_per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.active_per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+// Counts as an active connection until dropped
+struct ConnectionGuard {
+    limiter: Arc<Mutex<ConnectionLimiter>>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut limiter) = self.limiter.lock() {
+            limiter.release(self.ip);
+        }
+    }
+}
+
+// The clients of one listener and the named groups they have joined
+struct ClientGroups {
+    next_id: u64,
+    clients: HashMap<u64, tokio::sync::mpsc::Sender<SocketMessage>>,
+    groups: HashMap<String, std::collections::HashSet<u64>>,
+}
+
+impl ClientGroups {
+    fn new() -> Arc<Mutex<ClientGroups>> {
+        Arc::new(Mutex::new(ClientGroups{
+            next_id: 0,
+            clients: HashMap::new(),
+            groups: HashMap::new(),
+        }))
+    }
+
+    fn register(groups: &Arc<Mutex<ClientGroups>>, tx: tokio::sync::mpsc::Sender<SocketMessage>) -> GroupMembership {
+        let mut inner = groups.lock().expect("Groups lock poisoned");
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.clients.insert(id, tx);
+        GroupMembership{
+            groups: groups.clone(),
+            id,
+        }
+    }
+
+    // Sends to every client, or only to the members of `group`
+    fn broadcast(&self, group: Option<&str>, msg: &SocketMessage) {
+        let send_to = |id: &u64| {
+            if let (Some(tx), Some(msg)) = (self.clients.get(id), msg.duplicate()) {
+                // a client that can't keep up misses the message, same as with a plain send
+                tx.try_send(msg).unwrap_or_default();
+            }
+        };
+        match group {
+            Some(group) => self.groups.get(group).into_iter().flatten().for_each(send_to),
+            None => self.clients.keys().for_each(send_to),
+        }
+    }
+}
+
+// Keeps an accepted client reachable by broadcasts until dropped
+struct GroupMembership {
+    groups: Arc<Mutex<ClientGroups>>,
+    id: u64,
+}
+
+impl GroupMembership {
+    fn join(&self, group: String) {
+        let mut inner = self.groups.lock().expect("Groups lock poisoned");
+        inner.groups.entry(group).or_default().insert(self.id);
+    }
+
+    fn leave(&self, group: &str) {
+        let mut inner = self.groups.lock().expect("Groups lock poisoned");
+        if let Some(members) = inner.groups.get_mut(group) {
+            members.remove(&self.id);
+            if members.is_empty() {
+                inner.groups.remove(group);
+            }
+        }
+    }
+}
+
+impl Drop for GroupMembership {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.groups.lock() {
+            let id = self.id;
+            inner.clients.remove(&id);
+            inner.groups.retain(|_, members| {
+                members.remove(&id);
+                !members.is_empty()
+            });
+        }
+    }
+}
+
+
+pub struct PollnetSocket {
+    status: SocketStatus,
+    tx: tokio::sync::mpsc::Sender<SocketMessage>,
+    rx: std::sync::mpsc::Receiver<SocketMessage>,
+    message: Option<Vec<u8>>,
+    message_type: MessageType,
+    error: Option<String>,
+    last_client_handle: u32,
+    handshake: Option<HandshakeInfo>,
+    close_code: u16,
+    close_reason: Option<String>,
+    rtt_ms: f64,
+    membership: Option<GroupMembership>,
+    message_source: Option<String>,
+    exit_code: Option<i32>,
+    local_addr: Option<String>,
+    peer_addr: Option<String>,
+}
+
+impl PollnetSocket {
+    fn new(tx: tokio::sync::mpsc::Sender<SocketMessage>, rx: std::sync::mpsc::Receiver<SocketMessage>, status: SocketStatus) -> Box<PollnetSocket> {
+        Box::new(PollnetSocket{
+            tx,
+            rx,
+            status,
             message: None,
+            message_type: MessageType::NONE,
             error: None,
-            last_client_handle: 0
-        });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+            last_client_handle: 0,
+            handshake: None,
+            close_code: 0,
+            close_reason: None,
+            rtt_ms: -1.0,
+            membership: None,
+            message_source: None,
+            exit_code: None,
+            local_addr: None,
+            peer_addr: None,
+        })
+    }
+}
 
-        new_handle
+pub struct PollnetContext {
+    sockets: HashMap<u32, Box<PollnetSocket>>,
+    next_handle: u32,
+    thread: Option<thread::JoinHandle<()>>,
+    rt_handle: tokio::runtime::Handle,
+    shutdown_tx: Option<tokio::sync::oneshot::Sender<i32>>,
+    ws_limits: WsLimits,
+    ws_compression: WsCompression,
+    tls: TlsOptions,
+    tcp_options: TcpOptions,
+    udp_options: UdpOptions,
+    dns_server: Option<SocketAddr>,
+    proxy: ProxyOptions,
+}
+
+#[derive(Debug)]
+enum RecvError {
+    Empty,
+    Disconnected,
+}
+
+fn make_close_frame(code: u16, reason: String) -> tungstenite::protocol::CloseFrame<'static> {
+    tungstenite::protocol::CloseFrame{
+        code: code.into(),
+        reason: reason.into(),
     }
+}
 
-    fn listen_tcp(&mut self, addr: String) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+// Codes a close frame may carry on the wire (RFC 6455 section 7.4); 1005, 1006
+// and 1015 are only ever reported locally, anything else falls back to 1000
+fn sendable_close_code(code: u32) -> u16 {
+    match code {
+        1000..=1003 | 1007..=1014 | 3000..=4999 => code as u16,
+        _ => {
+            warn!("Close code {} can't be sent, using 1000 instead", code);
+            1000
+        }
+    }
+}
 
-        self.rt_handle.spawn(async move {
-            info!("TCP server spawned");
-            let listener = match TcpListener::bind(&addr).await {
-                Ok(listener) => listener,
-                Err(tcp_err) => {
-                    tx_from_sock.send(SocketMessage::Error(tcp_err.to_string())).unwrap_or_default();
-                    return;
-                }
+// A control frame payload is at most 125 bytes, two of which are the code
+const MAX_CLOSE_REASON: usize = 123;
+
+fn truncate_close_reason(mut reason: String) -> String {
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    reason
+}
+
+// Oversized messages get a "message too big" close, anything else just drops the connection
+fn close_frame_for_error(err: &tungstenite::Error) -> Option<tungstenite::protocol::CloseFrame<'static>> {
+    match err {
+        tungstenite::Error::Capacity(reason) => Some(make_close_frame(1009, reason.to_string())),
+        tungstenite::Error::Io(err) => err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<MessageTooBig>())
+            .map(|too_big| make_close_frame(1009, truncate_close_reason(too_big.0.clone()))),
+        _ => None,
+    }
+}
+
+// Returns true if the message was a close frame
+fn forward_ws_message(msg: tungstenite::protocol::Message, tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>) -> bool {
+    use tungstenite::protocol::Message;
+    match msg {
+        Message::Text(text) => {
+            tx_from_sock.send(SocketMessage::Message(text)).expect("TX error on socket message");
+        },
+        Message::Binary(data) => {
+            tx_from_sock.send(SocketMessage::BinaryMessage(data)).expect("TX error on socket message");
+        },
+        Message::Close(frame) => {
+            // 1005 is the reserved "no status received" code
+            let (code, reason) = match frame {
+                Some(frame) => (frame.code.into(), frame.reason.into_owned()),
+                None => (1005, String::new()),
             };
-            info!("TCP server waiting for connections on {}", addr);
-            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");                    
-            loop {
-                tokio::select! {
-                    from_c_message = rx_to_sock.recv() => {
-                        match from_c_message {
-                            Some(SocketMessage::Message(_msg)) => {}, // server socket ignores sends
-                            _ => break
-                        }
-                    },
-                    new_client = listener.accept() => {
-                        match new_client {
-                            Ok((tcp_stream, addr)) => {
-                                tokio::spawn(accept_tcp(tcp_stream, addr, Some(tx_from_sock.clone())));
-                            },
-                            Err(msg) => {
-                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
-                                break;
-                            }
-                        }
-                    },
-                };
+            tx_from_sock.send(SocketMessage::Close(code, reason)).expect("TX error on socket close");
+            return true;
+        },
+        // tungstenite answers pings by itself
+        Message::Ping(_) | Message::Pong(_) => {},
+    }
+    false
+}
+
+// permessage-deflate (RFC 7692). window_bits (9-15) caps the LZ77 window in
+// both directions: smaller windows save memory at the cost of ratio
+#[derive(Copy, Clone)]
+struct WsCompression {
+    enabled: bool,
+    window_bits: u8,
+}
+
+impl Default for WsCompression {
+    fn default() -> WsCompression {
+        WsCompression{enabled: false, window_bits: 15}
+    }
+}
+
+// The flush marker every compressed message ends with, which isn't sent
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+type ExtensionParams = Vec<(String, Option<String>)>;
+
+// The parameters of every permessage-deflate entry in Sec-WebSocket-Extensions
+// header values, in order; other extensions are skipped
+fn deflate_offers(values: &[&str]) -> Vec<ExtensionParams> {
+    values.iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|extension| {
+            let mut parts = extension.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+                return None;
             }
-        });
+            Some(parts.filter(|part| !part.is_empty()).map(|part| match part.split_once('=') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+                None => (part.to_ascii_lowercase(), None),
+            }).collect())
+        })
+        .collect()
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+impl WsCompression {
+    // 0 picks the largest window
+    fn new(enabled: bool, window_bits: u32) -> Option<WsCompression> {
+        match window_bits {
+            0 => Some(WsCompression{enabled, window_bits: 15}),
+            9..=15 => Some(WsCompression{enabled, window_bits: window_bits as u8}),
+            _ => None,
+        }
+    }
+
+    // The client's Sec-WebSocket-Extensions value
+    fn offer(&self) -> String {
+        if self.window_bits < 15 {
+            format!("permessage-deflate; client_max_window_bits={0}; server_max_window_bits={0}", self.window_bits)
+        } else {
+            "permessage-deflate; client_max_window_bits".to_string()
+        }
+    }
+
+    // The server's answer to the first offer it can take, if there's one
+    fn accept(&self, offers: &[ExtensionParams]) -> Option<String> {
+        offers.iter().find_map(|params| self.answer(params))
+    }
+
+    fn answer(&self, params: &ExtensionParams) -> Option<String> {
+        let mut response = vec!["permessage-deflate".to_string()];
+        let mut client_bits = None;
+        let mut server_bits = 15;
+        for (idx, (name, value)) in params.iter().enumerate() {
+            if params[..idx].iter().any(|(seen, _)| seen == name) {
+                return None;
+            }
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => response.push(name.clone()),
+                ("client_no_context_takeover", None) => {},
+                ("server_max_window_bits", Some(value)) => server_bits = parse_window_bits(value)?,
+                ("client_max_window_bits", None) => client_bits = Some(15),
+                ("client_max_window_bits", Some(value)) => client_bits = Some(parse_window_bits(value)?),
+                _ => return None,
+            }
+        }
+        let server_bits = server_bits.min(self.window_bits);
+        if server_bits < 15 {
+            response.push(format!("server_max_window_bits={}", server_bits));
+        }
+        // clients that didn't mention client_max_window_bits can't be held to one
+        if let Some(client_bits) = client_bits.map(|bits| bits.min(self.window_bits)).filter(|bits| *bits < 15) {
+            response.push(format!("client_max_window_bits={}", client_bits));
+        }
+        Some(response.join("; "))
+    }
+}
+
+// What the handshake response settled on for the messages we send
+#[derive(Debug, PartialEq)]
+struct DeflateAgreement {
+    window_bits: u8,
+    no_context_takeover: bool,
+}
+
+fn deflate_agreement(params: &ExtensionParams, server: bool) -> Result<DeflateAgreement, String> {
+    let (ours, theirs) = if server { ("server_", "client_") } else { ("client_", "server_") };
+    let mut agreement = DeflateAgreement{window_bits: 15, no_context_takeover: false};
+    for (name, value) in params {
+        let (side, param) = match (name.strip_prefix(ours), name.strip_prefix(theirs)) {
+            (Some(param), _) => (true, param),
+            (_, Some(param)) => (false, param),
+            _ => return Err(format!("unknown permessage-deflate parameter {}", name)),
+        };
+        match (param, value) {
+            ("no_context_takeover", None) => agreement.no_context_takeover |= side,
+            ("max_window_bits", Some(value)) => {
+                let bits = parse_window_bits(value).ok_or_else(|| format!("invalid {}", name))?;
+                if side {
+                    agreement.window_bits = bits;
+                }
+            },
+            _ => return Err(format!("invalid permessage-deflate parameter {}", name)),
+        }
+    }
+    Ok(agreement)
+}
+
+struct Deflate {
+    // zlib can't hold itself to a 256 byte window, so then we send uncompressed
+    compress: Option<flate2::Compress>,
+    decompress: flate2::Decompress,
+    reset_compress: bool,
+}
+
+impl Deflate {
+    fn new(agreement: DeflateAgreement) -> Deflate {
+        let level = flate2::Compression::default();
+        Deflate{
+            compress: Some(agreement.window_bits).filter(|bits| *bits >= 9).map(|bits| flate2::Compress::new_with_window_bits(level, false, bits)),
+            // a full window inflates whatever smaller one the peer uses
+            decompress: flate2::Decompress::new_with_window_bits(false, 15),
+            reset_compress: agreement.no_context_takeover,
+        }
+    }
+
+    fn compresses(&self) -> bool {
+        self.compress.is_some()
+    }
+
+    // Compresses the next part of a message, which fin ends
+    fn deflate(&mut self, mut input: &[u8], fin: bool) -> Result<Vec<u8>, IoError> {
+        let compress = self.compress.as_mut().expect("deflating without a compressor");
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(1024));
+            }
+            let consumed = compress.total_in();
+            compress.compress_vec(input, &mut out, flate2::FlushCompress::Sync).map_err(IoError::other)?;
+            input = &input[(compress.total_in() - consumed) as usize..];
+            // room to spare means the flush is complete
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if fin {
+            if out.ends_with(&DEFLATE_TAIL) {
+                out.truncate(out.len() - DEFLATE_TAIL.len());
+            }
+            if self.reset_compress {
+                compress.reset();
+            }
+        }
+        Ok(out)
+    }
+
+    // True when the peer ended the stream with a final block
+    fn inflate(&mut self, mut input: &[u8], out: &mut Vec<u8>, max_size: usize) -> Result<bool, IoError> {
+        loop {
+            if out.capacity() - out.len() < 1024 {
+                out.reserve((input.len() * 2).max(16 << 10));
+            }
+            let (consumed, produced) = (self.decompress.total_in(), out.len());
+            let status = self.decompress.decompress_vec(input, out, flate2::FlushDecompress::Sync)
+                .map_err(|err| IoError::new(std::io::ErrorKind::InvalidData, err))?;
+            input = &input[(self.decompress.total_in() - consumed) as usize..];
+            if out.len() > max_size {
+                return Err(IoError::other(MessageTooBig(format!("message inflates past {} bytes", max_size))));
+            }
+            if status == flate2::Status::StreamEnd {
+                // a final block ends this message's stream, the next starts afresh
+                self.decompress.reset(false);
+                return Ok(true);
+            }
+            let stalled = self.decompress.total_in() == consumed && out.len() == produced;
+            if (input.is_empty() && out.len() < out.capacity()) || stalled {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+// Reported for a message over the size limit, which gets a 1009 close
+#[derive(Debug)]
+struct MessageTooBig(String);
+
+impl std::fmt::Display for MessageTooBig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MessageTooBig {}
+
+#[derive(Copy, Clone)]
+struct FrameHeader {
+    first: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+    size: usize,
+}
+
+impl FrameHeader {
+    fn parse(data: &[u8]) -> Option<FrameHeader> {
+        let (first, second) = (*data.first()?, *data.get(1)?);
+        let (len, mut size) = match second & 0x7f {
+            126 => (u64::from(u16::from_be_bytes(data.get(2..4)?.try_into().ok()?)), 4),
+            127 => (u64::from_be_bytes(data.get(2..10)?.try_into().ok()?), 10),
+            len => (u64::from(len), 2),
+        };
+        let mask = if second & 0x80 != 0 {
+            size += 4;
+            Some(data.get(size - 4..size)?.try_into().ok()?)
+        } else {
+            None
+        };
+        Some(FrameHeader{first, mask, len, size})
+    }
+
+    fn fin(&self) -> bool {
+        self.first & 0x80 != 0
+    }
+
+    fn rsv1(&self) -> bool {
+        self.first & 0x40 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.first & 0x0f
+    }
+
+    // Appends the frame, masked with the same key as the original
+    fn write_frame(&self, first: u8, mut payload: Vec<u8>, out: &mut Vec<u8>) {
+        out.push(first);
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => out.push(mask_bit | len as u8),
+            len if len <= 0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        if let Some(mask) = self.mask {
+            out.extend_from_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        out.extend_from_slice(&payload);
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (idx, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[idx % 4];
+    }
+}
+
+const HEAD_END: &[u8; 4] = b"\r\n\r\n";
+
+// Looks for the blank line ending an HTTP head, carrying how much of it the
+// bytes so far ended with; gives the offset just past it if it's in data
+fn head_end(matched: &mut usize, data: &[u8]) -> Option<usize> {
+    for (idx, byte) in data.iter().enumerate() {
+        *matched = if *byte == HEAD_END[*matched] { *matched + 1 } else if *byte == b'\r' { 1 } else { 0 };
+        if *matched == HEAD_END.len() {
+            return Some(idx + 1);
+        }
+    }
+    None
+}
+
+fn check_limit(what: &str, size: u64, limit: Option<usize>) -> Result<(), IoError> {
+    match limit {
+        Some(limit) if size > limit as u64 => Err(IoError::other(MessageTooBig(format!("{} of {} bytes is over the {} byte limit", what, size, limit)))),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Copy, Clone)]
+enum ReadState {
+    Head(usize), // how much of the blank line ending the HTTP head we've seen
+    Header,
+    Payload(u64),
+}
+
+// Follows the incoming frames without touching them, to hold messages to
+// limits that can change after the handshake
+struct FrameSizes {
+    state: ReadState,
+    header: Vec<u8>, // a frame header split between reads
+    message_size: usize,
+}
+
+impl FrameSizes {
+    fn new() -> FrameSizes {
+        FrameSizes{state: ReadState::Head(0), header: Vec::new(), message_size: 0}
+    }
+
+    // Stops at the end of the HTTP head, if it's in data, and says where that is
+    fn scan(&mut self, data: &[u8], limits: &tungstenite::protocol::WebSocketConfig) -> Result<Option<usize>, IoError> {
+        let mut pos = 0;
+        while pos < data.len() {
+            match self.state {
+                ReadState::Head(mut matched) => {
+                    let end = head_end(&mut matched, &data[pos..]);
+                    self.state = ReadState::Head(matched);
+                    if let Some(end) = end {
+                        self.state = ReadState::Header;
+                        return Ok(Some(pos + end));
+                    }
+                    return Ok(None);
+                },
+                ReadState::Header => {
+                    self.header.push(data[pos]);
+                    pos += 1;
+                    if let Some(header) = FrameHeader::parse(&self.header) {
+                        self.header.clear();
+                        // control frames are tungstenite's to check
+                        if header.opcode() < 8 {
+                            check_limit("frame", header.len, limits.max_frame_size)?;
+                            if header.opcode() != 0 {
+                                self.message_size = 0;
+                            }
+                            self.message_size = self.message_size.saturating_add(header.len as usize);
+                            check_limit("message", self.message_size as u64, limits.max_message_size)?;
+                        }
+                        self.state = ReadState::Payload(header.len);
+                    }
+                },
+                ReadState::Payload(left) => {
+                    let count = (data.len() - pos).min(usize::try_from(left).unwrap_or(usize::MAX));
+                    pos += count;
+                    self.state = if count as u64 == left { ReadState::Header } else { ReadState::Payload(left - count as u64) };
+                },
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum FrameState {
+    Header,
+    Passthrough(u64),
+}
+
+// The frame rewriting for a connection that agreed on permessage-deflate:
+// compressed frames are inflated on the way in, messages compressed on the way out
+struct DeflateFrames {
+    deflate: Deflate,
+    read_state: FrameState,
+    raw_in: Vec<u8>,
+    ready_in: Vec<u8>,
+    ready_pos: usize,
+    in_compressed: bool,
+    in_size: usize,
+    pending_out: Vec<u8>,
+    // the first frame of a message decides for its continuations
+    out_compressed: bool,
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl DeflateFrames {
+    fn new(deflate: Deflate, raw_in: Vec<u8>) -> DeflateFrames {
+        DeflateFrames{
+            deflate,
+            read_state: FrameState::Header,
+            raw_in,
+            ready_in: Vec::new(),
+            ready_pos: 0,
+            in_compressed: false,
+            in_size: 0,
+            pending_out: Vec::new(),
+            out_compressed: false,
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+
+    fn process_incoming(&mut self, limits: &tungstenite::protocol::WebSocketConfig) -> Result<(), IoError> {
+        let mut start = 0;
+        let result = self.read_frames(limits, &mut start);
+        self.raw_in.drain(..start);
+        result
+    }
+
+    fn read_frames(&mut self, limits: &tungstenite::protocol::WebSocketConfig, start: &mut usize) -> Result<(), IoError> {
+        loop {
+            let data = &self.raw_in[*start..];
+            match self.read_state {
+                FrameState::Passthrough(left) => {
+                    let count = data.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+                    self.ready_in.extend_from_slice(&data[..count]);
+                    *start += count;
+                    if count as u64 == left {
+                        self.read_state = FrameState::Header;
+                    } else {
+                        self.read_state = FrameState::Passthrough(left - count as u64);
+                        return Ok(());
+                    }
+                },
+                FrameState::Header => {
+                    let header = match FrameHeader::parse(data) {
+                        Some(header) => header,
+                        None => return Ok(()),
+                    };
+                    // control frames are tungstenite's to check, and leave
+                    // the message they come in the middle of as it is
+                    let data_frame = header.opcode() < 8;
+                    let compressed = match header.opcode() {
+                        1 | 2 => {
+                            self.in_compressed = header.rsv1();
+                            self.in_size = 0;
+                            self.in_compressed
+                        },
+                        // RSV1 where it doesn't belong is tungstenite's to reject
+                        0 => self.in_compressed && !header.rsv1(),
+                        _ => false,
+                    };
+                    if data_frame {
+                        check_limit("frame", header.len, limits.max_frame_size)?;
+                    }
+                    if !compressed {
+                        if data_frame {
+                            self.in_size = self.in_size.saturating_add(header.len as usize);
+                            check_limit("message", self.in_size as u64, limits.max_message_size)?;
+                        }
+                        self.ready_in.extend_from_slice(&data[..header.size]);
+                        *start += header.size;
+                        self.read_state = FrameState::Passthrough(header.len);
+                        continue;
+                    }
+                    let end = header.size + header.len as usize;
+                    if data.len() < end {
+                        return Ok(());
+                    }
+                    let mut payload = data[header.size..end].to_vec();
+                    *start += end;
+                    self.inflate_frame(header, &mut payload, limits)?;
+                },
+            }
+        }
+    }
+
+    fn inflate_frame(&mut self, header: FrameHeader, payload: &mut [u8], limits: &tungstenite::protocol::WebSocketConfig) -> Result<(), IoError> {
+        if let Some(mask) = header.mask {
+            apply_mask(payload, mask);
+        }
+        let max_message = limits.max_message_size.unwrap_or(usize::MAX);
+        let mut inflated = Vec::new();
+        let room = max_message.saturating_sub(self.in_size);
+        let ended = self.deflate.inflate(payload, &mut inflated, room)?;
+        if header.fin() && !ended {
+            self.deflate.inflate(&DEFLATE_TAIL, &mut inflated, room)?;
+        }
+        self.in_size += inflated.len();
+        // what inflates past the frame size limit goes on as several frames
+        let chunk_size = limits.max_frame_size.unwrap_or(usize::MAX).max(1);
+        let chunks = inflated.len().div_ceil(chunk_size).max(1);
+        let mut rest = inflated;
+        for idx in 0..chunks {
+            let tail = rest.split_off(rest.len().min(chunk_size));
+            let opcode = if idx == 0 { header.opcode() } else { 0 };
+            let fin = if idx + 1 == chunks { header.first & 0x80 } else { 0 };
+            header.write_frame(fin | (header.first & 0x30) | opcode, rest, &mut self.ready_in);
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    fn process_outgoing(&mut self, compress: bool) -> Result<(), IoError> {
+        let mut start = 0;
+        let result = self.write_frames(compress, &mut start);
+        self.pending_out.drain(..start);
+        result
+    }
+
+    fn write_frames(&mut self, compress: bool, start: &mut usize) -> Result<(), IoError> {
+        loop {
+            let data = &self.pending_out[*start..];
+            let header = match FrameHeader::parse(data) {
+                Some(header) => header,
+                None => return Ok(()),
+            };
+            let end = header.size + header.len as usize;
+            if data.len() < end {
+                return Ok(());
+            }
+            let frame = &data[..end];
+            *start += end;
+            match header.opcode() {
+                1 | 2 => self.out_compressed = compress && !header.rsv1() && self.deflate.compresses(),
+                0 => {},
+                _ => {
+                    self.out.extend_from_slice(frame);
+                    continue;
+                },
+            }
+            if !self.out_compressed {
+                self.out.extend_from_slice(frame);
+                continue;
+            }
+            let mut payload = frame[header.size..].to_vec();
+            if let Some(mask) = header.mask {
+                apply_mask(&mut payload, mask);
+            }
+            let compressed = self.deflate.deflate(&payload, header.fin())?;
+            // only a message's first frame carries RSV1
+            let rsv1 = if header.opcode() == 0 { 0 } else { 0x40 };
+            header.write_frame(header.first | rsv1, compressed, &mut self.out);
+        }
+    }
+
+    // Ready once everything processed so far is written
+    fn poll_write_out<S: AsyncWrite + Unpin>(&mut self, inner: &mut S, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        while self.out_pos < self.out.len() {
+            match Pin::new(&mut *inner).poll_write(cx, &self.out[self.out_pos..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(count)) => self.out_pos += count,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read<S: AsyncRead + AsyncWrite + Unpin>(&mut self, inner: &mut S, limits: &tungstenite::protocol::WebSocketConfig, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<Result<(), IoError>> {
+        // tungstenite doesn't flush after a write it didn't finish
+        if let Poll::Ready(Err(err)) = self.poll_write_out(inner, cx) {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if self.ready_pos < self.ready_in.len() {
+                let count = buf.remaining().min(self.ready_in.len() - self.ready_pos);
+                buf.put_slice(&self.ready_in[self.ready_pos..self.ready_pos + count]);
+                self.ready_pos += count;
+                if self.ready_pos == self.ready_in.len() {
+                    self.ready_in.clear();
+                    self.ready_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if !self.raw_in.is_empty() {
+                // left over from the handshake
+                self.process_incoming(limits)?;
+                if !self.ready_in.is_empty() {
+                    continue;
+                }
+            }
+            let mut chunk = [0u8; 16 << 10];
+            let mut chunk_buf = tokio::io::ReadBuf::new(&mut chunk);
+            match Pin::new(&mut *inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) if chunk_buf.filled().is_empty() => {
+                    // a frame cut short is tungstenite's to report
+                    self.ready_in.append(&mut self.raw_in);
+                    if self.ready_in.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                },
+                Poll::Ready(Ok(())) => {
+                    self.raw_in.extend_from_slice(chunk_buf.filled());
+                    self.process_incoming(limits)?;
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_write<S: AsyncWrite + Unpin>(&mut self, inner: &mut S, compress: bool, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+        // only take more once the last batch is out, so nothing piles up here
+        if self.poll_write_out(inner, cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        self.pending_out.extend_from_slice(buf);
+        self.process_outgoing(compress)?;
+        if let Poll::Ready(Err(err)) = self.poll_write_out(inner, cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+}
+
+// Sits between tungstenite and the socket. Bytes go through untouched, but
+// incoming frames are followed to check their sizes, since tungstenite's
+// limits can't change after the handshake. tungstenite has no hook for
+// extensions either, so if the handshake settles on permessage-deflate the
+// frames after it go through DeflateFrames instead.
+struct WsFrames<S> {
+    inner: S,
+    server: bool,
+    offered: bool,
+    compress: bool,
+    limits: tungstenite::protocol::WebSocketConfig,
+    sizes: FrameSizes,
+    // the handshake response so far, until it's there to see what it agreed to
+    head: Option<Vec<u8>>,
+    head_matched: usize,
+    // what came in after the HTTP head, before we know how to read it
+    held: Vec<u8>,
+    deflate: Option<DeflateFrames>,
+}
+
+impl<S> WsFrames<S> {
+    fn new(inner: S, server: bool, compression: WsCompression, limits: WsLimits) -> WsFrames<S> {
+        WsFrames{
+            inner,
+            server,
+            offered: compression.enabled,
+            compress: compression.enabled,
+            limits: limits.config(),
+            sizes: FrameSizes::new(),
+            // servers only answer with what they were offered
+            head: Some(Vec::new()).filter(|_| compression.enabled || !server),
+            head_matched: 0,
+            held: Vec::new(),
+            deflate: None,
+        }
+    }
+
+    // Takes effect from the next frame; the send queue stays tungstenite's
+    fn set_limits(&mut self, limits: WsLimits) {
+        self.limits = limits.config();
+    }
+
+    // Turning compression off on a connection that agreed to it just sends
+    // the messages that follow uncompressed
+    fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    fn negotiate(&mut self, head: &[u8]) -> Result<(), IoError> {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+        if lines.next().and_then(|status| status.split_whitespace().nth(1)) != Some("101") {
+            return Ok(());
+        }
+        let values: Vec<&str> = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-extensions"))
+            .map(|(_, value)| value)
+            .collect();
+        let offers = deflate_offers(&values);
+        let params = match offers.as_slice() {
+            [] => return Ok(()),
+            [params] if self.offered => params,
+            _ => return Err(IoError::new(std::io::ErrorKind::InvalidData, "unexpected permessage-deflate response")),
+        };
+        let agreement = deflate_agreement(params, self.server).map_err(|err| IoError::new(std::io::ErrorKind::InvalidData, err))?;
+        self.deflate = Some(DeflateFrames::new(Deflate::new(agreement), std::mem::take(&mut self.held)));
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsFrames<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        if let Some(frames) = &mut this.deflate {
+            return frames.poll_read(&mut this.inner, &this.limits, cx, buf);
+        }
+        let before = buf.filled().len();
+        if this.held.is_empty() {
+            match Pin::new(&mut this.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) => {},
+                other => return other,
+            }
+        } else {
+            let count = buf.remaining().min(this.held.len());
+            buf.put_slice(&this.held[..count]);
+            this.held.drain(..count);
+        }
+        let in_head = matches!(this.sizes.state, ReadState::Head(_));
+        let end = this.sizes.scan(&buf.filled()[before..], &this.limits)?;
+        // clients read the response they negotiate from
+        let reading_head = in_head && !this.server && this.head.is_some();
+        if reading_head {
+            let head_len = end.unwrap_or(buf.filled().len() - before);
+            this.head.as_mut().expect("no head to negotiate").extend_from_slice(&buf.filled()[before..before + head_len]);
+        }
+        if let Some(end) = end {
+            // frames right behind the head wait until we know if they're compressed
+            let rest = buf.filled()[before + end..].to_vec();
+            this.held.splice(0..0, rest);
+            buf.set_filled(before + end);
+            if reading_head {
+                let head = this.head.take().expect("no head to negotiate");
+                this.negotiate(&head)?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WsFrames<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+        let this = self.get_mut();
+        if let Some(frames) = &mut this.deflate {
+            return frames.poll_write(&mut this.inner, this.compress, cx, buf);
+        }
+        if !this.server || this.head.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the response head goes out on its own, so what follows it can be compressed
+        let len = head_end(&mut this.head_matched.clone(), buf).unwrap_or(buf.len());
+        let written = match Pin::new(&mut this.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(written)) => written,
+            other => return other,
+        };
+        let end = head_end(&mut this.head_matched, &buf[..written]);
+        let mut head = this.head.take().expect("no head to negotiate");
+        head.extend_from_slice(&buf[..written]);
+        match end {
+            Some(_) => this.negotiate(&head)?,
+            None => this.head = Some(head),
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        if let Some(frames) = &mut this.deflate {
+            if frames.poll_write_out(&mut this.inner, cx)?.is_pending() {
+                return Poll::Pending;
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        if let Some(frames) = &mut this.deflate {
+            if frames.poll_write_out(&mut this.inner, cx)?.is_pending() {
+                return Poll::Pending;
+            }
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: TcpSocketRef> TcpSocketRef for WsFrames<S> {
+    fn tcp_ref(&self) -> Option<&TcpStream> {
+        self.inner.tcp_ref()
+    }
+}
+
+async fn send_ws_message<S>(ws_stream: &mut tokio_tungstenite::WebSocketStream<S>, msg: SocketMessage) -> Result<(), tungstenite::Error>
+where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin {
+    match msg {
+        SocketMessage::Message(msg) => ws_stream.send(tungstenite::protocol::Message::Text(msg)).await,
+        SocketMessage::BinaryMessage(msg) => ws_stream.send(tungstenite::protocol::Message::Binary(msg)).await,
+        _ => Ok(()),
+    }
+}
+
+// A bogus length prefix shouldn't be able to eat all our memory
+const MAX_FRAME_SIZE: usize = 64 << 20;
+
+#[derive(Clone, Default)]
+enum Framing {
+    #[default]
+    Raw,
+    Newline, // a trailing \r is dropped from received lines
+    Delimiter(Vec<u8>),
+    LengthPrefix{width: usize, big_endian: bool},
+    Netstring,
+}
+
+impl Framing {
+    // 0 raw, 1 newline, 2/3 u16 BE/LE length, 4/5 u32 BE/LE length, 6 netstring, 7 custom delimiter
+    fn from_mode(mode: u32, delimiter: Vec<u8>) -> Option<Framing> {
+        match mode {
+            0 => Some(Framing::Raw),
+            1 => Some(Framing::Newline),
+            2 => Some(Framing::LengthPrefix{width: 2, big_endian: true}),
+            3 => Some(Framing::LengthPrefix{width: 2, big_endian: false}),
+            4 => Some(Framing::LengthPrefix{width: 4, big_endian: true}),
+            5 => Some(Framing::LengthPrefix{width: 4, big_endian: false}),
+            6 => Some(Framing::Netstring),
+            7 if !delimiter.is_empty() => Some(Framing::Delimiter(delimiter)),
+            _ => None,
+        }
+    }
+}
+
+// Splits a byte stream into frames, and frames outgoing messages
+struct Framer {
+    framing: Framing,
+    buf: Vec<u8>,
+    // how much of the partial frame in buf was already searched for a delimiter
+    scan: usize,
+}
+
+impl Framer {
+    fn new(framing: Framing) -> Framer {
+        Framer{
+            framing,
+            buf: Vec::new(),
+            scan: 0,
+        }
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.scan = 0;
+    }
+
+    // Drops a partial frame
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.scan = 0;
+    }
+
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, IoError> {
+        let mut frame = Vec::with_capacity(payload.len() + 16);
+        match &self.framing {
+            Framing::Raw => frame.extend_from_slice(payload),
+            Framing::Newline => {
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+            },
+            Framing::Delimiter(delimiter) => {
+                frame.extend_from_slice(payload);
+                frame.extend_from_slice(delimiter);
+            },
+            Framing::LengthPrefix{width, big_endian} => {
+                let max_len = if *width == 2 { u16::MAX as usize } else { u32::MAX as usize };
+                if payload.len() > max_len {
+                    let msg = format!("Message of {} bytes is too long for a {} byte length prefix", payload.len(), width);
+                    return Err(IoError::new(std::io::ErrorKind::InvalidInput, msg));
+                }
+                let len = payload.len() as u32;
+                let prefix = if *big_endian { len.to_be_bytes() } else { len.to_le_bytes() };
+                match (width, big_endian) {
+                    (2, true) => frame.extend_from_slice(&prefix[2..]),
+                    (2, false) => frame.extend_from_slice(&prefix[..2]),
+                    _ => frame.extend_from_slice(&prefix),
+                }
+                frame.extend_from_slice(payload);
+            },
+            Framing::Netstring => {
+                frame.extend_from_slice(format!("{}:", payload.len()).as_bytes());
+                frame.extend_from_slice(payload);
+                frame.push(b',');
+            },
+        }
+        Ok(frame)
+    }
+
+    // Returns every frame completed by `data`; a partial frame is kept for the next call
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+        let mut start = 0;
+        let result = loop {
+            match self.next_frame(start) {
+                Ok(Some((frame, consumed))) => {
+                    frames.push(frame);
+                    start += consumed;
+                },
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        // the frames taken out go in one go rather than one at a time
+        self.buf.drain(..start);
+        result?;
+        if self.buf.len() > MAX_FRAME_SIZE {
+            return Err(format!("Frame exceeds {} bytes", MAX_FRAME_SIZE));
+        }
+        Ok(frames)
+    }
+
+    // At the end of a stream a last line without its delimiter still counts,
+    // a cut off length prefixed frame or netstring is an error
+    fn finish(&mut self) -> Result<Option<Vec<u8>>, String> {
+        self.scan = 0;
+        let rest = std::mem::take(&mut self.buf);
+        match self.framing {
+            _ if rest.is_empty() => Ok(None),
+            Framing::Raw | Framing::Newline | Framing::Delimiter(_) => Ok(Some(rest)),
+            Framing::LengthPrefix{..} | Framing::Netstring => Err(format!("Stream ended {} bytes into a frame", rest.len())),
+        }
+    }
+
+    // The next frame in buf from `offset` on, and how many bytes it took up
+    fn next_frame(&mut self, offset: usize) -> Result<Option<(Vec<u8>, usize)>, String> {
+        let buf = &self.buf[offset..];
+        let (start, end, consumed) = match &self.framing {
+            Framing::Raw => (0, buf.len(), buf.len()),
+            Framing::Newline => match buf[self.scan..].iter().position(|&b| b == b'\n').map(|pos| self.scan + pos) {
+                Some(pos) if pos > 0 && buf[pos - 1] == b'\r' => (0, pos - 1, pos + 1),
+                Some(pos) => (0, pos, pos + 1),
+                None => {
+                    self.scan = buf.len();
+                    return Ok(None);
+                },
+            },
+            Framing::Delimiter(delimiter) => match buf[self.scan..].windows(delimiter.len()).position(|window| window == delimiter.as_slice()).map(|pos| self.scan + pos) {
+                Some(pos) => (0, pos, pos + delimiter.len()),
+                None => {
+                    // a delimiter may have started in the last few bytes
+                    self.scan = (buf.len() + 1).saturating_sub(delimiter.len());
+                    return Ok(None);
+                },
+            },
+            Framing::LengthPrefix{width, big_endian} => {
+                if buf.len() < *width {
+                    return Ok(None);
+                }
+                let mut prefix = [0u8; 4];
+                if *big_endian {
+                    prefix[4 - width..].copy_from_slice(&buf[..*width]);
+                } else {
+                    prefix[..*width].copy_from_slice(&buf[..*width]);
+                }
+                let len = if *big_endian { u32::from_be_bytes(prefix) } else { u32::from_le_bytes(prefix) } as usize;
+                if len > MAX_FRAME_SIZE {
+                    return Err(format!("Frame of {} bytes exceeds {} bytes", len, MAX_FRAME_SIZE));
+                }
+                (*width, width + len, width + len)
+            },
+            Framing::Netstring => {
+                let colon = match buf.iter().position(|&b| b == b':') {
+                    Some(colon) => colon,
+                    None if buf.len() > 10 => return Err("Invalid netstring length".to_string()),
+                    None => return Ok(None),
+                };
+                let len: usize = std::str::from_utf8(&buf[..colon]).ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .ok_or_else(|| "Invalid netstring length".to_string())?;
+                if len > MAX_FRAME_SIZE {
+                    return Err(format!("Frame of {} bytes exceeds {} bytes", len, MAX_FRAME_SIZE));
+                }
+                if buf.len() > colon + 1 + len && buf[colon + 1 + len] != b',' {
+                    return Err("Netstring is missing its trailing comma".to_string());
+                }
+                (colon + 1, colon + 1 + len, colon + 2 + len)
+            },
+        };
+        if consumed == 0 || buf.len() < consumed {
+            return Ok(None);
+        }
+        self.scan = 0;
+        Ok(Some((buf[start..end].to_vec(), consumed)))
+    }
+}
+
+async fn send_tcp_message<S: AsyncWrite + Unpin>(tcp_stream: &mut S, framer: &Framer, msg: SocketMessage) -> Result<(), IoError> {
+    match msg {
+        SocketMessage::Message(msg) => tcp_stream.write_all(&framer.encode(msg.as_bytes())?).await,
+        SocketMessage::BinaryMessage(msg) => tcp_stream.write_all(&framer.encode(&msg)?).await,
+        _ => Ok(()),
+    }
+}
+
+// Sends our FIN after everything queued so far, then reads (and discards) until
+// the peer's FIN: closing with unread data would make the OS reset the
+// connection and the peer could lose what we sent last
+async fn finish_tcp<S: AsyncRead + AsyncWrite + Unpin>(tcp_stream: &mut S, read_open: bool, timeout_ms: u32) {
+    tcp_stream.shutdown().await.unwrap_or_default();
+    if !read_open {
+        return;
+    }
+    let mut buf = [0; 65536];
+    let drain = async {
+        loop {
+            match tcp_stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            }
+        }
+    };
+    if tokio::time::timeout(std::time::Duration::from_millis(timeout_ms.into()), drain).await.is_err() {
+        warn!("Peer didn't finish within {}ms, closing anyway", timeout_ms);
+    }
+}
+
+// Zero (or -1 for linger) leaves the OS default alone; the reuse flags only matter to listeners
+#[derive(Copy, Clone)]
+struct TcpOptions {
+    nodelay: bool,
+    keepalive_ms: u32,
+    keepalive_interval_ms: u32,
+    keepalive_count: u32,
+    send_buffer_size: u32,
+    recv_buffer_size: u32,
+    linger_secs: i32,
+    reuse_addr: bool,
+    reuse_port: bool,
+}
+
+impl Default for TcpOptions {
+    fn default() -> TcpOptions {
+        TcpOptions{
+            nodelay: false,
+            keepalive_ms: 0,
+            keepalive_interval_ms: 0,
+            keepalive_count: 0,
+            send_buffer_size: 0,
+            recv_buffer_size: 0,
+            linger_secs: -1,
+            reuse_addr: false,
+            reuse_port: false,
+        }
+    }
+}
+
+impl TcpOptions {
+    fn apply(&self, tcp_stream: &TcpStream) -> Result<(), IoError> {
+        let socket = socket2::SockRef::from(tcp_stream);
+        if self.nodelay {
+            tcp_stream.set_nodelay(true)?;
+        }
+        if self.keepalive_ms > 0 {
+            let mut keepalive = socket2::TcpKeepalive::new()
+                .with_time(std::time::Duration::from_millis(u64::from(self.keepalive_ms)));
+            if self.keepalive_interval_ms > 0 {
+                keepalive = keepalive.with_interval(std::time::Duration::from_millis(u64::from(self.keepalive_interval_ms)));
+            }
+            // windows has a fixed probe count
+            #[cfg(not(windows))]
+            {
+                if self.keepalive_count > 0 {
+                    keepalive = keepalive.with_retries(self.keepalive_count);
+                }
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if self.send_buffer_size > 0 {
+            socket.set_send_buffer_size(self.send_buffer_size as usize)?;
+        }
+        if self.recv_buffer_size > 0 {
+            socket.set_recv_buffer_size(self.recv_buffer_size as usize)?;
+        }
+        if self.linger_secs >= 0 {
+            tcp_stream.set_linger(Some(std::time::Duration::from_secs(self.linger_secs as u64)))?;
+        }
+        Ok(())
+    }
+
+    // Failing to set an option isn't worth losing the connection over
+    fn apply_or_warn(&self, tcp_stream: Option<&TcpStream>) {
+        if let Some(tcp_stream) = tcp_stream {
+            if let Err(err) = self.apply(tcp_stream) {
+                warn!("Could not set TCP socket options: {}", err);
+            }
+        }
+    }
+}
+
+// Lets socket options reach the TCP socket underneath a TLS session
+trait TcpSocketRef {
+    fn tcp_ref(&self) -> Option<&TcpStream>;
+
+    // Local and peer address, for the host to query
+    fn addresses(&self) -> (Option<String>, Option<String>) {
+        match self.tcp_ref() {
+            Some(tcp_stream) => (
+                tcp_stream.local_addr().ok().map(|addr| addr.to_string()),
+                tcp_stream.peer_addr().ok().map(|addr| addr.to_string()),
+            ),
+            None => (None, None),
+        }
+    }
+}
+
+impl TcpSocketRef for TcpStream {
+    fn tcp_ref(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl TcpSocketRef for tokio_native_tls::TlsStream<TcpStream> {
+    fn tcp_ref(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().get_ref().get_ref())
+    }
+}
+
+impl TcpSocketRef for tokio_tungstenite::MaybeTlsStream<TcpStream> {
+    fn tcp_ref(&self) -> Option<&TcpStream> {
+        match self {
+            tokio_tungstenite::stream::Stream::Plain(tcp_stream) => Some(tcp_stream),
+            tokio_tungstenite::stream::Stream::Tls(tls_stream) => tls_stream.tcp_ref(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl TcpSocketRef for tokio::net::UnixStream {
+    fn tcp_ref(&self) -> Option<&TcpStream> {
+        None
+    }
+
+    fn addresses(&self) -> (Option<String>, Option<String>) {
+        let name = |addr: tokio::net::unix::SocketAddr| unix_addr_name(&addr.into());
+        (self.local_addr().ok().and_then(name), self.peer_addr().ok().and_then(name))
+    }
+}
+
+// Unnamed sockets, which most clients are, have no address to report
+#[cfg(unix)]
+fn unix_addr_name(addr: &std::os::unix::net::SocketAddr) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        if let Some(name) = addr.as_abstract_name() {
+            return Some(format!("@{}", String::from_utf8_lossy(name)));
+        }
+    }
+    addr.as_pathname().map(|path| path.display().to_string())
+}
+
+// "@name" is a socket in the Linux abstract namespace: there's no file
+// to clean up and it goes away with the last handle
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> Result<tokio::net::UnixStream, IoError> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+        stream.set_nonblocking(true)?;
+        return tokio::net::UnixStream::from_std(stream);
+    }
+    tokio::net::UnixStream::connect(path).await
+}
+
+// Abstract sockets have no file permissions, so anyone on the machine could
+// connect to one; only accept peers running as the same user as us
+#[cfg(unix)]
+fn check_unix_peer(path: &str, stream: &tokio::net::UnixStream) -> Result<(), String> {
+    if !path.starts_with('@') {
+        return Ok(());
+    }
+    let uid = unsafe { libc::geteuid() };
+    match stream.peer_cred() {
+        Ok(cred) => check_peer_uid(cred.uid(), uid),
+        Err(err) => Err(format!("could not get peer credentials: {}", err)),
+    }
+}
+
+#[cfg(unix)]
+fn check_peer_uid(peer_uid: u32, uid: u32) -> Result<(), String> {
+    if peer_uid == uid {
+        Ok(())
+    } else {
+        Err(format!("peer uid {} does not match uid {}", peer_uid, uid))
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<tokio::net::UnixListener, IoError> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        listener.set_nonblocking(true)?;
+        return tokio::net::UnixListener::from_std(listener);
+    }
+    // a socket file left behind by a crashed process would block the bind,
+    // but one that still accepts connections belongs to a live server
+    use std::os::unix::fs::FileTypeExt;
+    let is_socket = std::fs::metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false);
+    if is_socket {
+        if let Err(err) = std::os::unix::net::UnixStream::connect(path) {
+            if err.kind() == std::io::ErrorKind::ConnectionRefused {
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+async fn bind_tcp(addr: &str, options: &TcpOptions) -> Result<TcpListener, IoError> {
+    if !options.reuse_addr && !options.reuse_port {
+        return TcpListener::bind(addr).await;
+    }
+    let sock_addr = match tokio::net::lookup_host(addr).await?.next() {
+        Some(sock_addr) => sock_addr,
+        None => return Err(IoError::new(std::io::ErrorKind::InvalidInput, format!("Could not resolve {}", addr))),
+    };
+    let socket = if sock_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(options.reuse_addr)?;
+    #[cfg(unix)]
+    socket.set_reuseport(options.reuse_port)?;
+    socket.bind(sock_addr)?;
+    socket.listen(1024)
+}
+
+// Settings a TCP listener passes on to the clients it accepts
+#[derive(Clone, Default)]
+struct TcpSettings {
+    framing: Framing,
+    options: TcpOptions,
+}
+
+async fn accept_ws(tcp_stream: TcpStream, addr: SocketAddr, outer_tx: std::sync::mpsc::Sender<SocketMessage>, settings: WsSettings, groups: Arc<Mutex<ClientGroups>>, _guard: ConnectionGuard) {//rx_to_sock: tokio::sync::mpsc::Receiver<SocketMessage>, tx_from_sock: std::sync::mpsc::Sender<SocketMessage>) {
+    let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+    let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+    let (local_addr, peer_addr) = tcp_stream.addresses();
+    // only clients that complete the handshake get reported to the host
+    let mut handshake = None;
+    let mut refusal = None;
+    let frames = WsFrames::new(tcp_stream, true, settings.compression, settings.limits);
+    let accepted = accept_hdr_async_with_config(frames, HandshakeCheck{
+        policy: &settings.policy,
+        compression: &settings.compression,
+        handshake: &mut handshake,
+        refusal: &mut refusal,
+    }, Some(settings.limits.send_queue_config())).await;
+
+    match accepted {
+        Ok(mut ws_stream) => {
+            outer_tx.send(SocketMessage::NewClient(ClientConn{
+                membership: Some(ClientGroups::register(&groups, tx_to_sock.clone())),
+                tx: tx_to_sock,
+                rx: rx_from_sock,
+                id: addr.to_string(),
+                handshake,
+                local_addr,
+                peer_addr,
+            })).expect("this shouldn't ever break?");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+            let mut peer_closed = false;
+            let mut close_frame = None;
+            let mut keepalive = Keepalive::new(settings.heartbeat);
+            loop {
+                tokio::select! {
+                    event = keepalive.next_event() => {
+                        match event {
+                            KeepaliveEvent::PingDue => {
+                                let payload = keepalive.ping_sent(Vec::new());
+                                if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Ping(payload)).await {
+                                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                                    break;
+                                }
+                            },
+                            KeepaliveEvent::TimedOut => {
+                                warn!("WS client {} stopped answering pings", addr);
+                                tx_from_sock.send(SocketMessage::Error(keepalive.timeout_error())).expect("TX error on socket error");
+                                break;
+                            },
+                        }
+                    },
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::SetHeartbeat(config)) => {
+                                keepalive.configure(config);
+                            },
+                            Some(SocketMessage::SetWsLimits(limits)) => {
+                                ws_stream.get_mut().set_limits(limits);
+                            },
+                            Some(SocketMessage::SetWsCompression(compression)) => {
+                                ws_stream.get_mut().set_compress(compression.enabled);
+                            },
+                            Some(SocketMessage::Ping(payload)) => {
+                                let payload = keepalive.ping_sent(payload);
+                                if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Ping(payload)).await {
+                                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                                    break;
+                                }
+                            },
+                            Some(SocketMessage::Message(msg)) => {
+                                if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Text(msg)).await {
+                                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                                    break;
+                                }
+                            },
+                            Some(SocketMessage::BinaryMessage(msg)) => {
+                                if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Binary(msg)).await {
+                                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                                    break;
+                                }
+                            },
+                            Some(SocketMessage::Close(code, reason)) => {
+                                close_frame = Some(make_close_frame(code, reason));
+                                break
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("WS client ignores {}", msg.kind()),
+                        }
+                    },
+                    from_sock_message = ws_stream.next() => {
+                        match from_sock_message {
+                            Some(Ok(tungstenite::protocol::Message::Pong(payload))) => {
+                                keepalive.pong_received(&payload, &tx_from_sock);
+                            },
+                            Some(Ok(msg)) => {
+                                peer_closed |= forward_ws_message(msg, &tx_from_sock);
+                            },
+                            Some(Err(msg)) => {
+                                close_frame = close_frame_for_error(&msg);
+                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
+                                break;
+                            },
+                            None => {
+                                if !peer_closed {
+                                    tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
+                                }
+                                break;
+                            }
+                        }
+                    },
+                };
+            }
+            ws_stream.close(close_frame).await.unwrap_or_default(); // if this errors we don't care
+        },
+        Err(err) => match refusal {
+            Some(reason) => {
+                warn!("Refused WS handshake from {}: {}", addr, reason);
+                outer_tx.send(SocketMessage::Rejected(format!("{}: {}", addr, reason))).unwrap_or_default();
+            },
+            None => error!("WS handshake with {} failed: {}", addr, err),
+        },
+    }
+}
+
+async fn accept_tls(acceptor: tokio_native_tls::TlsAcceptor, tcp_stream: TcpStream, addr: SocketAddr, outer_tx: std::sync::mpsc::Sender<SocketMessage>, settings: TcpSettings, groups: Arc<Mutex<ClientGroups>>, guard: ConnectionGuard) {
+    settings.options.apply_or_warn(Some(&tcp_stream));
+    // like WS handshakes, clients only get reported once TLS is up
+    match acceptor.accept(tcp_stream).await {
+        Ok(tls_stream) => accept_tcp(tls_stream, addr.to_string(), Some(outer_tx), settings, groups, Some(guard)).await,
+        Err(err) => error!("TLS handshake with {} failed: {}", addr, err),
+    }
+}
+
+async fn accept_tcp<S: AsyncRead + AsyncWrite + TcpSocketRef + Unpin>(tcp_stream: S, id: String, outer_tx: Option<std::sync::mpsc::Sender<SocketMessage>>, settings: TcpSettings, groups: Arc<Mutex<ClientGroups>>, _guard: Option<ConnectionGuard>) {
+    let (tx_to_sock, rx_to_sock) = tokio::sync::mpsc::channel(100);
+    let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+    if let Some(tx) = outer_tx {
+        let (local_addr, peer_addr) = tcp_stream.addresses();
+        tx.send(SocketMessage::NewClient(ClientConn{
+            membership: Some(ClientGroups::register(&groups, tx_to_sock.clone())),
+            tx: tx_to_sock,
+            rx: rx_from_sock,
+            id,
+            handshake: None,
+            local_addr,
+            peer_addr,
+        })).expect("this shouldn't ever break?");
+    }
+
+    stream_loop(tcp_stream, tx_from_sock, rx_to_sock, settings).await;
+}
+
+// Moves data between the host and an accepted connection or a Unix socket client
+async fn stream_loop<S: AsyncRead + AsyncWrite + TcpSocketRef + Unpin>(mut tcp_stream: S, tx_from_sock: std::sync::mpsc::Sender<SocketMessage>, mut rx_to_sock: tokio::sync::mpsc::Receiver<SocketMessage>, settings: TcpSettings) {
+    tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+    let mut buf = [0; 65536];
+    let mut framer = Framer::new(settings.framing);
+    let mut read_open = true;
+    let mut write_open = true;
+    loop {
+        tokio::select! {
+            from_c_message = rx_to_sock.recv() => {
+                match from_c_message {
+                    Some(SocketMessage::SetFraming(framing)) => {
+                        framer.set_framing(framing);
+                    },
+                    Some(SocketMessage::SetTcpOptions(options)) => {
+                        options.apply_or_warn(tcp_stream.tcp_ref());
+                    },
+                    Some(SocketMessage::Message(_)) | Some(SocketMessage::BinaryMessage(_)) if !write_open => {
+                        warn!("Dropping message sent after shutting down the write half");
+                    },
+                    Some(msg @ SocketMessage::Message(_)) | Some(msg @ SocketMessage::BinaryMessage(_)) => {
+                        if let Err(err) = send_tcp_message(&mut tcp_stream, &framer, msg).await {
+                            tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                            break;
+                        }
+                    },
+                    Some(SocketMessage::ShutdownWrite) => {
+                        if let Err(err) = tcp_stream.shutdown().await {
+                            tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                            break;
+                        }
+                        write_open = false;
+                        if !read_open {
+                            tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
+                            break;
+                        }
+                    },
+                    Some(SocketMessage::Finish(timeout_ms)) => {
+                        finish_tcp(&mut tcp_stream, read_open, timeout_ms).await;
+                        return;
+                    },
+                    Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | None => break,
+                    Some(msg) => warn!("Stream socket ignores {}", msg.kind()),
+                }
+            },
+            read = tcp_stream.read(&mut buf), if read_open => {
+                match read.map_err(|err| err.to_string()).and_then(|n| if n == 0 { Ok(None) } else { framer.decode(&buf[0..n]).map(Some) }) {
+                    Ok(None) => {
+                        read_open = false;
+                        // whatever the framer still holds comes before the EOF
+                        match framer.finish() {
+                            Ok(Some(frame)) => tx_from_sock.send(SocketMessage::BinaryMessage(frame)).expect("TX error on socket message"),
+                            Ok(None) => {},
+                            Err(err) => {
+                                tx_from_sock.send(SocketMessage::Error(err)).expect("TX error on socket error");
+                                break;
+                            },
+                        }
+                        if !write_open {
+                            tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
+                            break;
+                        }
+                        tx_from_sock.send(SocketMessage::Eof).expect("TX error on eof");
+                    },
+                    Ok(Some(frames)) => {
+                        for frame in frames {
+                            tx_from_sock.send(SocketMessage::BinaryMessage(frame)).expect("TX error on socket message");
+                        }
+                    }
+                    Err(err) => {
+                        tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
+                        break;
+                    }
+                }
+            },
+        };
+    }
+    info!("Closing TCP socket!");
+    tcp_stream.shutdown().await.unwrap_or_default(); // if this errors we don't care
+}
+
+// Zero TTLs leave the OS default alone; the reuse flags only matter when binding
+#[derive(Copy, Clone)]
+struct UdpOptions {
+    broadcast: bool,
+    ttl: u32,
+    multicast_ttl: u32,
+    multicast_loop: bool,
+    reuse_addr: bool,
+    reuse_port: bool,
+}
+
+impl Default for UdpOptions {
+    fn default() -> UdpOptions {
+        UdpOptions{
+            broadcast: false,
+            ttl: 0,
+            multicast_ttl: 0,
+            multicast_loop: true,
+            reuse_addr: false,
+            reuse_port: false,
+        }
+    }
+}
+
+impl UdpOptions {
+    fn apply(&self, udp_socket: &UdpSocket) -> Result<(), IoError> {
+        let socket = socket2::SockRef::from(udp_socket);
+        if udp_socket.local_addr()?.is_ipv4() {
+            udp_socket.set_broadcast(self.broadcast)?;
+            if self.ttl > 0 {
+                udp_socket.set_ttl(self.ttl)?;
+            }
+            if self.multicast_ttl > 0 {
+                udp_socket.set_multicast_ttl_v4(self.multicast_ttl)?;
+            }
+            udp_socket.set_multicast_loop_v4(self.multicast_loop)?;
+        } else {
+            // IPv6 has no broadcast, and calls its TTLs hop limits
+            if self.ttl > 0 {
+                socket.set_unicast_hops_v6(self.ttl)?;
+            }
+            if self.multicast_ttl > 0 {
+                socket.set_multicast_hops_v6(self.multicast_ttl)?;
+            }
+            udp_socket.set_multicast_loop_v6(self.multicast_loop)?;
+        }
+        Ok(())
+    }
+
+    fn apply_or_warn(&self, udp_socket: &UdpSocket) {
+        if let Err(err) = self.apply(udp_socket) {
+            warn!("Could not set UDP socket options: {}", err);
+        }
+    }
+}
+
+// The interface is the local address to join on for IPv4 groups, or the
+// interface index for IPv6 ones; empty lets the OS pick
+fn set_multicast_membership(udp_socket: &UdpSocket, group: &str, interface: &str, join: bool) -> Result<(), String> {
+    let group: IpAddr = group.parse().map_err(|_| format!("Invalid multicast group {}", group))?;
+    let invalid_interface = || format!("Invalid multicast interface {}", interface);
+    let result = match group {
+        IpAddr::V4(group) => {
+            let interface = if interface.is_empty() { Ipv4Addr::UNSPECIFIED } else { interface.parse().map_err(|_| invalid_interface())? };
+            if join {
+                udp_socket.join_multicast_v4(group, interface)
+            } else {
+                udp_socket.leave_multicast_v4(group, interface)
+            }
+        },
+        IpAddr::V6(group) => {
+            let interface = if interface.is_empty() { 0 } else { interface.parse().map_err(|_| invalid_interface())? };
+            if join {
+                udp_socket.join_multicast_v6(&group, interface)
+            } else {
+                udp_socket.leave_multicast_v6(&group, interface)
+            }
+        },
+    };
+    result.map_err(|err| err.to_string())
+}
+
+async fn bind_udp(bind_addr: &str, peer_addr: Option<&str>, options: &UdpOptions) -> Result<UdpSocket, String> {
+    let udp_socket = if options.reuse_addr || options.reuse_port {
+        let sock_addr = match tokio::net::lookup_host(bind_addr).await.map_err(|err| err.to_string())?.next() {
+            Some(sock_addr) => sock_addr,
+            None => return Err(format!("Could not resolve {}", bind_addr)),
+        };
+        let shared = || -> Result<UdpSocket, IoError> {
+            let socket = socket2::Socket::new(socket2::Domain::for_address(sock_addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+            socket.set_reuse_address(options.reuse_addr)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(options.reuse_port)?;
+            socket.bind(&sock_addr.into())?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket.into())
+        };
+        shared().map_err(|err| err.to_string())?
+    } else {
+        UdpSocket::bind(bind_addr).await.map_err(|err| err.to_string())?
+    };
+    options.apply_or_warn(&udp_socket);
+    if let Some(peer_addr) = peer_addr {
+        udp_socket.connect(peer_addr).await.map_err(|err| err.to_string())?;
+    }
+    Ok(udp_socket)
+}
+
+// Failed sends are only logged: UDP makes no delivery promises anyway,
+// so one bad destination shouldn't take the whole socket down
+async fn send_datagram(udp_socket: &UdpSocket, dest: Option<&str>, data: &[u8]) {
+    let sent = match dest {
+        Some(dest) => udp_socket.send_to(data, dest).await,
+        None => udp_socket.send(data).await,
+    };
+    if let Err(err) = sent {
+        warn!("UDP send to {} failed: {}", dest.unwrap_or("peer"), err);
+    }
+}
+
+// Hands a chunk of child process output to the host, returns false once the stream has ended
+fn forward_output(read: Result<usize, IoError>, buf: &[u8], framer: &mut Framer, source: &'static str, tx_from_sock: &std::sync::mpsc::Sender<SocketMessage>) -> bool {
+    let frames = match read {
+        Ok(0) => {
+            match framer.finish() {
+                Ok(Some(frame)) => tx_from_sock.send(SocketMessage::ProcessOutput(source, frame)).unwrap_or_default(),
+                Ok(None) => {},
+                Err(err) => warn!("Dropping the end of process {}: {}", source, err),
+            }
+            return false;
+        },
+        Ok(n) => framer.decode(&buf[0..n]),
+        Err(err) => {
+            warn!("Could not read process {}: {}", source, err);
+            return false;
+        }
+    };
+    match frames {
+        Ok(frames) => {
+            for frame in frames {
+                tx_from_sock.send(SocketMessage::ProcessOutput(source, frame)).unwrap_or_default();
+            }
+        },
+        Err(err) => {
+            // unlike a socket there's no peer to disconnect, so just resync
+            warn!("Dropping malformed process {}: {}", source, err);
+            framer.clear();
+        }
+    }
+    true
+}
+
+fn dns_resolver(dns_server: Option<SocketAddr>) -> Result<hickory_resolver::TokioResolver, String> {
+    use hickory_resolver::config::{ConnectionConfig, NameServerConfig, ResolverConfig};
+    let builder = match dns_server {
+        Some(server) => {
+            let mut udp = ConnectionConfig::udp();
+            udp.port = server.port();
+            let mut tcp = ConnectionConfig::tcp();
+            tcp.port = server.port();
+            let config = ResolverConfig::from_name_servers(vec![NameServerConfig::new(server.ip(), true, vec![udp, tcp])]);
+            hickory_resolver::TokioResolver::builder_with_config(config, hickory_resolver::net::runtime::TokioRuntimeProvider::default())
+        },
+        None => hickory_resolver::TokioResolver::builder_tokio().map_err(|err| err.to_string())?,
+    };
+    builder.build().map_err(|err| err.to_string())
+}
+
+// Plain address lookups go through the system resolver, same as connecting
+// does, unless a DNS server was configured; other record types always need
+// an actual DNS client
+async fn resolve_records(name: &str, record_type: &str, dns_server: Option<SocketAddr>) -> Result<Vec<String>, String> {
+    let record_type = record_type.to_ascii_uppercase();
+    let mut records: Vec<String> = Vec::new();
+    if dns_server.is_none() && (record_type.is_empty() || record_type == "A" || record_type == "AAAA") {
+        let addrs = tokio::net::lookup_host((name, 0)).await.map_err(|err| err.to_string())?;
+        for addr in addrs {
+            let wanted = match record_type.as_str() {
+                "A" => addr.is_ipv4(),
+                "AAAA" => addr.is_ipv6(),
+                _ => true,
+            };
+            let ip = addr.ip().to_string();
+            if wanted && !records.contains(&ip) {
+                records.push(ip);
+            }
+        }
+    } else {
+        let resolver = dns_resolver(dns_server)?;
+        if record_type.is_empty() {
+            let ips = resolver.lookup_ip(name).await.map_err(|err| err.to_string())?;
+            records.extend(ips.iter().map(|ip| ip.to_string()));
+        } else {
+            let wanted: hickory_resolver::proto::rr::RecordType = record_type.parse()
+                .map_err(|_| format!("Unknown record type {}", record_type))?;
+            let lookup = resolver.lookup(name, wanted).await.map_err(|err| err.to_string())?;
+            // answers can also hold the CNAMEs that led to the records
+            records.extend(lookup.answers().iter()
+                .filter(|record| record.record_type() == wanted)
+                .map(|record| record.data.to_string()));
+        }
+    }
+    if records.is_empty() {
+        let kind = if record_type.is_empty() { "address" } else { &record_type };
+        return Err(format!("No {} records found for {}", kind, name));
+    }
+    Ok(records)
+}
+
+// TLS settings for client connections, taken from the context when a handle is opened
+#[derive(Clone, Default)]
+struct TlsOptions {
+    ca_pems: Vec<String>,
+    client_identity: Option<(String, String)>, // certificate chain and PKCS#8 key, both PEM
+    server_name: Option<String>,
+    insecure: bool,
+}
+
+impl TlsOptions {
+    fn connector(&self) -> Result<native_tls::TlsConnector, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.ca_pems {
+            // a bundle can hold several certificates, but from_pem only reads the first
+            let blocks = pem.split_inclusive("-----END CERTIFICATE-----")
+                .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"));
+            for block in blocks {
+                let cert = native_tls::Certificate::from_pem(block.as_bytes())
+                    .map_err(|err| format!("Invalid CA certificate: {}", err))?;
+                builder.add_root_certificate(cert);
+            }
+        }
+        if let Some((cert, key)) = &self.client_identity {
+            let identity = native_tls::Identity::from_pkcs8(cert.as_bytes(), key.as_bytes())
+                .map_err(|err| format!("Invalid client certificate: {}", err))?;
+            builder.identity(identity);
+        }
+        if self.insecure {
+            warn!("TLS certificate verification is disabled");
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        builder.build().map_err(|err| err.to_string())
+    }
+
+    // reqwest always takes the TLS name from the url, so server_name can't apply
+    fn http_client(&self) -> Result<reqwest::Client, String> {
+        if let Some(server_name) = &self.server_name {
+            warn!("TLS server name {} is ignored for HTTP requests, the url host is verified", server_name);
+        }
+        reqwest::Client::builder()
+            .use_preconfigured_tls(self.connector()?)
+            .build()
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn tls_acceptor(cert_pem: &str, key_pem: &str) -> Result<tokio_native_tls::TlsAcceptor, String> {
+    let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), key_pem.as_bytes())
+        .map_err(|err| format!("Invalid server certificate: {}", err))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(|err| err.to_string())?;
+    Ok(tokio_native_tls::TlsAcceptor::from(acceptor))
+}
+
+async fn start_tls(tcp_stream: TcpStream, connector: native_tls::TlsConnector, host: &str, tls: &TlsOptions) -> Result<tokio_native_tls::TlsStream<TcpStream>, String> {
+    let server_name = tls.server_name.as_deref().unwrap_or(host);
+    tokio_native_tls::TlsConnector::from(connector).connect(server_name, tcp_stream).await
+        .map_err(|err| format!("TLS error: {}", err))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+#[derive(Clone, Debug)]
+struct ProxyServer {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+}
+
+impl ProxyServer {
+    // "socks5://[user:pass@]host[:port]" or "http://[user:pass@]host[:port]";
+    // a bare "host:port" is taken as an HTTP proxy, like curl does
+    fn parse(url: &str) -> Result<ProxyServer, String> {
+        let url = if url.contains("://") { url.to_string() } else { format!("http://{}", url) };
+        let parsed = url::Url::parse(&url).map_err(|err| format!("Invalid proxy {}: {}", url, err))?;
+        let (kind, default_port) = match parsed.scheme() {
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "http" => (ProxyKind::HttpConnect, 80),
+            scheme => return Err(format!("Unsupported proxy scheme {}", scheme)),
+        };
+        let host = match parsed.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            None => return Err(format!("No host in proxy {}", url)),
+        };
+        // the url keeps them percent-encoded, so "p%40ss" is sent as "p@ss"
+        let decode = |part: &str| percent_encoding::percent_decode_str(part).decode_utf8()
+            .map(|part| part.into_owned())
+            .map_err(|_| format!("Invalid credentials in proxy {}", url));
+        let auth = if parsed.username().is_empty() {
+            None
+        } else {
+            Some((decode(parsed.username())?, decode(parsed.password().unwrap_or(""))?))
+        };
+        Ok(ProxyServer{kind, host, port: parsed.port().unwrap_or(default_port), auth})
+    }
+}
+
+// Proxy settings for TCP, TLS and WS clients, taken from the context when a handle is opened;
+// a proxy that failed to parse is kept as its error so clients fail rather than connect directly
+#[derive(Clone, Default)]
+struct ProxyOptions {
+    server: Option<Result<ProxyServer, String>>,
+    from_env: bool,
+}
+
+// The environment is looked up through `env` so tests don't have to touch
+// the real one, which every thread shares
+fn env_var(env: &impl Fn(&str) -> Option<String>, names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|name| env(name))
+        .find(|value| !value.trim().is_empty())
+}
+
+// NO_PROXY is a comma separated list of host names, domain suffixes and
+// addresses, or "*" for everything
+fn bypasses_proxy(host: &str, env: &impl Fn(&str) -> Option<String>) -> bool {
+    let host = host.to_ascii_lowercase();
+    let no_proxy = env_var(env, &["NO_PROXY", "no_proxy"]).unwrap_or_default();
+    no_proxy.split(',').map(str::trim).filter(|entry| !entry.is_empty()).any(|entry| {
+        let entry = entry.to_ascii_lowercase();
+        let entry = entry.trim_start_matches('.');
+        entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+impl ProxyOptions {
+    // An explicitly set proxy always wins; otherwise TLS connections use
+    // HTTPS_PROXY and plain ones HTTP_PROXY, both falling back to ALL_PROXY
+    fn for_target(&self, host: &str, tls: bool) -> Result<Option<ProxyServer>, String> {
+        self.for_target_in(host, tls, &|name| std::env::var(name).ok())
+    }
+
+    fn for_target_in(&self, host: &str, tls: bool, env: &impl Fn(&str) -> Option<String>) -> Result<Option<ProxyServer>, String> {
+        if let Some(server) = &self.server {
+            return server.clone().map(Some);
+        }
+        if !self.from_env || bypasses_proxy(host, env) {
+            return Ok(None);
+        }
+        let names: &[&str] = if tls {
+            &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        } else {
+            &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]
+        };
+        env_var(env, names).map(|url| ProxyServer::parse(&url)).transpose()
+    }
+}
+
+fn basic_auth(user: &str, pass: &str) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass))
+}
+
+fn socks5_reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn socks5_handshake(stream: &mut TcpStream, host: &str, port: u16, auth: Option<&(String, String)>) -> Result<(), String> {
+    let io_err = |err: IoError| format!("SOCKS5 proxy error: {}", err);
+    let greeting: &[u8] = if auth.is_some() { &[5, 2, 0, 2] } else { &[5, 1, 0] };
+    stream.write_all(greeting).await.map_err(io_err)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(io_err)?;
+    if choice[0] != 5 {
+        return Err("Not a SOCKS5 proxy".to_string());
+    }
+    match (choice[1], auth) {
+        (0, _) => {},
+        (2, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err("SOCKS5 user name and password are limited to 255 bytes".to_string());
+            }
+            let mut login = vec![1, user.len() as u8];
+            login.extend_from_slice(user.as_bytes());
+            login.push(pass.len() as u8);
+            login.extend_from_slice(pass.as_bytes());
+            stream.write_all(&login).await.map_err(io_err)?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await.map_err(io_err)?;
+            if status[1] != 0 {
+                return Err("SOCKS5 proxy rejected the credentials".to_string());
+            }
+        },
+        _ => return Err("SOCKS5 proxy accepts none of our authentication methods".to_string()),
+    }
+
+    // host names are resolved by the proxy, which may be the only one able to
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) if host.len() > 255 => return Err(format!("Host name too long for SOCKS5: {}", host)),
+        Err(_) => {
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.map_err(io_err)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io_err)?;
+    if reply[1] != 0 {
+        return Err(format!("SOCKS5 proxy could not connect to {}:{}: {}", host, port, socks5_reply_reason(reply[1])));
+    }
+    // the address the proxy bound for us, which we have no use for
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await.map_err(io_err)?),
+        other => return Err(format!("Invalid SOCKS5 address type {}", other)),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await.map_err(io_err)?;
+    Ok(())
+}
+
+async fn http_connect(stream: &mut TcpStream, host: &str, port: u16, auth: Option<&(String, String)>) -> Result<(), String> {
+    let io_err = |err: IoError| format!("HTTP proxy error: {}", err);
+    let target = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((user, pass)) = auth {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", basic_auth(user, pass)));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.map_err(io_err)?;
+
+    // a byte at a time, so nothing past the headers is taken from the tunnel
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= 16384 {
+            return Err("HTTP proxy response headers are too long".to_string());
+        }
+        response.push(stream.read_u8().await.map_err(io_err)?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) if (200..300).contains(&code) => Ok(()),
+        _ => Err(format!("HTTP proxy refused to connect to {}: {}", target, status_line)),
+    }
+}
+
+// TCP connection to host:port, tunnelled through a proxy if one applies
+async fn open_stream(host: &str, port: u16, proxy: &ProxyOptions, tls: bool) -> Result<TcpStream, String> {
+    let server = match proxy.for_target(host, tls)? {
+        Some(server) => server,
+        None => return TcpStream::connect((host, port)).await.map_err(|err| err.to_string()),
+    };
+    info!("Connecting to {}:{} through {:?} proxy {}:{}", host, port, server.kind, server.host, server.port);
+    let mut stream = TcpStream::connect((server.host.as_str(), server.port)).await
+        .map_err(|err| format!("Could not reach proxy {}:{}: {}", server.host, server.port, err))?;
+    match server.kind {
+        ProxyKind::Socks5 => socks5_handshake(&mut stream, host, port, server.auth.as_ref()).await?,
+        ProxyKind::HttpConnect => http_connect(&mut stream, host, port, server.auth.as_ref()).await?,
+    }
+    Ok(stream)
+}
+
+// Raw TCP connection for open_tcp, or open_tls when given TLS options
+async fn connect_tcp(addr: &str, options: &TcpOptions, tls: Option<&TlsOptions>, proxy: &ProxyOptions) -> Result<tokio_tungstenite::MaybeTlsStream<TcpStream>, String> {
+    // a broken TLS setup shouldn't cost a connection attempt
+    let connector = tls.map(TlsOptions::connector).transpose()?;
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("Invalid port in {}", addr))?),
+        None => return Err(format!("No port in {}", addr)),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let tcp_stream = open_stream(host, port, proxy, tls.is_some()).await?;
+    options.apply_or_warn(Some(&tcp_stream));
+    match (tls, connector) {
+        (Some(tls), Some(connector)) => {
+            Ok(tokio_tungstenite::stream::Stream::Tls(start_tls(tcp_stream, connector, host, tls).await?))
+        },
+        _ => Ok(tokio_tungstenite::stream::Stream::Plain(tcp_stream)),
+    }
+}
+
+// Like connect_async, except that TLS goes through our own connector and server name
+async fn connect_ws(mut request: tungstenite::handshake::client::Request, limits: WsLimits, compression: WsCompression, tls: &TlsOptions, proxy: &ProxyOptions) -> Result<(tokio_tungstenite::WebSocketStream<WsFrames<tokio_tungstenite::MaybeTlsStream<TcpStream>>>, tungstenite::handshake::client::Response), String> {
+    let mode = tungstenite::client::uri_mode(request.uri()).map_err(|err| err.to_string())?;
+    let host = match request.uri().host() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_string(),
+        None => return Err("No host name in the url".to_string()),
+    };
+    let (port, connector) = match mode {
+        tungstenite::stream::Mode::Plain => (80, None),
+        tungstenite::stream::Mode::Tls => (443, Some(tls.connector()?)),
+    };
+    let port = request.uri().port_u16().unwrap_or(port);
+
+    let tcp_stream = open_stream(&host, port, proxy, connector.is_some()).await?;
+    let stream = match connector {
+        None => tokio_tungstenite::stream::Stream::Plain(tcp_stream),
+        Some(connector) => tokio_tungstenite::stream::Stream::Tls(start_tls(tcp_stream, connector, &host, tls).await?),
+    };
+    if compression.enabled {
+        let offer = http::HeaderValue::from_str(&compression.offer()).map_err(|err| err.to_string())?;
+        request.headers_mut().insert(http::header::SEC_WEBSOCKET_EXTENSIONS, offer);
+    }
+    let frames = WsFrames::new(stream, false, compression, limits);
+    client_async_with_config(request, frames, Some(limits.send_queue_config())).await.map_err(|err| err.to_string())
+}
+
+// Extra headers are given as "Name: value" lines, and protocols as a comma separated list
+fn build_ws_request(url: &str, headers: &str, protocols: &str) -> Result<tungstenite::handshake::client::Request, String> {
+    let real_url = url::Url::parse(url).map_err(|err| err.to_string())?;
+    let mut request = real_url.into_client_request().map_err(|err| err.to_string())?;
+    for line in headers.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (name, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => return Err(format!("Invalid header line: {}", line)),
+        };
+        let name = http::header::HeaderName::from_bytes(name.trim().as_bytes()).map_err(|err| err.to_string())?;
+        let value = http::HeaderValue::from_str(value.trim()).map_err(|err| err.to_string())?;
+        request.headers_mut().append(name, value);
+    }
+    let protocols: Vec<&str> = protocols.split(',').map(str::trim).filter(|p| !p.is_empty()).collect();
+    if !protocols.is_empty() {
+        let value = http::HeaderValue::from_str(&protocols.join(", ")).map_err(|err| err.to_string())?;
+        request.headers_mut().insert(http::header::SEC_WEBSOCKET_PROTOCOL, value);
+    }
+    Ok(request)
+}
+
+fn reject_http_request(status: http::StatusCode) -> Result<Response<Body>, IoError> {
+    Response::builder().status(status).body(Body::empty()).map_err(|_| IoError::other("Rust errors are a pain"))
+}
+
+// An HTTP connection that made it past the listener's caps, counted until it closes
+struct AdmittedStream {
+    stream: AddrStream,
+    _guard: ConnectionGuard,
+}
+
+impl AsyncRead for AdmittedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AdmittedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+// Closes connections over the caps as soon as they're accepted, like the
+// TCP and WS listeners do, so hyper never sees them
+struct LimitedIncoming {
+    incoming: AddrIncoming,
+    limiter: Arc<Mutex<ConnectionLimiter>>,
+    tx_from_sock: std::sync::mpsc::Sender<SocketMessage>,
+}
+
+impl Accept for LimitedIncoming {
+    type Conn = AdmittedStream;
+    type Error = IoError;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<AdmittedStream, IoError>>> {
+        let this = self.get_mut();
+        loop {
+            let stream = match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => stream,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let remote_addr = stream.remote_addr();
+            match ConnectionLimiter::admit(&this.limiter, Some(remote_addr.ip()), false) {
+                Ok(guard) => return Poll::Ready(Some(Ok(AdmittedStream{stream, _guard: guard}))),
+                Err(reason) => {
+                    warn!("Rejected HTTP connection from {}: {}", remote_addr, reason);
+                    this.tx_from_sock.send(SocketMessage::Rejected(format!("{}: {}", remote_addr, reason))).unwrap_or_default();
+                },
+            }
+        }
+    }
+}
+
+async fn handle_http_request<B>(req: Request<B>, static_: Option<Static>, virtual_files: Arc<RwLock<HashMap<String, Vec<u8>>>>, limiter: Arc<Mutex<ConnectionLimiter>>, remote_addr: SocketAddr, tx_from_sock: std::sync::mpsc::Sender<SocketMessage>) -> Result<Response<Body>, IoError> {
+    if !limiter.lock().expect("Limiter lock poisoned").take_token(remote_addr.ip()) {
+        tx_from_sock.send(SocketMessage::Rejected(format!("{}: rate limited", remote_addr))).unwrap_or_default();
+        return reject_http_request(http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    {
+        // Do we need like... more headers???
+        let vfiles = virtual_files.read().expect("RwLock poisoned");
+        if let Some(file_data) = vfiles.get(req.uri().path()) {
+            return Response::builder()
+                    .status(http::StatusCode::OK)
+                    .body(Body::from(file_data.clone()))
+                    .map_err(|_| IoError::other("Rust errors are a pain"))
+        }
+    }
+
+    match static_ {
+        Some(static_) => static_.clone().serve(req).await,
+        None => reject_http_request(http::StatusCode::NOT_FOUND),
+    }
+}
+
+impl PollnetContext {
+    fn new() -> PollnetContext {
+        if let Err(err) = env_logger::try_init() {
+            warn!("Multiple contexts created!: {}", err)
+        }
+
+        let (handle_tx, handle_rx) = std::sync::mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let shutdown_tx = Some(shutdown_tx);
+
+        let thread = Some(thread::spawn(move || {
+            let rt = runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Unable to create the runtime");
+
+            // Send handle back out so we can store it?
+            handle_tx
+                .send(rt.handle().clone())
+                .expect("Unable to give runtime handle to another thread");
+
+            // Continue running until notified to shutdown
+            info!("tokio runtime starting");
+            rt.block_on(async {
+                shutdown_rx.await.unwrap();
+                // uh let's just put in a 'safety' delay to shut everything down?
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            });
+            rt.shutdown_timeout(std::time::Duration::from_millis(200));
+            info!("tokio runtime shutdown");
+        }));
+
+        PollnetContext{
+            next_handle: 1,
+            rt_handle: handle_rx.recv().unwrap(),
+            thread,
+            shutdown_tx,
+            sockets: HashMap::new(),
+            ws_limits: WsLimits::default(),
+            ws_compression: WsCompression::default(),
+            tls: TlsOptions::default(),
+            tcp_options: TcpOptions::default(),
+            udp_options: UdpOptions::default(),
+            dns_server: None,
+            proxy: ProxyOptions::default(),
+        }
+    }
+
+    fn _next_handle_that_satisfies_the_borrow_checker(next_handle: &mut u32) -> u32 {
+        let new_handle: u32 = *next_handle;
+        *next_handle += 1;
+        new_handle   
+    }
+
+    fn _next_handle(&mut self) -> u32 {
+        PollnetContext::_next_handle_that_satisfies_the_borrow_checker(&mut self.next_handle)
+    }
+
+    fn _add_socket(&mut self, tx: tokio::sync::mpsc::Sender<SocketMessage>, rx: std::sync::mpsc::Receiver<SocketMessage>) -> u32 {
+        let socket = PollnetSocket::new(tx, rx, SocketStatus::OPENING);
+        let new_handle = self._next_handle();
+        self.sockets.insert(new_handle, socket);
+
+        new_handle
+    }
+
+    // A handle that errors out straight away, for things this platform can't do
+    #[cfg(not(unix))]
+    fn _unsupported(&mut self, reason: &str) -> u32 {
+        let (tx_to_sock, _rx_to_sock) = tokio::sync::mpsc::channel(1);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        tx_from_sock.send(SocketMessage::Error(reason.to_string())).unwrap_or_default();
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    fn serve_http(&mut self, bind_addr: String, serve_dir: Option<String>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+        // Spawn a future onto the runtime
+        self.rt_handle.spawn(async move {
+            info!("HTTP server spawned");
+            let addr = bind_addr.parse();
+            if addr.is_err() {
+                error!("Invalid TCP address: {}", bind_addr);
+                tx_from_sock.send(SocketMessage::Error("Invalid TCP address".to_string())).unwrap_or_default();
+                return;
+            }
+            let addr = addr.unwrap();
+
+            let static_ = serve_dir.map(|path_string| Static::new(Path::new(&path_string)));
+
+            let virtual_files: HashMap<String, Vec<u8>> = HashMap::new();
+            let virtual_files = Arc::new(RwLock::new(virtual_files));
+            let virtual_files_two_the_clone_wars = virtual_files.clone();
+            let limiter = ConnectionLimiter::new();
+            let limiter_for_shutdown = limiter.clone();
+            let tx_for_service = tx_from_sock.clone();
+
+            let make_service = make_service_fn(move |conn: &AdmittedStream| {
+                // Rust demands all these clones for reasons I don't fully understand
+                // I definitely feel so much safer though!
+                let static_ = static_.clone();
+                let virtual_files = virtual_files.clone();
+                let limiter = limiter.clone();
+                let tx_from_sock = tx_for_service.clone();
+                let remote_addr = conn.stream.remote_addr();
+                future::ok::<_, hyper::Error>(service_fn(move |req| {
+                    handle_http_request(req, static_.clone(), virtual_files.clone(), limiter.clone(), remote_addr, tx_from_sock.clone())
+                }))
+            });
+
+            let incoming = AddrIncoming::bind(&addr);
+            if let Err(bind_err) = incoming {
+                error!("Couldn't bind {}: {}", bind_addr, bind_err);
+                tx_from_sock.send(SocketMessage::Error(bind_err.to_string())).unwrap_or_default();
+                return;
+            }
+            let incoming = incoming.unwrap();
+            let local_addr = incoming.local_addr();
+            let incoming = LimitedIncoming{incoming, limiter: limiter_for_shutdown.clone(), tx_from_sock: tx_from_sock.clone()};
+            let server = hyper::Server::builder(incoming).serve(make_service);
+            tx_from_sock.send(SocketMessage::Addresses(Some(local_addr.to_string()), None)).unwrap_or_default();
+            tx_from_sock.send(SocketMessage::Connect).unwrap_or_default();
+            let graceful = server.with_graceful_shutdown(async move {
+                let virtual_files = virtual_files_two_the_clone_wars.clone();
+                loop {
+                    match rx_to_sock.recv().await {
+                        Some(SocketMessage::Disconnect) | Some(SocketMessage::Error(_)) | None => {
+                            break
+                        },
+                        Some(SocketMessage::FileAdd(filename, filedata)) => {
+                            let mut vfiles = virtual_files.write().expect("Lock is poisoned");
+                            vfiles.insert(filename, filedata);
+                        },
+                        Some(SocketMessage::FileRemove(filename)) => {
+                            let mut vfiles = virtual_files.write().expect("Lock is poisoned");
+                            vfiles.remove(&filename);
+                        },
+                        Some(SocketMessage::SetLimits(limits)) => {
+                            limiter_for_shutdown.lock().expect("Limiter lock poisoned").set_limits(limits);
+                        },
+                        _ => {} // ignore sends?
+                    }
+                }
+                info!("HTTP server trying to gracefully exit?");
+            });
+            info!("HTTP server running on http://{}/", addr);
+            if let Err(err) = graceful.await {
+                tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default(); // don't care at this point
+            }
+            info!("HTTP server stopped.");
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    fn listen_ws(&mut self, addr: String) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let limits = self.ws_limits;
+        let compression = self.ws_compression;
+
+        self.rt_handle.spawn(async move {
+            info!("WS server spawned");
+            let listener = match TcpListener::bind(&addr).await {
+                Ok(listener) => listener,
+                Err(tcp_err) => {
+                    tx_from_sock.send(SocketMessage::Error(tcp_err.to_string())).unwrap_or_default();
+                    return;
+                }
+            };
+            info!("WS server waiting for connections on {}", addr);
+            tx_from_sock.send(SocketMessage::Addresses(listener.local_addr().ok().map(|addr| addr.to_string()), None)).expect("oh boy");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");                    
+            let limiter = ConnectionLimiter::new();
+            let groups = ClientGroups::new();
+            let mut settings = WsSettings{
+                limits,
+                compression,
+                ..WsSettings::default()
+            };
+            loop {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::Message(_msg)) => {}, // server socket ignores sends
+                            Some(SocketMessage::SetLimits(limits)) => {
+                                limiter.lock().expect("Limiter lock poisoned").set_limits(limits);
+                            },
+                            Some(SocketMessage::SetHeartbeat(config)) => {
+                                settings.heartbeat = config;
+                            },
+                            Some(SocketMessage::SetPolicy(policy)) => {
+                                settings.policy = policy;
+                            },
+                            Some(SocketMessage::SetWsLimits(limits)) => {
+                                settings.limits = limits;
+                            },
+                            Some(SocketMessage::SetWsCompression(compression)) => {
+                                settings.compression = compression;
+                            },
+                            Some(SocketMessage::Broadcast(group, msg)) => {
+                                groups.lock().expect("Groups lock poisoned").broadcast(group.as_deref(), &msg);
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("WS listener ignores {}", msg.kind()),
+                        }
+                    },
+                    new_client = listener.accept() => {
+                        match new_client {
+                            Ok((tcp_stream, addr)) => {
+                                match ConnectionLimiter::admit(&limiter, Some(addr.ip()), true) {
+                                    Ok(guard) => {
+                                        tokio::spawn(accept_ws(tcp_stream, addr, tx_from_sock.clone(), settings.clone(), groups.clone(), guard));
+                                    },
+                                    Err(reason) => {
+                                        warn!("Rejected WS connection from {}: {}", addr, reason);
+                                        tx_from_sock.send(SocketMessage::Rejected(format!("{}: {}", addr, reason))).unwrap_or_default();
+                                    }
+                                }
+                            },
+                            Err(msg) => {
+                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
+                                break;
+                            }
+                        }
+                    },
+                };
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // With an identity (certificate chain and PKCS#8 key PEMs) clients have to speak TLS
+    fn listen_tcp(&mut self, addr: String, identity: Option<(String, String)>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let options = self.tcp_options;
+
+        self.rt_handle.spawn(async move {
+            info!("TCP server spawned");
+            let acceptor = match identity.map(|(cert, key)| tls_acceptor(&cert, &key)).transpose() {
+                Ok(acceptor) => acceptor,
+                Err(err) => {
+                    tx_from_sock.send(SocketMessage::Error(err)).unwrap_or_default();
+                    return;
+                }
+            };
+            let listener = match bind_tcp(&addr, &options).await {
+                Ok(listener) => listener,
+                Err(tcp_err) => {
+                    tx_from_sock.send(SocketMessage::Error(tcp_err.to_string())).unwrap_or_default();
+                    return;
+                }
+            };
+            info!("TCP server waiting for connections on {}", addr);
+            tx_from_sock.send(SocketMessage::Addresses(listener.local_addr().ok().map(|addr| addr.to_string()), None)).expect("oh boy");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");                    
+            let limiter = ConnectionLimiter::new();
+            let groups = ClientGroups::new();
+            let mut settings = TcpSettings{
+                options,
+                ..TcpSettings::default()
+            };
+            loop {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::Message(_msg)) => {}, // server socket ignores sends
+                            Some(SocketMessage::SetLimits(limits)) => {
+                                limiter.lock().expect("Limiter lock poisoned").set_limits(limits);
+                            },
+                            Some(SocketMessage::Broadcast(group, msg)) => {
+                                groups.lock().expect("Groups lock poisoned").broadcast(group.as_deref(), &msg);
+                            },
+                            Some(SocketMessage::SetFraming(framing)) => {
+                                settings.framing = framing;
+                            },
+                            Some(SocketMessage::SetTcpOptions(options)) => {
+                                settings.options = options;
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("TCP listener ignores {}", msg.kind()),
+                        }
+                    },
+                    new_client = listener.accept() => {
+                        match new_client {
+                            Ok((tcp_stream, addr)) => {
+                                match ConnectionLimiter::admit(&limiter, Some(addr.ip()), true) {
+                                    Ok(guard) => match &acceptor {
+                                        Some(acceptor) => {
+                                            tokio::spawn(accept_tls(acceptor.clone(), tcp_stream, addr, tx_from_sock.clone(), settings.clone(), groups.clone(), guard));
+                                        },
+                                        None => {
+                                            settings.options.apply_or_warn(Some(&tcp_stream));
+                                            tokio::spawn(accept_tcp(tcp_stream, addr.to_string(), Some(tx_from_sock.clone()), settings.clone(), groups.clone(), Some(guard)));
+                                        },
+                                    },
+                                    Err(reason) => {
+                                        warn!("Rejected TCP connection from {}: {}", addr, reason);
+                                        tx_from_sock.send(SocketMessage::Rejected(format!("{}: {}", addr, reason))).unwrap_or_default();
+                                    }
+                                }
+                            },
+                            Err(msg) => {
+                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
+                                break;
+                            }
+                        }
+                    },
+                };
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(&mut self, path: String) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+        self.rt_handle.spawn(async move {
+            info!("Unix socket server spawned");
+            let listener = match bind_unix(&path) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default();
+                    return;
+                }
+            };
+            info!("Unix socket server waiting for connections on {}", path);
+            tx_from_sock.send(SocketMessage::Addresses(listener.local_addr().ok().and_then(|addr| unix_addr_name(&addr.into())), None)).expect("oh boy");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+            let limiter = ConnectionLimiter::new();
+            let groups = ClientGroups::new();
+            let mut settings = TcpSettings::default();
+            loop {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::Message(_msg)) => {}, // server socket ignores sends
+                            Some(SocketMessage::SetLimits(limits)) => {
+                                limiter.lock().expect("Limiter lock poisoned").set_limits(limits);
+                            },
+                            Some(SocketMessage::Broadcast(group, msg)) => {
+                                groups.lock().expect("Groups lock poisoned").broadcast(group.as_deref(), &msg);
+                            },
+                            Some(SocketMessage::SetFraming(framing)) => {
+                                settings.framing = framing;
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("Unix socket listener ignores {}", msg.kind()),
+                        }
+                    },
+                    new_client = listener.accept() => {
+                        match new_client {
+                            Ok((unix_stream, _addr)) => {
+                                if let Err(reason) = check_unix_peer(&path, &unix_stream) {
+                                    warn!("Rejected Unix socket connection on {}: {}", path, reason);
+                                    tx_from_sock.send(SocketMessage::Rejected(format!("{}: {}", path, reason))).unwrap_or_default();
+                                    continue;
+                                }
+                                match ConnectionLimiter::admit(&limiter, None, true) {
+                                    Ok(guard) => {
+                                        tokio::spawn(accept_tcp(unix_stream, path.clone(), Some(tx_from_sock.clone()), settings.clone(), groups.clone(), Some(guard)));
+                                    },
+                                    Err(reason) => {
+                                        warn!("Rejected Unix socket connection on {}: {}", path, reason);
+                                        tx_from_sock.send(SocketMessage::Rejected(format!("{}: {}", path, reason))).unwrap_or_default();
+                                    }
+                                }
+                            },
+                            Err(msg) => {
+                                tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
+                                break;
+                            }
+                        }
+                    },
+                };
+            }
+            if !path.starts_with('@') {
+                std::fs::remove_file(&path).unwrap_or_default();
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    #[cfg(unix)]
+    fn open_unix(&mut self, path: String) -> u32 {
+        let (tx_to_sock, rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+        self.rt_handle.spawn(async move {
+            info!("Unix socket client attempting to connect to {}", path);
+            match connect_unix(&path).await {
+                Ok(unix_stream) => {
+                    let (local_addr, peer_addr) = unix_stream.addresses();
+                    tx_from_sock.send(SocketMessage::Addresses(local_addr, peer_addr)).expect("oh boy");
+                    stream_loop(unix_stream, tx_from_sock, rx_to_sock, TcpSettings::default()).await
+                },
+                Err(err) => {
+                    error!("Unix socket connection error: {}", err);
+                    tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default();
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(&mut self, _path: String) -> u32 {
+        self._unsupported("Unix sockets are not supported on this platform")
+    }
+
+    #[cfg(not(unix))]
+    fn open_unix(&mut self, _path: String) -> u32 {
+        self._unsupported("Unix sockets are not supported on this platform")
+    }
+
+    fn open_ws(&mut self, url: String, headers: String, protocols: String) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let mut limits = self.ws_limits;
+        let mut compression = self.ws_compression;
+        let tls = self.tls.clone();
+        let proxy = self.proxy.clone();
+
+        self.rt_handle.spawn(async move {
+            info!("WS client spawned");
+            let mut reconnector = Reconnector::new();
+            let mut keepalive = Keepalive::new(HeartbeatConfig::default());
+            loop {
+                let request = match build_ws_request(&url, &headers, &protocols) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        error!("Invalid WS request for {}: {}", url, err);
+                        tx_from_sock.send(SocketMessage::Error(err)).unwrap_or_default();
+                        return;
+                    }
+                };
+
+                info!("WS client attempting to connect to {}", url);
+                let loss = match connect_ws(request, limits, compression, &tls, &proxy).await {
+                    Ok((mut ws_stream, response)) => {
+                        let (local_addr, peer_addr) = ws_stream.get_ref().addresses();
+                        tx_from_sock.send(SocketMessage::Addresses(local_addr, peer_addr)).expect("oh boy");
+                        tx_from_sock.send(SocketMessage::Handshake(HandshakeInfo::from_headers(response.headers()))).expect("oh boy");
+                        // start over with a fresh schedule on every connection
+                        keepalive.configure(keepalive.config);
+                        let mut loss = None;
+                        for msg in reconnector.connected(&tx_from_sock) {
+                            if let Err(err) = send_ws_message(&mut ws_stream, msg).await {
+                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                break;
+                            }
+                        }
+                        let mut peer_closed = false;
+                        let mut close_frame = None;
+                        while loss.is_none() {
+                            tokio::select! {
+                                event = keepalive.next_event() => {
+                                    match event {
+                                        KeepaliveEvent::PingDue => {
+                                            let payload = keepalive.ping_sent(Vec::new());
+                                            if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Ping(payload)).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        KeepaliveEvent::TimedOut => {
+                                            warn!("WS server {} stopped answering pings", url);
+                                            loss = Some(ConnectionLoss::Error(keepalive.timeout_error()));
+                                        },
+                                    }
+                                },
+                                from_c_message = rx_to_sock.recv() => {
+                                    match from_c_message {
+                                        Some(SocketMessage::SetHeartbeat(config)) => {
+                                            keepalive.configure(config);
+                                        },
+                                        Some(SocketMessage::SetWsLimits(new_limits)) => {
+                                            limits = new_limits;
+                                            ws_stream.get_mut().set_limits(limits);
+                                        },
+                                        Some(SocketMessage::SetWsCompression(new_compression)) => {
+                                            // the window and whether to offer at all wait for the next handshake
+                                            compression = new_compression;
+                                            ws_stream.get_mut().set_compress(compression.enabled);
+                                        },
+                                        Some(SocketMessage::Ping(payload)) => {
+                                            let payload = keepalive.ping_sent(payload);
+                                            if let Err(err) = ws_stream.send(tungstenite::protocol::Message::Ping(payload)).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        Some(SocketMessage::Close(code, reason)) => {
+                                            close_frame = Some(make_close_frame(code, reason));
+                                            break
+                                        },
+                                        Some(SocketMessage::SetReconnect(policy)) => {
+                                            reconnector.policy = Some(policy);
+                                        },
+                                        Some(SocketMessage::AddHello(msg)) => {
+                                            reconnector.hellos.push(msg.clone());
+                                            if let Err(err) = send_ws_message(&mut ws_stream, SocketMessage::Message(msg)).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        Some(msg @ SocketMessage::Message(_)) | Some(msg @ SocketMessage::BinaryMessage(_)) => {
+                                            if let Err(err) = send_ws_message(&mut ws_stream, msg).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        Some(SocketMessage::Disconnect) | Some(SocketMessage::Finish(_)) | None => break,
+                                        Some(msg) => warn!("WS client ignores {}", msg.kind()),
+                                    }
+                                },
+                                from_sock_message = ws_stream.next() => {
+                                    match from_sock_message {
+                                        Some(Ok(tungstenite::protocol::Message::Pong(payload))) => {
+                                            keepalive.pong_received(&payload, &tx_from_sock);
+                                        },
+                                        Some(Ok(msg)) => {
+                                            peer_closed |= forward_ws_message(msg, &tx_from_sock);
+                                        },
+                                        Some(Err(msg)) => {
+                                            close_frame = close_frame_for_error(&msg);
+                                            loss = Some(ConnectionLoss::Error(msg.to_string()));
+                                        },
+                                        None => {
+                                            if !peer_closed {
+                                                loss = Some(ConnectionLoss::Disconnect);
+                                            }
+                                            break;
+                                        }
+                                    }
+                                },
+                            };
+                        }
+                        info!("Closing websocket!");
+                        ws_stream.close(close_frame).await.unwrap_or_default(); // if this errors we don't care
+                        loss
+                    },
+                    Err(err) => {
+                        error!("WS client connection error: {}", err);
+                        Some(ConnectionLoss::Error(err.to_string()))
+                    }
+                };
+
+                // a close from either end is deliberate, so only reconnect after failures
+                match loss {
+                    Some(loss) => {
+                        let apply = |msg| match msg {
+                            SocketMessage::SetHeartbeat(config) => keepalive.config = config,
+                            SocketMessage::SetWsLimits(new_limits) => limits = new_limits,
+                            SocketMessage::SetWsCompression(new_compression) => compression = new_compression,
+                            _ => (),
+                        };
+                        if !reconnector.retry(&mut rx_to_sock, &tx_from_sock, loss, apply).await {
+                            return;
+                        }
+                    },
+                    None => return,
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // `tls` of None is plain TCP
+    fn open_tcp(&mut self, addr: String, tls: Option<TlsOptions>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let mut options = self.tcp_options;
+        let proxy = self.proxy.clone();
+
+        self.rt_handle.spawn(async move {
+            let mut buf = [0; 65536];
+            let mut reconnector = Reconnector::new();
+            let mut framer = Framer::new(Framing::Raw);
+            loop {
+                info!("TCP client attempting to connect to {}", addr);
+                let loss = match connect_tcp(&addr, &options, tls.as_ref(), &proxy).await {
+                    Ok(mut tcp_stream) => {
+                        let (local_addr, peer_addr) = tcp_stream.addresses();
+                        tx_from_sock.send(SocketMessage::Addresses(local_addr, peer_addr)).expect("oh boy");
+                        // a frame cut off by the last connection won't be finished by this one
+                        framer.clear();
+                        let mut loss = None;
+                        let mut read_open = true;
+                        let mut write_open = true;
+                        for msg in reconnector.connected(&tx_from_sock) {
+                            if let Err(err) = send_tcp_message(&mut tcp_stream, &framer, msg).await {
+                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                break;
+                            }
+                        }
+                        while loss.is_none() {
+                            tokio::select! {
+                                from_c_message = rx_to_sock.recv() => {
+                                    match from_c_message {
+                                        Some(SocketMessage::SetReconnect(policy)) => {
+                                            reconnector.policy = Some(policy);
+                                        },
+                                        Some(SocketMessage::AddHello(msg)) => {
+                                            reconnector.hellos.push(msg.clone());
+                                            if let Err(err) = send_tcp_message(&mut tcp_stream, &framer, SocketMessage::Message(msg)).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        Some(SocketMessage::SetFraming(framing)) => {
+                                            framer.set_framing(framing);
+                                        },
+                                        Some(SocketMessage::SetTcpOptions(new_options)) => {
+                                            // kept for reconnects too
+                                            options = new_options;
+                                            options.apply_or_warn(tcp_stream.tcp_ref());
+                                        },
+                                        Some(SocketMessage::Message(_)) | Some(SocketMessage::BinaryMessage(_)) if !write_open => {
+                                            warn!("Dropping message sent after shutting down the write half");
+                                        },
+                                        Some(msg @ SocketMessage::Message(_)) | Some(msg @ SocketMessage::BinaryMessage(_)) => {
+                                            if let Err(err) = send_tcp_message(&mut tcp_stream, &framer, msg).await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            }
+                                        },
+                                        Some(SocketMessage::ShutdownWrite) => {
+                                            if let Err(err) = tcp_stream.shutdown().await {
+                                                loss = Some(ConnectionLoss::Error(err.to_string()));
+                                            } else if !read_open {
+                                                tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
+                                                break;
+                                            }
+                                            write_open = false;
+                                        },
+                                        Some(SocketMessage::Finish(timeout_ms)) => {
+                                            finish_tcp(&mut tcp_stream, read_open, timeout_ms).await;
+                                            return;
+                                        },
+                                        Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | None => break,
+                                        Some(msg) => warn!("TCP client ignores {}", msg.kind()),
+                                    }
+                                },
+                                read = tcp_stream.read(&mut buf), if read_open => {
+                                    match read {
+                                        Ok(0) => {
+                                            // the peer closed on purpose, like a WS close frame, so
+                                            // this never reconnects; only errors do
+                                            read_open = false;
+                                            match framer.finish() {
+                                                Ok(Some(frame)) => tx_from_sock.send(SocketMessage::BinaryMessage(frame)).expect("TX error on socket message"),
+                                                Ok(None) => {},
+                                                Err(err) => {
+                                                    loss = Some(ConnectionLoss::Error(err));
+                                                    continue;
+                                                },
+                                            }
+                                            if !write_open {
+                                                tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on disconnect");
+                                                break;
+                                            }
+                                            tx_from_sock.send(SocketMessage::Eof).expect("TX error on eof");
+                                        }
+                                        Ok(n) => match framer.decode(&buf[0..n]) {
+                                            Ok(frames) => {
+                                                for frame in frames {
+                                                    tx_from_sock.send(SocketMessage::BinaryMessage(frame)).expect("TX error on socket message");
+                                                }
+                                            },
+                                            Err(err) => {
+                                                loss = Some(ConnectionLoss::Error(err));
+                                            },
+                                        },
+                                        Err(err) => {
+                                            loss = Some(ConnectionLoss::Error(err.to_string()));
+                                        }
+                                    }
+                                },
+                            };
+                        }
+                        info!("Closing TCP socket!");
+                        tcp_stream.shutdown().await.unwrap_or_default(); // if this errors we don't care
+                        loss
+                    },
+                    Err(err) => {
+                        error!("TCP client connection error: {}", err);
+                        Some(ConnectionLoss::Error(err))
+                    }
+                };
+
+                match loss {
+                    Some(loss) => {
+                        let apply = |msg| match msg {
+                            SocketMessage::SetFraming(framing) => framer.set_framing(framing),
+                            SocketMessage::SetTcpOptions(new_options) => options = new_options,
+                            _ => (),
+                        };
+                        if !reconnector.retry(&mut rx_to_sock, &tx_from_sock, loss, apply).await {
+                            return;
+                        }
+                    },
+                    None => return,
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // A peer address makes this a connected socket: plain sends go to the peer
+    // and only datagrams from the peer are received
+    fn open_udp(&mut self, bind_addr: String, peer_addr: Option<String>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let options = self.udp_options;
+
+        self.rt_handle.spawn(async move {
+            info!("UDP socket binding to {}", bind_addr);
+            let udp_socket = match bind_udp(&bind_addr, peer_addr.as_deref(), &options).await {
+                Ok(udp_socket) => udp_socket,
+                Err(err) => {
+                    error!("UDP socket error: {}", err);
+                    tx_from_sock.send(SocketMessage::Error(err)).expect("TX error on udp error");
+                    return;
+                }
+            };
+            let local_addr = udp_socket.local_addr().ok().map(|addr| addr.to_string());
+            let peer_addr = udp_socket.peer_addr().ok().map(|addr| addr.to_string());
+            tx_from_sock.send(SocketMessage::Addresses(local_addr, peer_addr)).expect("oh boy");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+
+            let mut buf = [0; 65536];
+            loop {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::Message(msg)) => send_datagram(&udp_socket, None, msg.as_bytes()).await,
+                            Some(SocketMessage::BinaryMessage(msg)) => send_datagram(&udp_socket, None, &msg).await,
+                            Some(SocketMessage::SendTo(dest, msg)) => send_datagram(&udp_socket, Some(&dest), &msg).await,
+                            Some(SocketMessage::SetUdpOptions(options)) => options.apply_or_warn(&udp_socket),
+                            Some(SocketMessage::JoinMulticast(group, interface)) => {
+                                if let Err(err) = set_multicast_membership(&udp_socket, &group, &interface, true) {
+                                    warn!("Could not join multicast group {}: {}", group, err);
+                                    tx_from_sock.send(SocketMessage::Warning(format!("Could not join multicast group {}: {}", group, err))).unwrap_or_default();
+                                }
+                            },
+                            Some(SocketMessage::LeaveMulticast(group, interface)) => {
+                                if let Err(err) = set_multicast_membership(&udp_socket, &group, &interface, false) {
+                                    warn!("Could not leave multicast group {}: {}", group, err);
+                                    tx_from_sock.send(SocketMessage::Warning(format!("Could not leave multicast group {}: {}", group, err))).unwrap_or_default();
+                                }
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("UDP socket ignores {}", msg.kind()),
+                        }
+                    },
+                    received = udp_socket.recv_from(&mut buf) => {
+                        match received {
+                            Ok((n, source)) => {
+                                tx_from_sock.send(SocketMessage::Datagram(source, buf[0..n].to_vec())).expect("TX error on datagram");
+                            },
+                            // an ICMP unreachable for something we sent earlier, the socket itself is fine
+                            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused || err.kind() == std::io::ErrorKind::ConnectionReset => {
+                                warn!("UDP peer unreachable: {}", err);
+                            },
+                            Err(err) => {
+                                tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on udp error");
+                                break;
+                            }
+                        }
+                    },
+                };
+            }
+            info!("Closing UDP socket!");
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // Stdout and stderr arrive as messages tagged with their source, sends go
+    // to stdin; closing the handle kills the process
+    fn spawn_process(&mut self, program: String, args: Vec<String>, cwd: Option<String>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+        self.rt_handle.spawn(async move {
+            info!("Spawning process {}", program);
+            let mut command = tokio::process::Command::new(&program);
+            command.args(&args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true);
+            if let Some(cwd) = &cwd {
+                command.current_dir(cwd);
+            }
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    error!("Could not spawn {}: {}", program, err);
+                    tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default();
+                    return;
+                }
+            };
+            let mut stdin = child.stdin.take();
+            let mut stdout = child.stdout.take().expect("stdout is piped");
+            let mut stderr = child.stderr.take().expect("stderr is piped");
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+
+            let mut stdout_buf = [0; 65536];
+            let mut stderr_buf = [0; 65536];
+            let mut stdout_framer = Framer::new(Framing::Raw);
+            let mut stderr_framer = Framer::new(Framing::Raw);
+            // stdin only encodes, but keeps its own framer so it never shares state with stdout
+            let mut stdin_framer = Framer::new(Framing::Raw);
+            let mut stdout_open = true;
+            let mut stderr_open = true;
+            let mut status = None;
+            // output written before the exit gets a moment to arrive before the
+            // exit is reported, but a grandchild holding the pipes can't hold it up
+            let mut drain_deadline = None;
+            while status.is_none() || stdout_open || stderr_open {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::SetFraming(framing)) => {
+                                stdout_framer.set_framing(framing.clone());
+                                stderr_framer.set_framing(framing.clone());
+                                stdin_framer.set_framing(framing);
+                            },
+                            Some(msg @ SocketMessage::Message(_)) | Some(msg @ SocketMessage::BinaryMessage(_)) => {
+                                match stdin.as_mut() {
+                                    Some(pipe) => {
+                                        // the exit status will tell the host what went wrong
+                                        if let Err(err) = send_tcp_message(pipe, &stdin_framer, msg).await {
+                                            warn!("Could not write to {}: {}", program, err);
+                                        }
+                                    },
+                                    None => warn!("Dropping message sent after closing stdin"),
+                                }
+                            },
+                            Some(SocketMessage::ShutdownWrite) => {
+                                stdin = None;
+                            },
+                            Some(SocketMessage::Finish(timeout_ms)) => {
+                                // an EOF on stdin is how most tools are told to wrap up
+                                drop(stdin.take());
+                                if tokio::time::timeout(std::time::Duration::from_millis(timeout_ms.into()), child.wait()).await.is_err() {
+                                    warn!("{} didn't exit within {}ms, killing it", program, timeout_ms);
+                                }
+                                return;
+                            },
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | None => return,
+                            Some(msg) => warn!("Process {} ignores {}", program, msg.kind()),
+                        }
+                    },
+                    read = stdout.read(&mut stdout_buf), if stdout_open => {
+                        stdout_open = forward_output(read, &stdout_buf, &mut stdout_framer, "stdout", &tx_from_sock);
+                    },
+                    read = stderr.read(&mut stderr_buf), if stderr_open => {
+                        stderr_open = forward_output(read, &stderr_buf, &mut stderr_framer, "stderr", &tx_from_sock);
+                    },
+                    exit = child.wait(), if status.is_none() => {
+                        match exit {
+                            Ok(exit) => {
+                                status = Some(exit);
+                                drain_deadline = Some(tokio::time::Instant::now() + std::time::Duration::from_millis(200));
+                            },
+                            Err(err) => {
+                                tx_from_sock.send(SocketMessage::Error(err.to_string())).unwrap_or_default();
+                                return;
+                            }
+                        }
+                    },
+                    _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                        warn!("{} exited but its output is still open, probably held by a child process", program);
+                        break;
+                    },
+                };
+            }
+            if let Some(status) = status {
+                info!("Process {} exited: {}", program, status);
+                tx_from_sock.send(SocketMessage::Exited(status.code(), status.to_string())).unwrap_or_default();
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // Every record is its own message, the handle closes after the last one;
+    // an empty record type means both A and AAAA
+    fn resolve(&mut self, name: String, record_type: String) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let dns_server = self.dns_server;
+
+        self.rt_handle.spawn(async move {
+            info!("Resolving {} {}", record_type, name);
+            let lookup = resolve_records(&name, &record_type, dns_server);
+            tokio::pin!(lookup);
+            loop {
+                tokio::select! {
+                    records = &mut lookup => {
+                        match records {
+                            Ok(records) => {
+                                for record in records {
+                                    tx_from_sock.send(SocketMessage::Message(record)).unwrap_or_default();
+                                }
+                            },
+                            Err(err) => {
+                                warn!("Could not resolve {}: {}", name, err);
+                                tx_from_sock.send(SocketMessage::Error(err)).unwrap_or_default();
+                            }
+                        }
+                        break;
+                    },
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            // closed before the answer came in
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("DNS lookup ignores {}", msg.kind()),
+                        }
+                    },
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    // Ticks that pile up between polls are delivered as one, with the
+    // number of ticks as the message
+    fn timer(&mut self, ms: u32, repeat: bool) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+
+        self.rt_handle.spawn(async move {
+            let period = std::time::Duration::from_millis(ms.max(1).into());
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            let pending = Arc::new(AtomicU64::new(0));
+            tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
+            loop {
+                tokio::select! {
+                    from_c_message = rx_to_sock.recv() => {
+                        match from_c_message {
+                            Some(SocketMessage::Message(_)) | Some(SocketMessage::BinaryMessage(_)) => {}, // timers ignore sends
+                            Some(SocketMessage::Disconnect) | Some(SocketMessage::Close(_, _)) | Some(SocketMessage::Finish(_)) | None => break,
+                            Some(msg) => warn!("Timer ignores {}", msg.kind()),
+                        }
+                    },
+                    _ = interval.tick() => {
+                        // the host only needs waking for the first tick it hasn't collected yet
+                        if pending.fetch_add(1, Ordering::SeqCst) == 0 {
+                            tx_from_sock.send(SocketMessage::Tick(pending.clone())).unwrap_or_default();
+                        }
+                        if !repeat {
+                            tx_from_sock.send(SocketMessage::Disconnect).unwrap_or_default();
+                            break;
+                        }
+                    },
+                };
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    async fn _handle_get(url: String, tls: TlsOptions, dest: std::sync::mpsc::Sender<SocketMessage>) {
+        info!("HTTP GET: {}", url);
+        let client = match tls.http_client() {
+            Ok(client) => client,
+            Err(err) => {
+                dest.send(SocketMessage::Error(err)).expect("TX error on http get error");
+                return;
+            }
+        };
+        let resp = match client.get(&url).send().await {
+            Ok(resp) => resp,
+            Err(err) => {
+                error!("HTTP GET failed: {}", err);
+                dest.send(SocketMessage::Error(err.to_string())).expect("TX error on http post error");
+                return;
+            }
+        };
+        dest.send(SocketMessage::Addresses(None, resp.remote_addr().map(|addr| addr.to_string()))).unwrap_or_default();
+        match resp.bytes().await {
+            Ok(body) => {
+                dest.send(SocketMessage::BinaryMessage(body.to_vec())).expect("TX error on http body");
+            },
+            Err(body_err) => {
+                dest.send(SocketMessage::Error(body_err.to_string())).expect("TX error on http body error");
+            }
+        };
+    }
+
+    fn open_http_get_simple(&mut self, url: String) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let tls = self.tls.clone();
+
+        self.rt_handle.spawn(async move {
+            let get_handler = PollnetContext::_handle_get(url, tls, tx_from_sock);
+            tokio::pin!(get_handler);
+            loop {
+                tokio::select! {
+                    _ = &mut get_handler => break,
+                    from_c_message = rx_to_sock.recv() => {
+                        if let Some(SocketMessage::Disconnect) = from_c_message {
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    async fn _handle_post(url: String, content_type: String, body: Vec<u8>, tls: TlsOptions, dest: std::sync::mpsc::Sender<SocketMessage>) {
+        info!("HTTP POST: {} (w/ {})", url, content_type);
+        let client = match tls.http_client() {
+            Ok(client) => client,
+            Err(err) => {
+                dest.send(SocketMessage::Error(err)).expect("TX error on http post error");
+                return;
+            }
+        };
+        let resp = match client.post(&url).header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body).send().await {
+            Ok(resp) => resp,
+            Err(err) => {
+                error!("HTTP POST failed: {}", err);
+                dest.send(SocketMessage::Error(err.to_string())).expect("TX error on http post error");
+                return;
+            }
+        };
+        dest.send(SocketMessage::Addresses(None, resp.remote_addr().map(|addr| addr.to_string()))).unwrap_or_default();
+        match resp.bytes().await {
+            Ok(body) => {
+                dest.send(SocketMessage::BinaryMessage(body.to_vec())).expect("TX error on http body");
+            },
+            Err(body_err) => {
+                dest.send(SocketMessage::Error(body_err.to_string())).expect("TX error on http body error");
+            }
+        };
+    }
+
+    fn open_http_post_simple(&mut self, url: String, content_type: String, body: Vec<u8>) -> u32 {
+        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
+        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+        let tls = self.tls.clone();
+
+        self.rt_handle.spawn(async move {
+            let post_handler = PollnetContext::_handle_post(url, content_type, body, tls, tx_from_sock);
+            tokio::pin!(post_handler);
+            loop {
+                tokio::select! {
+                    _ = &mut post_handler => break,
+                    from_c_message = rx_to_sock.recv() => {
+                        if let Some(SocketMessage::Disconnect) = from_c_message {
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+
+        self._add_socket(tx_to_sock, rx_from_sock)
+    }
+
+    fn close_all(&mut self) {
+        info!("Closing all sockets!");
+        for (_, sock) in self.sockets.iter_mut() {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    // don't care about errors at this point
+                    block_on(sock.tx.send(SocketMessage::Disconnect)).unwrap_or_default();
+                    sock.status = SocketStatus::CLOSED;
+                },
+                _ => (),
+            }
+        }
+        self.sockets.clear(); // everything should be closed and safely droppable
+    }
+
+    fn close(&mut self, handle: u32) {
+        self._close(handle, SocketMessage::Disconnect)
+    }
+
+    // Only stream sockets and processes wait for the other end, everything else just closes
+    fn close_graceful(&mut self, handle: u32, timeout_ms: u32) {
+        self._close(handle, SocketMessage::Finish(timeout_ms))
+    }
+
+    fn shutdown_write(&mut self, handle: u32) {
+        self._try_send(handle, SocketMessage::ShutdownWrite)
+    }
+
+    // Only websockets make use of the code and reason, everything else just closes
+    fn close_with_reason(&mut self, handle: u32, code: u32, reason: String) {
+        let code = sendable_close_code(code);
+        self._close(handle, SocketMessage::Close(code, truncate_close_reason(reason)))
+    }
+
+    fn _close(&mut self, handle: u32, close_message: SocketMessage) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    let _ = block_on(sock.tx.send(close_message));
+                    sock.status = SocketStatus::CLOSED;
+                },
+                _ => (),
+            }
+            // Note: since we don't wait here for any kind of "disconnect" reply,
+            // a socket that has been closed should just return without sending a reply
+            self.sockets.remove(&handle);
+        }
+    }
+
+    fn send(&mut self, handle: u32, msg: String) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    sock.tx.try_send(SocketMessage::Message(msg)).unwrap_or_default()
+                },
+                _ => (),
+            };
+        }
+    }
+
+    fn send_binary(&mut self, handle: u32, msg: Vec<u8>) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    sock.tx.try_send(SocketMessage::BinaryMessage(msg)).unwrap_or_default()
+                },
+                _ => (),
+            };
+        }
+    }
+
+    fn send_to(&mut self, handle: u32, addr: String, msg: Vec<u8>) {
+        self._try_send(handle, SocketMessage::SendTo(addr, msg))
+    }
+
+    fn add_virtual_file(&mut self, handle: u32, filename: String, filedata: Vec<u8>) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    sock.tx.try_send(SocketMessage::FileAdd(filename, filedata)).unwrap_or_default()
+                },
+                _ => (),
+            };
+        }
+    }
+
+    fn remove_virtual_file(&mut self, handle: u32, filename: String) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    sock.tx.try_send(SocketMessage::FileRemove(filename)).unwrap_or_default()
+                },
+                _ => (),
+            };
+        }
+    }
+
+    // Hands a message to the socket's task if the socket is still alive
+    fn _try_send(&mut self, handle: u32, msg: SocketMessage) {
+        if let Some(sock) = self.sockets.get_mut(&handle) {
+            match sock.status {
+                SocketStatus::OPEN | SocketStatus::OPENING => {
+                    sock.tx.try_send(msg).unwrap_or_default()
+                },
+                _ => (),
+            };
+        }
+    }
+
+    fn set_listener_limits(&mut self, handle: u32, limits: ListenerLimits) {
+        self._try_send(handle, SocketMessage::SetLimits(limits))
+    }
+
+    fn set_reconnect(&mut self, handle: u32, policy: ReconnectPolicy) {
+        self._try_send(handle, SocketMessage::SetReconnect(policy))
+    }
+
+    fn add_hello_message(&mut self, handle: u32, msg: String) {
+        self._try_send(handle, SocketMessage::AddHello(msg))
+    }
+
+    fn set_heartbeat(&mut self, handle: u32, config: HeartbeatConfig) {
+        self._try_send(handle, SocketMessage::SetHeartbeat(config))
+    }
+
+    fn ping(&mut self, handle: u32, payload: Vec<u8>) {
+        self._try_send(handle, SocketMessage::Ping(payload))
+    }
+
+    fn set_tcp_options(&mut self, handle: u32, options: TcpOptions) {
+        self._try_send(handle, SocketMessage::SetTcpOptions(options))
+    }
+
+    fn set_udp_options(&mut self, handle: u32, options: UdpOptions) {
+        self._try_send(handle, SocketMessage::SetUdpOptions(options))
+    }
+
+    fn join_multicast(&mut self, handle: u32, group: String, interface: String) {
+        self._try_send(handle, SocketMessage::JoinMulticast(group, interface))
+    }
+
+    fn leave_multicast(&mut self, handle: u32, group: String, interface: String) {
+        self._try_send(handle, SocketMessage::LeaveMulticast(group, interface))
+    }
+
+    fn set_framing(&mut self, handle: u32, framing: Framing) {
+        self._try_send(handle, SocketMessage::SetFraming(framing))
+    }
+
+    fn set_ws_limits(&mut self, handle: u32, limits: WsLimits) {
+        self._try_send(handle, SocketMessage::SetWsLimits(limits))
+    }
+
+    fn set_ws_compression(&mut self, handle: u32, compression: WsCompression) {
+        self._try_send(handle, SocketMessage::SetWsCompression(compression))
+    }
+
+    fn set_ws_policy(&mut self, handle: u32, policy: WsPolicy) {
+        self._try_send(handle, SocketMessage::SetPolicy(policy))
+    }
+
+    // `group` of None reaches every client of the listener
+    fn broadcast(&mut self, handle: u32, group: Option<String>, msg: SocketMessage) {
+        self._try_send(handle, SocketMessage::Broadcast(group, Box::new(msg)))
+    }
+
+    // Membership is tracked right away so a broadcast sent next can't miss the client
+    fn join_group(&mut self, handle: u32, group: String) {
+        if let Some(membership) = self.sockets.get(&handle).and_then(|sock| sock.membership.as_ref()) {
+            membership.join(group);
+        }
+    }
+
+    fn leave_group(&mut self, handle: u32, group: &str) {
+        if let Some(membership) = self.sockets.get(&handle).and_then(|sock| sock.membership.as_ref()) {
+            membership.leave(group);
+        }
+    }
+
+    fn update(&mut self, handle: u32, blocking: bool) -> SocketResult {
+        let sock = match self.sockets.get_mut(&handle) {
+            Some(sock) => sock,
+            None => return SocketResult::INVALIDHANDLE,
+        };
+
+        match sock.status {
+            SocketStatus::OPEN | SocketStatus::OPENING => {
+                // This block is apparently impossible to move into a helper function
+                // for borrow checker "reasons"
+                let result = if blocking {
+                    sock.rx.recv().map_err(|_err| RecvError::Disconnected)
+                } else {
+                    sock.rx.try_recv().map_err(|err| match err {
+                        std::sync::mpsc::TryRecvError::Empty => RecvError::Empty,
+                        std::sync::mpsc::TryRecvError::Disconnected => RecvError::Disconnected,
+                    })
+                };
+
+                if result.is_ok() {
+                    sock.message_type = MessageType::NONE;
+                }
+                match result {
+                    Ok(SocketMessage::Connect) => {
+                        sock.status = SocketStatus::OPEN;
+                        SocketResult::OPENING
+                    },
+                    Ok(SocketMessage::Disconnect) | Err(RecvError::Disconnected) => {
+                        sock.status = SocketStatus::CLOSED;
+                        SocketResult::CLOSED
+                    },
+                    Ok(SocketMessage::Message(msg)) => {
+                        sock.message = Some(msg.into_bytes());
+                        sock.message_type = MessageType::TEXT;
+                        SocketResult::HASDATA
+                    },
+                    Ok(SocketMessage::BinaryMessage(msg)) => {
+                        sock.message = Some(msg);
+                        sock.message_type = MessageType::BINARY;
+                        SocketResult::HASDATA
+                    },
+                    Ok(SocketMessage::Close(code, reason)) => {
+                        sock.close_reason = Some(reason.clone());
+                        sock.message = Some(reason.into_bytes());
+                        sock.message_type = MessageType::CLOSE;
+                        sock.close_code = code;
+                        sock.status = SocketStatus::CLOSED;
+                        SocketResult::CLOSED
+                    },
+                    Ok(SocketMessage::Error(err)) => {
+                        sock.error = Some(err);
+                        sock.status = SocketStatus::ERROR;
+                        SocketResult::ERROR
+                    },
+                    Ok(SocketMessage::NewClient(conn)) => {
+                        // can't use self._next_handle() either for questionable reasons
+                        let new_handle = PollnetContext::_next_handle_that_satisfies_the_borrow_checker(&mut self.next_handle);
+                        sock.last_client_handle = new_handle;
+                        sock.message = Some(conn.id.into_bytes());
+                        // assume client sockets start open?
+                        let mut client_socket = PollnetSocket::new(conn.tx, conn.rx, SocketStatus::OPEN);
+                        client_socket.handshake = conn.handshake;
+                        client_socket.membership = conn.membership;
+                        client_socket.local_addr = conn.local_addr;
+                        client_socket.peer_addr = conn.peer_addr;
+                        self.sockets.insert(new_handle, client_socket);
+                        SocketResult::NEWCLIENT
+                    },
+                    Ok(SocketMessage::Rejected(msg)) => {
+                        sock.message = Some(msg.into_bytes());
+                        SocketResult::REJECTED
+                    },
+                    Ok(SocketMessage::Warning(msg)) => {
+                        sock.message = Some(msg.into_bytes());
+                        SocketResult::WARNING
+                    },
+                    Ok(SocketMessage::Handshake(info)) => {
+                        sock.handshake = Some(info);
+                        SocketResult::OPENING
+                    },
+                    Ok(SocketMessage::Reconnecting(reason)) => {
+                        sock.message = Some(reason.into_bytes());
+                        sock.status = SocketStatus::OPENING;
+                        // the next connection reports its own
+                        sock.local_addr = None;
+                        sock.peer_addr = None;
+                        SocketResult::RECONNECTING
+                    },
+                    Ok(SocketMessage::Reconnected) => {
+                        sock.status = SocketStatus::OPEN;
+                        SocketResult::RECONNECTED
+                    },
+                    Ok(SocketMessage::Eof) => SocketResult::EOF,
+                    Ok(SocketMessage::Addresses(local_addr, peer_addr)) => {
+                        sock.local_addr = local_addr;
+                        sock.peer_addr = peer_addr;
+                        SocketResult::NODATA
+                    },
+                    Ok(SocketMessage::Tick(pending)) => {
+                        sock.message = Some(pending.swap(0, Ordering::SeqCst).to_string().into_bytes());
+                        sock.message_type = MessageType::TEXT;
+                        SocketResult::HASDATA
+                    },
+                    Ok(SocketMessage::ProcessOutput(source, data)) => {
+                        sock.message = Some(data);
+                        sock.message_type = MessageType::BINARY;
+                        sock.message_source = Some(source.to_string());
+                        SocketResult::HASDATA
+                    },
+                    Ok(SocketMessage::Exited(code, description)) => {
+                        sock.exit_code = code;
+                        sock.close_reason = Some(description.clone());
+                        sock.message = Some(description.into_bytes());
+                        sock.message_type = MessageType::CLOSE;
+                        sock.status = SocketStatus::CLOSED;
+                        SocketResult::CLOSED
+                    },
+                    Ok(SocketMessage::Datagram(source, data)) => {
+                        sock.message = Some(data);
+                        sock.message_type = MessageType::BINARY;
+                        sock.message_source = Some(source.to_string());
+                        SocketResult::HASDATA
+                    },
+                    Ok(SocketMessage::RoundTrip(rtt_ms)) => {
+                        sock.rtt_ms = rtt_ms;
+                        SocketResult::PONG
+                    },
+                    Ok(_) => SocketResult::NODATA,
+                    Err(RecvError::Empty) => SocketResult::NODATA,
+                }
+            },
+            SocketStatus::CLOSED => SocketResult::CLOSED,
+            _ => SocketResult::ERROR
+        }
+    }
+
+
+    fn shutdown(&mut self) {
+        info!("Starting shutdown");
+        self.close_all();
+        info!("All sockets should be closed?");
+        if let Some(tx) = self.shutdown_tx.take() {
+            tx.send(0).unwrap_or_default();
+        }
+        if let Some(handle) = self.thread.take() {
+            handle.join().unwrap_or_default();
+        }
+        info!("Thread should be joined?");
+    }
+}
+
+fn c_str_to_string(s: *const c_char) -> String {
+    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+}
+
+fn c_data_to_vec(data: *const u8, datasize: u32) -> Vec<u8> {
+    // empty buffers are allowed to be NULL
+    if datasize == 0 {
+        return Vec::new();
+    }
+    unsafe { std::slice::from_raw_parts(data, datasize as usize).to_vec() }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_init() -> *mut PollnetContext {
+    Box::into_raw(Box::new(PollnetContext::new()))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_shutdown(ctx: *mut PollnetContext) {
+    info!("Requested ctx close!");
+    let ctx = unsafe{&mut *ctx};
+    ctx.shutdown();
+
+    // take ownership and drop
+    let b = unsafe{ Box::from_raw(ctx) };
+    drop(b);
+    info!("Everything should be dead now!");
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_ws(ctx: *mut PollnetContext, url: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let url = c_str_to_string(url);
+    ctx.open_ws(url, String::new(), String::new())
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_ws_with_headers(ctx: *mut PollnetContext, url: *const c_char, headers: *const c_char, protocols: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let url = c_str_to_string(url);
+    let headers = c_str_to_string(headers);
+    let protocols = c_str_to_string(protocols);
+    ctx.open_ws(url, headers, protocols)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_listen_ws(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.listen_ws(addr)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_tcp(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.open_tcp(addr, None)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_tls(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    let tls = ctx.tls.clone();
+    ctx.open_tcp(addr, Some(tls))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_unix(ctx: *mut PollnetContext, path: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let path = c_str_to_string(path);
+    ctx.open_unix(path)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_listen_unix(ctx: *mut PollnetContext, path: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let path = c_str_to_string(path);
+    ctx.listen_unix(path)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_resolve(ctx: *mut PollnetContext, hostname: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let hostname = c_str_to_string(hostname);
+    ctx.resolve(hostname, String::new())
+}
+
+// record_type is a DNS type name like "A", "AAAA", "SRV" or "TXT"
+#[no_mangle]
+pub extern "C" fn pollnet_resolve_records(ctx: *mut PollnetContext, name: *const c_char, record_type: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let name = c_str_to_string(name);
+    let record_type = c_str_to_string(record_type);
+    ctx.resolve(name, record_type)
+}
+
+// "ip" or "ip:port" of the DNS server for lookups from now on, empty goes
+// back to the system configuration; returns 0 and keeps the current server
+// if addr isn't a valid address
+#[no_mangle]
+pub extern "C" fn pollnet_set_dns_server(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.dns_server = if addr.is_empty() {
+        None
+    } else if let Ok(server) = addr.parse::<SocketAddr>() {
+        Some(server)
+    } else if let Ok(ip) = addr.parse::<IpAddr>() {
+        Some(SocketAddr::new(ip, 53))
+    } else {
+        warn!("Invalid DNS server {}", addr);
+        return 0;
+    };
+    1
+}
+
+// Proxy for TCP, TLS and WS clients opened from now on, as
+// "socks5://[user:pass@]host[:port]" or "http://[user:pass@]host[:port]"
+// for HTTP CONNECT; empty goes back to a direct connection. Returns 0 for
+// an invalid url, and clients opened until the next call fail to connect.
+#[no_mangle]
+pub extern "C" fn pollnet_set_proxy(ctx: *mut PollnetContext, url: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let url = c_str_to_string(url);
+    if url.is_empty() {
+        ctx.proxy.server = None;
+        return 1;
+    }
+    let server = ProxyServer::parse(&url);
+    if let Err(err) = &server {
+        warn!("{}", err);
+    }
+    let ok = server.is_ok();
+    ctx.proxy.server = Some(server);
+    ok as u32
+}
+
+// Without an explicit proxy, use HTTP_PROXY/HTTPS_PROXY/ALL_PROXY and
+// NO_PROXY from the environment for clients opened from now on
+#[no_mangle]
+pub extern "C" fn pollnet_set_proxy_from_env(ctx: *mut PollnetContext, enabled: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.proxy.from_env = enabled != 0;
+}
+
+// Fires once after `ms`, or every `ms` if repeat is nonzero; each HASDATA
+// carries how many ticks have passed since the last one was polled
+#[no_mangle]
+pub extern "C" fn pollnet_timer(ctx: *mut PollnetContext, ms: u32, repeat: u32) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    ctx.timer(ms, repeat != 0)
+}
+
+// One argument per line; an empty or null cwd keeps the current directory
+#[no_mangle]
+pub extern "C" fn pollnet_spawn_process(ctx: *mut PollnetContext, program: *const c_char, args: *const c_char, cwd: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let program = c_str_to_string(program);
+    let args = if args.is_null() { String::new() } else { c_str_to_string(args) };
+    let args = args.lines().map(|arg| arg.to_string()).collect();
+    let cwd = if cwd.is_null() { String::new() } else { c_str_to_string(cwd) };
+    let cwd = if cwd.is_empty() { None } else { Some(cwd) };
+    ctx.spawn_process(program, args, cwd)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_udp(ctx: *mut PollnetContext, bind_addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let bind_addr = c_str_to_string(bind_addr);
+    ctx.open_udp(bind_addr, None)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_open_udp_connected(ctx: *mut PollnetContext, bind_addr: *const c_char, peer_addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let bind_addr = c_str_to_string(bind_addr);
+    let peer_addr = c_str_to_string(peer_addr);
+    ctx.open_udp(bind_addr, Some(peer_addr))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_listen_tcp(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.listen_tcp(addr, None)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_listen_tls(ctx: *mut PollnetContext, addr: *const c_char, cert_pem: *const c_char, key_pem: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    let cert_pem = c_str_to_string(cert_pem);
+    let key_pem = c_str_to_string(key_pem);
+    ctx.listen_tcp(addr, Some((cert_pem, key_pem)))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_simple_http_get(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.open_http_get_simple(addr)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_simple_http_post(ctx: *mut PollnetContext, addr: *const c_char, content_type: *const c_char, bodydata: *const u8, bodysize: u32) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    let content_type = c_str_to_string(content_type);
+    let body = c_data_to_vec(bodydata, bodysize);
+    ctx.open_http_post_simple(addr, content_type, body)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_serve_static_http(ctx: *mut PollnetContext, addr: *const c_char, serve_dir: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    let serve_dir = c_str_to_string(serve_dir);
+    ctx.serve_http(addr, Some(serve_dir))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_serve_http(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    ctx.serve_http(addr, None)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_close(ctx: *mut PollnetContext, handle: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.close(handle)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_close_with_reason(ctx: *mut PollnetContext, handle: u32, code: u32, reason: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let reason = c_str_to_string(reason);
+    ctx.close_with_reason(handle, code, reason)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_close_graceful(ctx: *mut PollnetContext, handle: u32, timeout_ms: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.close_graceful(handle, timeout_ms)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_shutdown_write(ctx: *mut PollnetContext, handle: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.shutdown_write(handle)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_close_all(ctx: *mut PollnetContext) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.close_all()
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_status(ctx: *mut PollnetContext, handle: u32) -> SocketStatus {
+    let ctx = unsafe{&*ctx};
+    if let Some(socket) = ctx.sockets.get(&handle) {
+        socket.status
+    } else {
+        SocketStatus::INVALIDHANDLE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_send(ctx: *mut PollnetContext, handle: u32, msg: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let msg = c_str_to_string(msg);
+    ctx.send(handle, msg)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_send_binary(ctx: *mut PollnetContext, handle: u32, msg: *const u8, msgsize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let msg = c_data_to_vec(msg, msgsize);
+    ctx.send_binary(handle, msg)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_send_to(ctx: *mut PollnetContext, handle: u32, addr: *const c_char, msg: *const u8, msgsize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let addr = c_str_to_string(addr);
+    let msg = c_data_to_vec(msg, msgsize);
+    ctx.send_to(handle, addr, msg)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_add_virtual_file(ctx: *mut PollnetContext, handle: u32, filename: *const c_char, filedata: *const u8, datasize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let filename = c_str_to_string(filename);
+    let filedata = c_data_to_vec(filedata, datasize);
+    ctx.add_virtual_file(handle, filename, filedata)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_remove_virtual_file(ctx: *mut PollnetContext, handle: u32, filename: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let filename = c_str_to_string(filename);
+    ctx.remove_virtual_file(handle, filename)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_listener_limits(ctx: *mut PollnetContext, handle: u32, max_clients: u32, max_per_ip: u32, rate: f64, burst: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.set_listener_limits(handle, ListenerLimits{max_clients, max_per_ip, rate, burst})
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_reconnect(ctx: *mut PollnetContext, handle: u32, initial_delay_ms: u32, max_delay_ms: u32, max_attempts: u32, jitter: f64) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.set_reconnect(handle, ReconnectPolicy{initial_delay_ms, max_delay_ms, max_attempts, jitter})
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_add_hello_message(ctx: *mut PollnetContext, handle: u32, msg: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let msg = c_str_to_string(msg);
+    ctx.add_hello_message(handle, msg)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_add_tls_ca_pem(ctx: *mut PollnetContext, pem: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let pem = c_str_to_string(pem);
+    ctx.tls.ca_pems.push(pem);
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_tls_client_cert(ctx: *mut PollnetContext, cert_pem: *const c_char, key_pem: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let cert_pem = c_str_to_string(cert_pem);
+    let key_pem = c_str_to_string(key_pem);
+    ctx.tls.client_identity = if cert_pem.is_empty() { None } else { Some((cert_pem, key_pem)) };
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_tls_server_name(ctx: *mut PollnetContext, server_name: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let server_name = c_str_to_string(server_name);
+    ctx.tls.server_name = if server_name.is_empty() { None } else { Some(server_name) };
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_tls_insecure(ctx: *mut PollnetContext, insecure: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.tls.insecure = insecure != 0;
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_clear_tls_options(ctx: *mut PollnetContext) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.tls = TlsOptions::default();
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_default_tcp_options(ctx: *mut PollnetContext, nodelay: u32, keepalive_ms: u32, keepalive_interval_ms: u32, keepalive_count: u32, send_buffer_size: u32, recv_buffer_size: u32, linger_secs: i32, reuse_addr: u32, reuse_port: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.tcp_options = TcpOptions{
+        nodelay: nodelay != 0,
+        keepalive_ms,
+        keepalive_interval_ms,
+        keepalive_count,
+        send_buffer_size,
+        recv_buffer_size,
+        linger_secs,
+        reuse_addr: reuse_addr != 0,
+        reuse_port: reuse_port != 0,
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_tcp_options(ctx: *mut PollnetContext, handle: u32, nodelay: u32, keepalive_ms: u32, keepalive_interval_ms: u32, keepalive_count: u32, send_buffer_size: u32, recv_buffer_size: u32, linger_secs: i32) {
+    let ctx = unsafe{&mut *ctx};
+    let options = TcpOptions{
+        nodelay: nodelay != 0,
+        keepalive_ms,
+        keepalive_interval_ms,
+        keepalive_count,
+        send_buffer_size,
+        recv_buffer_size,
+        linger_secs,
+        reuse_addr: false,
+        reuse_port: false,
+    };
+    ctx.set_tcp_options(handle, options)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_default_udp_options(ctx: *mut PollnetContext, broadcast: u32, ttl: u32, multicast_ttl: u32, multicast_loop: u32, reuse_addr: u32, reuse_port: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.udp_options = UdpOptions{
+        broadcast: broadcast != 0,
+        ttl,
+        multicast_ttl,
+        multicast_loop: multicast_loop != 0,
+        reuse_addr: reuse_addr != 0,
+        reuse_port: reuse_port != 0,
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_udp_options(ctx: *mut PollnetContext, handle: u32, broadcast: u32, ttl: u32, multicast_ttl: u32, multicast_loop: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let options = UdpOptions{
+        broadcast: broadcast != 0,
+        ttl,
+        multicast_ttl,
+        multicast_loop: multicast_loop != 0,
+        reuse_addr: false,
+        reuse_port: false,
+    };
+    ctx.set_udp_options(handle, options)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_join_multicast(ctx: *mut PollnetContext, handle: u32, group: *const c_char, interface: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    let interface = if interface.is_null() { String::new() } else { c_str_to_string(interface) };
+    ctx.join_multicast(handle, group, interface)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_leave_multicast(ctx: *mut PollnetContext, handle: u32, group: *const c_char, interface: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    let interface = if interface.is_null() { String::new() } else { c_str_to_string(interface) };
+    ctx.leave_multicast(handle, group, interface)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_framing(ctx: *mut PollnetContext, handle: u32, mode: u32, delimiter: *const u8, delimiter_size: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let delimiter = if delimiter.is_null() { Vec::new() } else { c_data_to_vec(delimiter, delimiter_size) };
+    match Framing::from_mode(mode, delimiter) {
+        Some(framing) => ctx.set_framing(handle, framing),
+        None => warn!("Ignoring invalid framing mode {}", mode),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_default_ws_limits(ctx: *mut PollnetContext, max_message_size: u32, max_frame_size: u32, max_send_queue: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.ws_limits = WsLimits{max_message_size, max_frame_size, max_send_queue};
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_ws_limits(ctx: *mut PollnetContext, handle: u32, max_message_size: u32, max_frame_size: u32, max_send_queue: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.set_ws_limits(handle, WsLimits{max_message_size, max_frame_size, max_send_queue})
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_default_ws_compression(ctx: *mut PollnetContext, enabled: u32, window_bits: u32) {
+    let ctx = unsafe{&mut *ctx};
+    match WsCompression::new(enabled != 0, window_bits) {
+        Some(compression) => ctx.ws_compression = compression,
+        None => warn!("Ignoring invalid deflate window bits {}", window_bits),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_ws_compression(ctx: *mut PollnetContext, handle: u32, enabled: u32, window_bits: u32) {
+    let ctx = unsafe{&mut *ctx};
+    match WsCompression::new(enabled != 0, window_bits) {
+        Some(compression) => ctx.set_ws_compression(handle, compression),
+        None => warn!("Ignoring invalid deflate window bits {}", window_bits),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_heartbeat(ctx: *mut PollnetContext, handle: u32, interval_ms: u32, timeout_ms: u32) {
+    let ctx = unsafe{&mut *ctx};
+    ctx.set_heartbeat(handle, HeartbeatConfig{interval_ms, timeout_ms})
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_ping(ctx: *mut PollnetContext, handle: u32, payload: *const u8, payloadsize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let payload = c_data_to_vec(payload, payloadsize);
+    ctx.ping(handle, payload)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_set_ws_policy(ctx: *mut PollnetContext, handle: u32, origins: *const c_char, token_name: *const c_char, token: *const c_char, paths: *const c_char, protocols: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let split_list = |list: *const c_char| -> Vec<String> {
+        c_str_to_string(list).split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    };
+    let policy = WsPolicy{
+        origins: split_list(origins),
+        token_name: c_str_to_string(token_name),
+        token: c_str_to_string(token),
+        paths: split_list(paths),
+        protocols: split_list(protocols),
+    };
+    ctx.set_ws_policy(handle, policy)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_broadcast(ctx: *mut PollnetContext, handle: u32, msg: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let msg = c_str_to_string(msg);
+    ctx.broadcast(handle, None, SocketMessage::Message(msg))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_broadcast_binary(ctx: *mut PollnetContext, handle: u32, msg: *const u8, msgsize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let msg = c_data_to_vec(msg, msgsize);
+    ctx.broadcast(handle, None, SocketMessage::BinaryMessage(msg))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_send_to_group(ctx: *mut PollnetContext, handle: u32, group: *const c_char, msg: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    let msg = c_str_to_string(msg);
+    ctx.broadcast(handle, Some(group), SocketMessage::Message(msg))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_send_binary_to_group(ctx: *mut PollnetContext, handle: u32, group: *const c_char, msg: *const u8, msgsize: u32) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    let msg = c_data_to_vec(msg, msgsize);
+    ctx.broadcast(handle, Some(group), SocketMessage::BinaryMessage(msg))
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_join_group(ctx: *mut PollnetContext, handle: u32, group: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    ctx.join_group(handle, group)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_leave_group(ctx: *mut PollnetContext, handle: u32, group: *const c_char) {
+    let ctx = unsafe{&mut *ctx};
+    let group = c_str_to_string(group);
+    ctx.leave_group(handle, &group)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_rtt(ctx: *mut PollnetContext, handle: u32) -> f64 {
+    let ctx = unsafe{&*ctx};
+    match ctx.sockets.get(&handle) {
+        Some(socket) => socket.rtt_ms,
+        None => -1.0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_update(ctx: *mut PollnetContext, handle: u32) -> SocketResult {
+    let ctx = unsafe{&mut *ctx};
+    ctx.update(handle, false)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_update_blocking(ctx: *mut PollnetContext, handle: u32) -> SocketResult {
+    let ctx = unsafe{&mut *ctx};
+    ctx.update(handle, true)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get_mut(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    // a message that doesn't fit stays put for another try with a bigger buffer
+    match &socket.message {
+        Some(msg) => {
+            let ncopy = copy_to_dest(msg, dest, dest_size);
+            if ncopy >= 0 {
+                socket.message = None;
+            }
+            ncopy
+        },
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_message_type(ctx: *mut PollnetContext, handle: u32) -> MessageType {
+    let ctx = unsafe{&*ctx};
+    match ctx.sockets.get(&handle) {
+        Some(socket) => socket.message_type,
+        None => MessageType::NONE,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_close_code(ctx: *mut PollnetContext, handle: u32) -> u32 {
+    let ctx = unsafe{&*ctx};
+    match ctx.sockets.get(&handle) {
+        Some(socket) => socket.close_code as u32,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_close_reason(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.close_reason {
+        Some(reason) => copy_to_dest(reason.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_connected_client_handle(ctx: *mut PollnetContext, handle: u32) -> u32 {
+    let ctx = unsafe{&mut *ctx};
+    match ctx.sockets.get_mut(&handle) {
+        Some(socket) => socket.last_client_handle,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_error(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get_mut(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.error {
+        Some(msg) => {
+            let ncopy = copy_to_dest(msg.as_bytes(), dest, dest_size);
+            if ncopy >= 0 {
+                socket.error = None;
+            }
+            ncopy
+        },
+        None => 0,
+    }
+}
+
+// Every getter answers -2 when dest is too small, so it can't be mistaken
+// for an empty value
+fn copy_to_dest(data: &[u8], dest: *mut u8, dest_size: u32) -> i32 {
+    if data.len() < (dest_size as usize) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+        }
+        data.len() as i32
+    } else {
+        -2
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_handshake_path(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.handshake {
+        Some(info) => copy_to_dest(info.path.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_handshake_query(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.handshake {
+        Some(info) => copy_to_dest(info.query.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_handshake_headers(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.handshake {
+        Some(info) => copy_to_dest(info.headers.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_handshake_protocol(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match socket.handshake.as_ref().and_then(|info| info.protocol.as_ref()) {
+        Some(protocol) => copy_to_dest(protocol.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+// Source address of the last datagram received on a UDP socket,
+// or "stdout"/"stderr" for the last message from a process
+#[no_mangle]
+pub extern "C" fn pollnet_get_message_source(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    match &socket.message_source {
+        Some(source) => copy_to_dest(source.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+fn copy_addr_to_dest(addr: &Option<String>, dest: *mut u8, dest_size: u32) -> i32 {
+    match addr {
+        Some(addr) => copy_to_dest(addr.as_bytes(), dest, dest_size),
+        None => 0,
+    }
+}
+
+// Known once the handle is open and cleared while a client reconnects;
+// listeners and servers have no peer, and handles without a socket (timers,
+// processes, lookups) have neither
+#[no_mangle]
+pub extern "C" fn pollnet_get_local_addr(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    copy_addr_to_dest(&socket.local_addr, dest, dest_size)
+}
+
+// Through a proxy this is the proxy's address, not the final destination's
+#[no_mangle]
+pub extern "C" fn pollnet_get_peer_addr(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    let socket = match ctx.sockets.get(&handle) {
+        Some(socket) => socket,
+        None => return -1,
+    };
+
+    copy_addr_to_dest(&socket.peer_addr, dest, dest_size)
+}
+
+// -1 while the process runs, or if it was killed by a signal
+#[no_mangle]
+pub extern "C" fn pollnet_get_exit_code(ctx: *mut PollnetContext, handle: u32) -> i32 {
+    let ctx = unsafe{&mut *ctx};
+    match ctx.sockets.get(&handle) {
+        Some(socket) => socket.exit_code.unwrap_or(-1),
+        None => -1,
+    }
+}
+
+static mut HACKSTATICCONTEXT: *mut PollnetContext = std::ptr::null_mut();
+
+/// # Safety
+///
+/// Not thread safe: only call this from a single thread.
+#[no_mangle]
+pub unsafe extern "C" fn pollnet_get_or_init_static() -> *mut PollnetContext {
+    if HACKSTATICCONTEXT.is_null() {
+        warn!("INITIALIZING HACK STATIC CONTEXT");
+        HACKSTATICCONTEXT = Box::into_raw(Box::new(PollnetContext::new()))
+    }
+    HACKSTATICCONTEXT
+}
+
+
+// One line per address: name, interface index, family, address, netmask,
+// prefix length, broadcast address (may be empty) and comma separated flags
+// (up, loopback, link_local, p2p), all tab separated. Returns -1 if the
+// interfaces can't be listed and -2 if they don't fit in dest.
+#[no_mangle]
+pub extern "C" fn pollnet_get_interfaces(dest: *mut u8, dest_size: u32) -> i32 {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(err) => {
+            error!("Could not list network interfaces: {}", err);
+            return -1;
+        }
+    };
+    let mut listing = String::new();
+    for interface in interfaces {
+        let (family, netmask, prefix_len, broadcast) = match &interface.addr {
+            if_addrs::IfAddr::V4(addr) => ("ipv4", addr.netmask.to_string(), addr.prefixlen, addr.broadcast.map(|ip| ip.to_string())),
+            if_addrs::IfAddr::V6(addr) => ("ipv6", addr.netmask.to_string(), addr.prefixlen, addr.broadcast.map(|ip| ip.to_string())),
+        };
+        let flags: Vec<&str> = [
+            (interface.is_oper_up(), "up"),
+            (interface.is_loopback(), "loopback"),
+            (interface.is_link_local(), "link_local"),
+            (interface.is_p2p(), "p2p"),
+        ].iter().filter(|(set, _)| *set).map(|(_, flag)| *flag).collect();
+        listing.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            interface.name,
+            interface.index.unwrap_or(0),
+            family,
+            interface.ip(),
+            netmask,
+            prefix_len,
+            broadcast.unwrap_or_default(),
+            flags.join(",")));
+    }
+    copy_to_dest(listing.as_bytes(), dest, dest_size)
+}
+
+#[no_mangle]
+pub extern "C" fn pollnet_get_nanoid(dest: *mut u8, dest_size: u32) -> i32 {
+    let id = nanoid::nanoid!();
+    copy_to_dest(id.as_bytes(), dest, dest_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(uri: &str, headers: &[(&str, &str)]) -> tungstenite::handshake::server::Request {
+        let mut builder = tungstenite::http::Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn list(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn ws_policy_lets_everything_through_by_default() {
+        let policy = WsPolicy::default();
+        assert_eq!(policy.check(&upgrade_request("/anything?x=1", &[])), Ok(None));
+    }
+
+    #[test]
+    fn ws_policy_checks_paths_and_origins() {
+        let policy = WsPolicy{
+            paths: list(&["/ws"]),
+            origins: list(&["https://example.com"]),
+            ..WsPolicy::default()
+        };
+        let status = |uri, headers| policy.check(&upgrade_request(uri, headers)).map_err(|(status, _)| status);
+        assert_eq!(status("/ws", &[("Origin", "HTTPS://EXAMPLE.COM")]), Ok(None));
+        assert_eq!(status("/other", &[("Origin", "https://example.com")]), Err(http::StatusCode::NOT_FOUND));
+        assert_eq!(status("/ws", &[("Origin", "https://evil.com")]), Err(http::StatusCode::FORBIDDEN));
+        assert_eq!(status("/ws", &[]), Err(http::StatusCode::FORBIDDEN));
+
+        let anyone = WsPolicy{origins: list(&["*"]), ..WsPolicy::default()};
+        assert_eq!(anyone.check(&upgrade_request("/", &[("Origin", "https://evil.com")])), Ok(None));
+    }
+
+    #[test]
+    fn ws_policy_takes_the_token_from_a_header_or_the_query() {
+        let policy = WsPolicy{
+            token_name: "Authorization".to_string(),
+            token: "s3cret".to_string(),
+            ..WsPolicy::default()
+        };
+        let allowed = |uri, headers| policy.check(&upgrade_request(uri, headers)).is_ok();
+        assert!(allowed("/", &[("Authorization", "s3cret")]));
+        assert!(allowed("/", &[("Authorization", "Bearer s3cret")]));
+        assert!(allowed("/?Authorization=s3cret", &[]));
+        assert!(!allowed("/", &[("Authorization", "Bearer wrong")]));
+        assert!(!allowed("/?Authorization=wrong", &[]));
+        assert_eq!(policy.check(&upgrade_request("/", &[])).unwrap_err().0, http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn ws_policy_picks_the_subprotocol_in_our_order() {
+        let policy = WsPolicy{protocols: list(&["v2", "v1"]), ..WsPolicy::default()};
+        let check = |offered| policy.check(&upgrade_request("/", &[("Sec-WebSocket-Protocol", offered)]));
+        assert_eq!(check("v1, v2"), Ok(Some("v2".to_string())));
+        assert_eq!(check("v0,v1"), Ok(Some("v1".to_string())));
+        assert_eq!(check("v3").unwrap_err().0, http::StatusCode::BAD_REQUEST);
+    }
+
+    fn decode_all(framer: &mut Framer, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        chunks.iter().flat_map(|chunk| framer.decode(chunk).unwrap()).collect()
+    }
+
+    #[test]
+    fn newline_framing_drops_a_trailing_cr() {
+        let mut framer = Framer::new(Framing::Newline);
+        let frames = decode_all(&mut framer, &[b"one\r\ntw", b"o\n\nthr", b"ee\r", b"\nfour"]);
+        assert_eq!(frames, vec![b"one".to_vec(), b"two".to_vec(), b"".to_vec(), b"three".to_vec()]);
+        // the last line counts at the end of the stream even without its newline
+        assert_eq!(framer.finish(), Ok(Some(b"four".to_vec())));
+        assert_eq!(framer.encode(b"hi").unwrap(), b"hi\n");
+    }
+
+    #[test]
+    fn delimiter_framing_finds_delimiters_split_across_reads() {
+        let mut framer = Framer::new(Framing::Delimiter(b"||".to_vec()));
+        let frames = decode_all(&mut framer, &[b"a|", b"|b", b"c|", b"|", b"|||d"]);
+        assert_eq!(frames, vec![b"a".to_vec(), b"bc".to_vec(), b"".to_vec()]);
+        assert_eq!(framer.finish(), Ok(Some(b"|d".to_vec())));
+    }
+
+    #[test]
+    fn length_prefixed_framing_in_both_byte_orders() {
+        for (width, big_endian) in [(2, true), (2, false), (4, true), (4, false)] {
+            let mut framer = Framer::new(Framing::LengthPrefix{width, big_endian});
+            let mut stream = framer.encode(b"hello").unwrap();
+            stream.extend(framer.encode(b"").unwrap());
+            stream.extend(framer.encode(&[7; 300]).unwrap());
+            assert_eq!(stream.len(), 3 * width + 305);
+            let prefix = &stream[..width];
+            assert_eq!(if big_endian { prefix[width - 1] } else { prefix[0] }, 5);
+            // fed a byte at a time, nothing comes out early
+            let chunks: Vec<&[u8]> = stream.chunks(1).collect();
+            let frames = decode_all(&mut framer, &chunks);
+            assert_eq!(frames, vec![b"hello".to_vec(), Vec::new(), vec![7; 300]]);
+            // a cut off frame isn't delivered at the end of the stream
+            framer.decode(&stream[..width + 2]).unwrap();
+            assert!(framer.finish().is_err());
+            assert_eq!(framer.finish(), Ok(None));
+        }
+        let short = Framer::new(Framing::LengthPrefix{width: 2, big_endian: true});
+        assert!(short.encode(&vec![0; 70000]).is_err());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut framer = Framer::new(Framing::LengthPrefix{width: 4, big_endian: true});
+        assert!(framer.decode(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes()).is_err());
+        let mut framer = Framer::new(Framing::Netstring);
+        assert!(framer.decode(format!("{}:", MAX_FRAME_SIZE + 1).as_bytes()).is_err());
+        let mut framer = Framer::new(Framing::Newline);
+        assert!(framer.decode(&vec![b'x'; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn netstring_framing() {
+        let mut framer = Framer::new(Framing::Netstring);
+        assert_eq!(framer.encode(b"hello").unwrap(), b"5:hello,");
+        let frames = decode_all(&mut framer, &[b"5:hel", b"lo,0:,", b"3:abc"]);
+        assert_eq!(frames, vec![b"hello".to_vec(), Vec::new()]);
+        assert_eq!(framer.decode(b",").unwrap(), vec![b"abc".to_vec()]);
+        assert!(Framer::new(Framing::Netstring).decode(b"3:abcd").is_err());
+        assert!(Framer::new(Framing::Netstring).decode(b"x:").is_err());
+        assert!(Framer::new(Framing::Netstring).decode(b"12345678901").is_err());
+    }
+
+    fn ms(duration: std::time::Duration) -> u128 {
+        duration.as_millis()
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_the_cap() {
+        let policy = ReconnectPolicy{initial_delay_ms: 100, max_delay_ms: 1000, max_attempts: 0, jitter: 0.0};
+        let delays: Vec<u128> = (1..=6).map(|attempt| ms(policy.delay(attempt))).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        // attempt 0 is treated like the first one and huge attempts don't overflow
+        assert_eq!(ms(policy.delay(0)), 100);
+        assert_eq!(ms(policy.delay(u32::MAX)), 1000);
+    }
+
+    #[test]
+    fn reconnect_delay_never_drops_below_the_initial_delay_cap() {
+        // a max below the initial delay is raised to it
+        let policy = ReconnectPolicy{initial_delay_ms: 500, max_delay_ms: 100, max_attempts: 0, jitter: 0.0};
+        assert_eq!(ms(policy.delay(1)), 500);
+        assert_eq!(ms(policy.delay(4)), 500);
+    }
+
+    #[test]
+    fn reconnect_jitter_only_shortens_the_delay() {
+        let policy = ReconnectPolicy{initial_delay_ms: 1000, max_delay_ms: 1000, max_attempts: 0, jitter: 0.5};
+        for _ in 0..100 {
+            let delay = ms(policy.delay(1));
+            assert!((500..=1000).contains(&delay), "delay {}", delay);
+        }
+        // out of range jitter is clamped, so the delay can't go negative
+        let policy = ReconnectPolicy{jitter: 5.0, ..policy};
+        for _ in 0..100 {
+            assert!(ms(policy.delay(1)) <= 1000);
+        }
+    }
+
+    #[test]
+    fn reconnect_queue_drops_the_oldest_and_warns_once_per_outage() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut reconnector = Reconnector::new();
+        let fill = |reconnector: &mut Reconnector| {
+            for idx in 0..MAX_RECONNECT_QUEUE + 2 {
+                assert!(reconnector.queue(SocketMessage::Message(idx.to_string()), &tx, &mut |_| ()));
+            }
+        };
+        fill(&mut reconnector);
+        assert_eq!(reconnector.pending.len(), MAX_RECONNECT_QUEUE);
+        assert!(matches!(reconnector.pending.front(), Some(SocketMessage::Message(msg)) if msg == "2"));
+        let events: Vec<SocketMessage> = rx.try_iter().collect();
+        assert!(matches!(events.as_slice(), [SocketMessage::Warning(_)]));
+
+        assert_eq!(reconnector.connected(&tx).len(), MAX_RECONNECT_QUEUE);
+        fill(&mut reconnector);
+        let events: Vec<SocketMessage> = rx.try_iter().collect();
+        assert!(matches!(events.as_slice(), [SocketMessage::Connect, SocketMessage::Warning(_)]));
+    }
+
+    fn deflate_response(compression: WsCompression, offer: &str) -> Option<String> {
+        compression.accept(&deflate_offers(&[offer]))
+    }
+
+    #[test]
+    fn deflate_server_answers_the_first_usable_offer() {
+        let full = WsCompression{enabled: true, window_bits: 15};
+        assert_eq!(deflate_response(full, "x-webkit-deflate-frame, permessage-deflate; client_max_window_bits").as_deref(), Some("permessage-deflate"));
+        assert_eq!(deflate_response(full, "permessage-deflate; server_no_context_takeover; server_max_window_bits=10").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=10"));
+        // unknown parameters, duplicates and bad window sizes rule an offer out
+        assert_eq!(deflate_response(full, "permessage-deflate; x=1, permessage-deflate; client_max_window_bits=\"12\"").as_deref(),
+            Some("permessage-deflate; client_max_window_bits=12"));
+        assert_eq!(deflate_response(full, "permessage-deflate; server_max_window_bits=16"), None);
+        assert_eq!(deflate_response(full, "permessage-deflate; server_no_context_takeover; server_no_context_takeover"), None);
+        assert_eq!(deflate_response(full, "x-other"), None);
+
+        let small = WsCompression{enabled: true, window_bits: 10};
+        assert_eq!(deflate_response(small, "permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate; server_max_window_bits=10; client_max_window_bits=10"));
+        assert_eq!(deflate_response(small, "permessage-deflate; server_max_window_bits=9; client_max_window_bits=12").as_deref(),
+            Some("permessage-deflate; server_max_window_bits=9; client_max_window_bits=10"));
+        assert_eq!(small.offer(), "permessage-deflate; client_max_window_bits=10; server_max_window_bits=10");
+    }
+
+    #[test]
+    fn deflate_agreement_is_read_from_our_side() {
+        let params = &deflate_offers(&["permessage-deflate; client_no_context_takeover; server_max_window_bits=11"])[0];
+        assert_eq!(deflate_agreement(params, false), Ok(DeflateAgreement{window_bits: 15, no_context_takeover: true}));
+        assert_eq!(deflate_agreement(params, true), Ok(DeflateAgreement{window_bits: 11, no_context_takeover: false}));
+        let bad = &deflate_offers(&["permessage-deflate; server_max_window_bits=7"])[0];
+        assert!(deflate_agreement(bad, true).is_err());
+        let unknown = &deflate_offers(&["permessage-deflate; mystery"])[0];
+        assert!(deflate_agreement(unknown, false).is_err());
+    }
+
+    #[test]
+    fn deflate_round_trips_messages_with_and_without_context() {
+        for no_context_takeover in [false, true] {
+            let mut sender = Deflate::new(DeflateAgreement{window_bits: 9, no_context_takeover});
+            let mut receiver = Deflate::new(DeflateAgreement{window_bits: 15, no_context_takeover: false});
+            for round in 0..3 {
+                let message = format!("{{\"round\":{},\"state\":\"{}\"}}", round, "x".repeat(2000)).into_bytes();
+                let compressed = sender.deflate(&message, true).unwrap();
+                assert!(compressed.len() < message.len() / 10);
+                assert!(!compressed.ends_with(&DEFLATE_TAIL));
+                let mut inflated = Vec::new();
+                assert!(!receiver.inflate(&compressed, &mut inflated, usize::MAX).unwrap());
+                receiver.inflate(&DEFLATE_TAIL, &mut inflated, usize::MAX).unwrap();
+                assert_eq!(inflated, message);
+            }
+        }
+        // an 8 bit window can't be honoured, so those messages go out as they are
+        let tiny = Deflate::new(DeflateAgreement{window_bits: 8, no_context_takeover: false});
+        assert!(!tiny.compresses());
+    }
+
+    #[test]
+    fn inflating_stops_at_the_size_limit() {
+        let mut sender = Deflate::new(DeflateAgreement{window_bits: 15, no_context_takeover: false});
+        let mut receiver = Deflate::new(DeflateAgreement{window_bits: 15, no_context_takeover: false});
+        let compressed = sender.deflate(&vec![0u8; 1 << 20], true).unwrap();
+        let err = receiver.inflate(&compressed, &mut Vec::new(), 1000).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<MessageTooBig>());
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
-        });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+    fn client_frame(opcode_and_fin: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        FrameHeader{first: 0, mask: Some([9, 8, 7, 6]), len: 0, size: 0}.write_frame(opcode_and_fin, payload.to_vec(), &mut frame);
+        frame
+    }
 
-        new_handle
+    #[test]
+    fn frame_sizes_apply_new_limits_to_the_next_frame() {
+        let mut limits = WsLimits{max_message_size: 100, ..WsLimits::default()}.config();
+        let mut sizes = FrameSizes::new();
+        let head = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut data = head.to_vec();
+        data.extend(client_frame(0x82, &[1; 80]));
+        // the head ends partway, and the frame after it comes in bytes at a time
+        assert_eq!(sizes.scan(&data, &limits).unwrap(), Some(head.len()));
+        for byte in &data[head.len()..] {
+            assert_eq!(sizes.scan(&[*byte], &limits).unwrap(), None);
+        }
+
+        // fragments add up to the message size, pings in between don't count
+        let mut data = client_frame(0x02, &[1; 60]);
+        data.extend(client_frame(0x89, &[1; 60]));
+        sizes.scan(&data, &limits).unwrap();
+        let err = sizes.scan(&client_frame(0x80, &[1; 60]), &limits).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<MessageTooBig>());
+
+        let mut sizes = FrameSizes::new();
+        sizes.scan(b"GET / HTTP/1.1\r\n\r\n", &limits).unwrap();
+        limits = WsLimits{max_frame_size: 10, ..WsLimits::default()}.config();
+        sizes.scan(&client_frame(0x81, b"hello"), &limits).unwrap();
+        assert!(sizes.scan(&client_frame(0x81, b"hello world"), &limits).is_err());
     }
 
-    fn open_ws(&mut self, url: String) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+    #[test]
+    fn outgoing_fragments_share_one_compressed_stream() {
+        let agreement = || DeflateAgreement{window_bits: 15, no_context_takeover: false};
+        let mut frames = DeflateFrames::new(Deflate::new(agreement()), Vec::new());
+        let text = "fragmented ".repeat(100);
+        let (first, second) = text.split_at(500);
+        frames.pending_out.extend(client_frame(0x01, first.as_bytes()));
+        frames.pending_out.extend(client_frame(0x89, b"ping"));
+        frames.pending_out.extend(client_frame(0x80, second.as_bytes()));
+        frames.process_outgoing(true).unwrap();
 
-        self.rt_handle.spawn(async move {
-            info!("WS client spawned");
-            let real_url = url::Url::parse(&url);
-            if let Err(url_err) = real_url {
-                error!("Invalid URL: {}", url);
-                tx_from_sock.send(SocketMessage::Error(url_err.to_string())).unwrap_or_default();
-                return;
+        let mut receiver = Deflate::new(agreement());
+        let mut inflated = Vec::new();
+        let mut rest = &frames.out[..];
+        let mut headers = Vec::new();
+        while let Some(header) = FrameHeader::parse(rest) {
+            let mut payload = rest[header.size..header.size + header.len as usize].to_vec();
+            apply_mask(&mut payload, header.mask.unwrap());
+            match header.opcode() {
+                9 => assert_eq!(payload, b"ping"),
+                _ => {
+                    receiver.inflate(&payload, &mut inflated, usize::MAX).unwrap();
+                },
             }
+            headers.push((header.opcode(), header.rsv1(), header.fin()));
+            rest = &rest[header.size + header.len as usize..];
+        }
+        receiver.inflate(&DEFLATE_TAIL, &mut inflated, usize::MAX).unwrap();
+        assert_eq!(inflated, text.as_bytes());
+        // RSV1 only marks the first frame, and the ping goes through as it was
+        assert_eq!(headers, [(1, true, false), (9, false, true), (0, false, true)]);
+    }
 
-            info!("WS client attempting to connect to {}", url);
-            match connect_async(real_url.unwrap()).await {
-                Ok((mut ws_stream, _)) => {
-                    tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
-                    loop {
-                        tokio::select! {
-                            from_c_message = rx_to_sock.recv() => {
-                                match from_c_message {
-                                    Some(SocketMessage::Message(msg)) => {
-                                        ws_stream.send(tungstenite::protocol::Message::Text(msg)).await.expect("WS send error");
-                                    },
-                                    Some(SocketMessage::BinaryMessage(msg)) => {
-                                        ws_stream.send(tungstenite::protocol::Message::Binary(msg)).await.expect("WS send error");
-                                    },
-                                    _ => break
-                                }
-                            },
-                            from_sock_message = ws_stream.next() => {
-                                match from_sock_message {
-                                    Some(Ok(msg)) => {
-                                        tx_from_sock.send(SocketMessage::BinaryMessage(msg.into_data())).expect("TX error on socket message");
-                                    },
-                                    Some(Err(msg)) => {
-                                        tx_from_sock.send(SocketMessage::Error(msg.to_string())).expect("TX error on socket error");
-                                        break;
-                                    },
-                                    None => {
-                                        tx_from_sock.send(SocketMessage::Disconnect).expect("TX error on remote socket close");
-                                        break;
-                                    }
-                                }
-                            },
-                        };
-                    }
-                    info!("Closing websocket!");
-                    ws_stream.close(None).await.unwrap_or_default(); // if this errors we don't care
-                },
-                Err(err) => {
-                    error!("WS client connection error: {}", err);
-                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on connection error");
-                }
+    #[test]
+    fn frame_headers_round_trip() {
+        for (len, masked) in [(5, false), (125, true), (126, false), (70000, true)] {
+            let mask = if masked { Some([1, 2, 3, 4]) } else { None };
+            let header = FrameHeader{first: 0x82, mask, len: 0, size: 0};
+            let payload: Vec<u8> = (0..len).map(|idx| idx as u8).collect();
+            let mut frame = Vec::new();
+            header.write_frame(0xc1, payload.clone(), &mut frame);
+            let parsed = FrameHeader::parse(&frame).unwrap();
+            assert!(parsed.fin() && parsed.rsv1());
+            assert_eq!(parsed.opcode(), 1);
+            assert_eq!(parsed.len, len as u64);
+            assert_eq!(parsed.mask, mask);
+            let mut body = frame[parsed.size..].to_vec();
+            if let Some(mask) = parsed.mask {
+                apply_mask(&mut body, mask);
             }
-        });
+            assert_eq!(body, payload);
+            // a header cut short isn't parsed
+            assert!(FrameHeader::parse(&frame[..parsed.size - 1]).is_none());
+        }
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
-        });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+    #[test]
+    fn close_codes_that_cant_be_sent_fall_back_to_normal() {
+        assert_eq!(sendable_close_code(1000), 1000);
+        assert_eq!(sendable_close_code(1008), 1008);
+        assert_eq!(sendable_close_code(4000), 4000);
+        for code in [0, 999, 1004, 1005, 1006, 1015, 2999, 5000, 66536] {
+            assert_eq!(sendable_close_code(code), 1000, "code {}", code);
+        }
+    }
 
-        new_handle
+    #[test]
+    fn close_reasons_are_truncated_on_a_char_boundary() {
+        assert_eq!(truncate_close_reason("bye".to_string()), "bye");
+        let ascii = "a".repeat(200);
+        assert_eq!(truncate_close_reason(ascii).len(), MAX_CLOSE_REASON);
+        // 'é' is two bytes, so byte 123 falls in the middle of one
+        let accented = "é".repeat(100);
+        let truncated = truncate_close_reason(accented);
+        assert_eq!(truncated.len(), 122);
+        assert!(truncated.chars().all(|c| c == 'é'));
     }
 
-    fn open_tcp(&mut self, addr: String) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
 
-        self.rt_handle.spawn(async move {
-            info!("TCP client attempting to connect to {}", addr);
-            let mut buf = [0; 65536];
-            match TcpStream::connect(addr).await {
-                Ok(mut tcp_stream) => {
-                    tx_from_sock.send(SocketMessage::Connect).expect("oh boy");
-                    loop {
-                        tokio::select! {
-                            from_c_message = rx_to_sock.recv() => {
-                                match from_c_message {
-                                    Some(SocketMessage::Message(msg)) => {
-                                        tcp_stream.write_all(msg.as_bytes()).await.expect("TCP send error");
-                                    },
-                                    Some(SocketMessage::BinaryMessage(msg)) => {
-                                        tcp_stream.write_all(&msg).await.expect("TCP send error");
-                                    },
-                                    _ => break
-                                }
-                            },
-                            _ = tcp_stream.readable() => {
-                                match tcp_stream.try_read(&mut buf){
-                                    Ok(n) => {
-                                        // TODO: can we avoid these copies? Does it matter?
-                                        let submessage = buf[0..n].to_vec();
-                                        tx_from_sock.send(SocketMessage::BinaryMessage(submessage)).expect("TX error on socket message");
-                                    }
-                                    Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-                                        // no effect?
-                                    }
-                                    Err(err) => {
-                                        tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on socket error");
-                                        break;
-                                    }
-                                }
-                            },
-                        };
-                    }
-                    info!("Closing TCP socket!");
-                    tcp_stream.shutdown().await.unwrap_or_default(); // if this errors we don't care
-                },
-                Err(err) => {
-                    error!("TCP client connection error: {}", err);
-                    tx_from_sock.send(SocketMessage::Error(err.to_string())).expect("TX error on connection error");
-                }
-            }
-        });
+    fn limiter_with(limits: ListenerLimits) -> Arc<Mutex<ConnectionLimiter>> {
+        let limiter = ConnectionLimiter::new();
+        limiter.lock().unwrap().set_limits(limits);
+        limiter
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
-        });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+    #[test]
+    fn limiter_caps_total_clients() {
+        let limiter = limiter_with(ListenerLimits{max_clients: 2, ..ListenerLimits::default()});
+        let first = ConnectionLimiter::admit(&limiter, Some(ip(1)), true).unwrap();
+        let _second = ConnectionLimiter::admit(&limiter, Some(ip(2)), true).unwrap();
+        assert_eq!(ConnectionLimiter::admit(&limiter, Some(ip(3)), true).err().unwrap(), "too many clients");
+        drop(first);
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(3)), true).is_ok());
+    }
 
-        new_handle
+    #[test]
+    fn limiter_caps_clients_per_address() {
+        let limiter = limiter_with(ListenerLimits{max_per_ip: 1, ..ListenerLimits::default()});
+        let first = ConnectionLimiter::admit(&limiter, Some(ip(1)), true).unwrap();
+        assert_eq!(ConnectionLimiter::admit(&limiter, Some(ip(1)), true).err().unwrap(), "too many connections from this address");
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(2)), true).is_ok());
+        drop(first);
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(1)), true).is_ok());
+        assert!(limiter.lock().unwrap().active_per_ip.is_empty());
     }
 
-    async fn _handle_get(url: String, dest: std::sync::mpsc::Sender<SocketMessage>) {
-        info!("HTTP GET: {}", url);
-        let resp = match reqwest::get(&url).await {
-            Ok(resp) => resp,
-            Err(err) => {
-                error!("HTTP GET failed: {}", err);
-                dest.send(SocketMessage::Error(err.to_string())).expect("TX error on http post error");
-                return;
-            }
-        };
-        match resp.bytes().await {
-            Ok(body) => {
-                dest.send(SocketMessage::BinaryMessage(body.to_vec())).expect("TX error on http body");
-            },
-            Err(body_err) => {
-                dest.send(SocketMessage::Error(body_err.to_string())).expect("TX error on http body error");
-            }
-        };
+    #[test]
+    fn peers_without_an_address_only_count_towards_max_clients() {
+        let limiter = limiter_with(ListenerLimits{max_clients: 2, max_per_ip: 1, rate: 0.001, burst: 1});
+        let first = ConnectionLimiter::admit(&limiter, None, true).unwrap();
+        let _second = ConnectionLimiter::admit(&limiter, None, true).unwrap();
+        assert_eq!(ConnectionLimiter::admit(&limiter, None, true).err().unwrap(), "too many clients");
+        drop(first);
+        assert!(ConnectionLimiter::admit(&limiter, None, true).is_ok());
+        assert!(limiter.lock().unwrap().active_per_ip.is_empty());
     }
 
-    fn open_http_get_simple(&mut self, url: String) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+    #[test]
+    fn token_bucket_allows_a_burst_then_limits() {
+        let limiter = limiter_with(ListenerLimits{rate: 0.001, burst: 2, ..ListenerLimits::default()});
+        let mut inner = limiter.lock().unwrap();
+        assert!(inner.take_token(ip(1)));
+        assert!(inner.take_token(ip(1)));
+        assert!(!inner.take_token(ip(1)));
+        // buckets are per address
+        assert!(inner.take_token(ip(2)));
+    }
 
-        self.rt_handle.spawn(async move {
-            let get_handler = PollnetContext::_handle_get(url, tx_from_sock);
-            tokio::pin!(get_handler);
-            loop {
-                tokio::select! {
-                    _ = &mut get_handler => break,
-                    from_c_message = rx_to_sock.recv() => {
-                        match from_c_message {
-                            Some(SocketMessage::Disconnect) => break,
-                            _ => ()
-                        }
-                    },
-                }
-            }
-        });
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let limiter = limiter_with(ListenerLimits{rate: 1000.0, burst: 1, ..ListenerLimits::default()});
+        let mut inner = limiter.lock().unwrap();
+        assert!(inner.take_token(ip(1)));
+        inner.buckets.get_mut(&ip(1)).unwrap().last_refill -= std::time::Duration::from_millis(10);
+        assert!(inner.take_token(ip(1)));
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
+    #[test]
+    fn rate_limit_only_counts_requests() {
+        let limiter = limiter_with(ListenerLimits{rate: 0.001, burst: 1, ..ListenerLimits::default()});
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(1)), true).is_ok());
+        assert_eq!(ConnectionLimiter::admit(&limiter, Some(ip(1)), true).err().unwrap(), "rate limited");
+        // HTTP connections are admitted without spending tokens
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(1)), false).is_ok());
+        // new limits start with full buckets
+        limiter.lock().unwrap().set_limits(ListenerLimits{rate: 0.001, burst: 1, ..ListenerLimits::default()});
+        assert!(ConnectionLimiter::admit(&limiter, Some(ip(1)), true).is_ok());
+    }
+
+    #[test]
+    fn proxy_schemes_and_default_ports() {
+        let socks = ProxyServer::parse("socks5://proxy.lan").unwrap();
+        assert_eq!((socks.kind, socks.host.as_str(), socks.port), (ProxyKind::Socks5, "proxy.lan", 1080));
+        assert_eq!(ProxyServer::parse("socks5h://proxy.lan:9050").unwrap().port, 9050);
+        let http = ProxyServer::parse("http://proxy.lan").unwrap();
+        assert_eq!((http.kind, http.port), (ProxyKind::HttpConnect, 80));
+        // like curl, no scheme means HTTP
+        let bare = ProxyServer::parse("proxy.lan:3128").unwrap();
+        assert_eq!((bare.kind, bare.port), (ProxyKind::HttpConnect, 3128));
+        assert!(ProxyServer::parse("ftp://proxy.lan").is_err());
+        assert!(ProxyServer::parse("http://").is_err());
+    }
+
+    #[test]
+    fn proxy_ipv6_hosts_and_credentials() {
+        let server = ProxyServer::parse("socks5://me%40home:p%3Ass@[::1]:9050").unwrap();
+        assert_eq!((server.host.as_str(), server.port), ("::1", 9050));
+        assert_eq!(server.auth, Some(("me@home".to_string(), "p:ss".to_string())));
+        let server = ProxyServer::parse("http://user@proxy.lan").unwrap();
+        assert_eq!(server.auth, Some(("user".to_string(), String::new())));
+        assert_eq!(ProxyServer::parse("http://proxy.lan").unwrap().auth, None);
+        assert!(ProxyServer::parse("http://%ff:x@proxy.lan").is_err());
+    }
+
+    #[test]
+    fn proxy_from_env_honours_no_proxy_and_fails_closed() {
+        let mut vars = std::collections::HashMap::new();
+        vars.insert("no_proxy", " .internal,10.0.0.1 ,");
+        vars.insert("HTTPS_PROXY", "socks5://tls-proxy:1081");
+        vars.insert("HTTP_PROXY", "gopher://plain-proxy");
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        assert!(bypasses_proxy("internal", &env));
+        assert!(bypasses_proxy("db.Internal", &env));
+        assert!(bypasses_proxy("10.0.0.1", &env));
+        assert!(!bypasses_proxy("notinternal", &env));
+        assert!(!bypasses_proxy("example.com", &env));
+
+        let options = ProxyOptions{server: None, from_env: true};
+        assert_eq!(options.for_target_in("example.com", true, &env).unwrap().unwrap().host, "tls-proxy");
+        assert!(options.for_target_in("db.internal", true, &env).unwrap().is_none());
+        // a broken proxy setting must not turn into a direct connection
+        assert!(options.for_target_in("example.com", false, &env).is_err());
+        assert!(ProxyOptions{from_env: false, ..options.clone()}.for_target_in("example.com", false, &env).unwrap().is_none());
+        let explicit = ProxyOptions{server: Some(Err("Invalid proxy".to_string())), from_env: true};
+        assert!(explicit.for_target_in("db.internal", true, &env).is_err());
+
+        // blank values don't count, so ALL_PROXY is next in line
+        vars.insert("HTTP_PROXY", " ");
+        vars.insert("ALL_PROXY", "http://all-proxy:3128");
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        assert_eq!(options.for_target_in("example.com", false, &env).unwrap().unwrap().host, "all-proxy");
+
+        vars.insert("no_proxy", "*");
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        assert!(options.for_target_in("example.com", false, &env).unwrap().is_none());
+    }
+
+    #[test]
+    fn basic_auth_is_padded() {
+        assert_eq!(basic_auth("a", ""), "YTo=");
+        assert_eq!(basic_auth("ab", ""), "YWI6");
+        assert_eq!(basic_auth("Aladdin", "open sesame"), "QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+    }
+
+    fn run<F: std::future::Future>(future: F) -> F::Output {
+        runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    // A one-shot proxy on a local port; `serve` plays the proxy's side of the exchange
+    async fn fake_proxy<F, Fut>(kind: ProxyKind, auth: Option<(&str, &str)>, serve: F) -> ProxyOptions
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await;
         });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
+        let auth = auth.map(|(user, pass)| (user.to_string(), pass.to_string()));
+        ProxyOptions{server: Some(Ok(ProxyServer{kind, host: "127.0.0.1".to_string(), port, auth})), from_env: false}
+    }
 
-        new_handle
+    async fn read_tunnel(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        data
     }
 
-    async fn _handle_post(url: String, content_type: String, body: Vec<u8>, dest: std::sync::mpsc::Sender<SocketMessage>) {
-        info!("HTTP POST: {} (w/ {})", url, content_type);
-        let client = reqwest::Client::new();
-        let resp = match client.post(&url).header(reqwest::header::CONTENT_TYPE, content_type)
-            .body(body).send().await {
-            Ok(resp) => resp,
-            Err(err) => {
-                error!("HTTP POST failed: {}", err);
-                dest.send(SocketMessage::Error(err.to_string())).expect("TX error on http post error");
-                return;
-            }
-        };
-        match resp.bytes().await {
-            Ok(body) => {
-                dest.send(SocketMessage::BinaryMessage(body.to_vec())).expect("TX error on http body");
-            },
-            Err(body_err) => {
-                dest.send(SocketMessage::Error(body_err.to_string())).expect("TX error on http body error");
-            }
-        };
+    #[test]
+    fn socks5_connects_through_a_proxy() {
+        run(async {
+            let proxy = fake_proxy(ProxyKind::Socks5, Some(("user", "pass")), |mut stream| async move {
+                let mut greeting = [0u8; 4];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 2, 0, 2]);
+                stream.write_all(&[5, 2]).await.unwrap();
+                let mut login = [0u8; 11];
+                stream.read_exact(&mut login).await.unwrap();
+                assert_eq!(&login, b"\x01\x04user\x04pass");
+                stream.write_all(&[1, 0]).await.unwrap();
+                let mut request = [0u8; 18];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+                stream.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x1f, 0x90]).await.unwrap();
+                stream.write_all(b"tunnel").await.unwrap();
+            }).await;
+            let mut stream = open_stream("example.com", 443, &proxy, true).await.unwrap();
+            assert_eq!(read_tunnel(&mut stream).await, b"tunnel");
+        });
     }
 
-    fn open_http_post_simple(&mut self, url: String, content_type: String, body: Vec<u8>) -> u32 {
-        let (tx_to_sock, mut rx_to_sock) = tokio::sync::mpsc::channel(100);
-        let (tx_from_sock, rx_from_sock) = std::sync::mpsc::channel();
+    #[test]
+    fn socks5_reports_refusals() {
+        run(async {
+            let proxy = fake_proxy(ProxyKind::Socks5, None, |mut stream| async move {
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 1, 0]);
+                stream.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 10];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(request, [5, 1, 0, 1, 10, 1, 2, 3, 0, 80]);
+                stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            }).await;
+            let err = open_stream("10.1.2.3", 80, &proxy, false).await.unwrap_err();
+            assert!(err.ends_with("connection refused"), "{}", err);
+        });
+    }
 
-        self.rt_handle.spawn(async move {
-            let post_handler = PollnetContext::_handle_post(url, content_type, body, tx_from_sock);
-            tokio::pin!(post_handler);
-            loop {
-                tokio::select! {
-                    _ = &mut post_handler => break,
-                    from_c_message = rx_to_sock.recv() => {
-                        match from_c_message {
-                            Some(SocketMessage::Disconnect) => break,
-                            _ => ()
-                        }
-                    },
+    #[test]
+    fn http_connect_goes_through_a_proxy() {
+        run(async {
+            let proxy = fake_proxy(ProxyKind::HttpConnect, Some(("user", "pass")), |mut stream| async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
                 }
-            }
+                let request = String::from_utf8(request).unwrap();
+                assert!(request.starts_with("CONNECT [::1]:8080 HTTP/1.1\r\nHost: [::1]:8080\r\n"), "{}", request);
+                assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"), "{}", request);
+                // the tunnel data follows the headers in the same write
+                stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\ntunnel").await.unwrap();
+            }).await;
+            let mut stream = open_stream("::1", 8080, &proxy, false).await.unwrap();
+            assert_eq!(read_tunnel(&mut stream).await, b"tunnel");
         });
+    }
 
-        let socket = Box::new(PollnetSocket{
-            tx: tx_to_sock,
-            rx: rx_from_sock,
-            status: SocketStatus::OPENING,
-            message: None,
-            error: None,
-            last_client_handle: 0
+    #[test]
+    fn http_connect_reports_refusals() {
+        run(async {
+            let proxy = fake_proxy(ProxyKind::HttpConnect, None, |mut stream| async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+            }).await;
+            let err = open_stream("example.com", 80, &proxy, false).await.unwrap_err();
+            assert!(err.ends_with("407 Proxy Authentication Required"), "{}", err);
         });
-        let new_handle = self._next_handle();
-        self.sockets.insert(new_handle, socket);
-
-        new_handle
     }
 
-    fn close_all(&mut self) {
-        info!("Closing all sockets!");
-        for (_, sock) in self.sockets.iter_mut() {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    // don't care about errors at this point
-                    block_on(sock.tx.send(SocketMessage::Disconnect)).unwrap_or_default();
-                    sock.status = SocketStatus::CLOSED;
-                },
-                _ => (),
+    // Polls until the handle reports something other than NODATA
+    fn next_event(ctx: &mut PollnetContext, handle: u32) -> SocketResult {
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            match ctx.update(handle, false) {
+                SocketResult::NODATA if Instant::now() < deadline => thread::sleep(std::time::Duration::from_millis(2)),
+                SocketResult::NODATA => panic!("no event on handle {}", handle),
+                result => return result,
             }
         }
-        self.sockets.clear(); // everything should be closed and safely droppable
     }
 
-    fn close(&mut self, handle: u32) {
-        if let Some(sock) = self.sockets.get_mut(&handle) {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    match block_on(sock.tx.send(SocketMessage::Disconnect)) {
-                        _ => ()
-                    }
-                    sock.status = SocketStatus::CLOSED;
-                },
-                _ => (),
-            }
-            // Note: since we don't wait here for any kind of "disconnect" reply,
-            // a socket that has been closed should just return without sending a reply
-            self.sockets.remove(&handle);
-        }
+    fn expect_event(ctx: &mut PollnetContext, handle: u32, expected: SocketResult) -> Vec<u8> {
+        let result = next_event(ctx, handle);
+        assert_eq!(result, expected, "{:?}", ctx.sockets[&handle].error);
+        ctx.sockets[&handle].message.clone().unwrap_or_default()
     }
 
-    fn send(&mut self, handle: u32, msg: String) {
-        if let Some(sock) = self.sockets.get_mut(&handle) {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    sock.tx.try_send(SocketMessage::Message(msg)).unwrap_or_default()
-                },
-                _ => (),
-            };
-        }
+    fn local_addr(ctx: &PollnetContext, handle: u32) -> String {
+        ctx.sockets[&handle].local_addr.clone().unwrap()
     }
 
-    fn send_binary(&mut self, handle: u32, msg: Vec<u8>) {
-        if let Some(sock) = self.sockets.get_mut(&handle) {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    sock.tx.try_send(SocketMessage::BinaryMessage(msg)).unwrap_or_default()
-                },
-                _ => (),
-            };
+    // Settings travel to the runtime on a channel, so give them a moment to land
+    fn settle() {
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    fn wait_open(ctx: &mut PollnetContext, handle: u32) {
+        while !matches!(ctx.sockets[&handle].status, SocketStatus::OPEN) {
+            let result = next_event(ctx, handle);
+            assert_eq!(result, SocketResult::OPENING, "{:?}", ctx.sockets[&handle].error);
         }
     }
 
-    fn add_virtual_file(&mut self, handle: u32, filename: String, filedata: Vec<u8>) {
-        if let Some(sock) = self.sockets.get_mut(&handle) {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    sock.tx.try_send(SocketMessage::FileAdd(filename, filedata)).unwrap_or_default()
-                },
-                _ => (),
-            };
+    fn accept_client(ctx: &mut PollnetContext, server: u32) -> u32 {
+        expect_event(ctx, server, SocketResult::NEWCLIENT);
+        ctx.sockets[&server].last_client_handle
+    }
+
+    // A WS listener on a free local port and a client connected to it
+    fn ws_pair(ctx: &mut PollnetContext, path: &str, headers: &str, protocols: &str, policy: WsPolicy) -> (u32, u32, u32) {
+        let server = ctx.listen_ws("127.0.0.1:0".to_string());
+        expect_event(ctx, server, SocketResult::OPENING);
+        ctx.set_ws_policy(server, policy);
+        settle();
+        let url = format!("ws://{}{}", local_addr(ctx, server), path);
+        let client = ctx.open_ws(url, headers.to_string(), protocols.to_string());
+        let accepted = accept_client(ctx, server);
+        // accepted handles start out open, but still report it
+        assert_eq!(next_event(ctx, accepted), SocketResult::OPENING);
+        wait_open(ctx, client);
+        (server, client, accepted)
+    }
+
+    #[test]
+    fn tcp_clients_only_reconnect_after_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ctx = PollnetContext::new();
+        let client = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        ctx.set_reconnect(client, ReconnectPolicy{initial_delay_ms: 10, max_delay_ms: 10, max_attempts: 0, jitter: 0.0});
+        let (peer, _) = listener.accept().unwrap();
+        wait_open(&mut ctx, client);
+
+        // a reset is an error, so the client comes back
+        socket2::SockRef::from(&peer).set_linger(Some(std::time::Duration::from_secs(0))).unwrap();
+        drop(peer);
+        assert_eq!(next_event(&mut ctx, client), SocketResult::RECONNECTING);
+        let (peer, _) = listener.accept().unwrap();
+        assert_eq!(next_event(&mut ctx, client), SocketResult::RECONNECTED);
+
+        // a FIN is the peer hanging up on purpose
+        drop(peer);
+        assert_eq!(next_event(&mut ctx, client), SocketResult::EOF);
+        listener.set_nonblocking(true).unwrap();
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn tcp_framers_hand_over_what_they_hold_at_eof() {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ctx = PollnetContext::new();
+        let lines = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        ctx.set_framing(lines, Framing::Newline);
+        let (mut peer, _) = listener.accept().unwrap();
+        wait_open(&mut ctx, lines);
+        settle();
+        peer.write_all(b"whole\nhalf").unwrap();
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(expect_event(&mut ctx, lines, SocketResult::HASDATA), b"whole");
+        assert_eq!(expect_event(&mut ctx, lines, SocketResult::HASDATA), b"half");
+        assert_eq!(next_event(&mut ctx, lines), SocketResult::EOF);
+
+        // a length prefixed frame cut short is an error rather than a message
+        let prefixed = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        ctx.set_framing(prefixed, Framing::LengthPrefix{width: 2, big_endian: true});
+        let (mut peer, _) = listener.accept().unwrap();
+        wait_open(&mut ctx, prefixed);
+        settle();
+        peer.write_all(&[0, 5, b'h', b'e']).unwrap();
+        drop(peer);
+        expect_event(&mut ctx, prefixed, SocketResult::ERROR);
+
+        // and the same for clients accepted by a listener
+        let server = ctx.listen_tcp("127.0.0.1:0".to_string(), None);
+        expect_event(&mut ctx, server, SocketResult::OPENING);
+        ctx.set_framing(server, Framing::Newline);
+        settle();
+        let mut peer = std::net::TcpStream::connect(local_addr(&ctx, server)).unwrap();
+        let accepted = accept_client(&mut ctx, server);
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
+        peer.write_all(b"no newline").unwrap();
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), b"no newline");
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::EOF);
+        ctx.shutdown();
+    }
+
+    // The descriptor of the socket behind a handle, found by its addresses
+    #[cfg(unix)]
+    fn handle_fd(ctx: &PollnetContext, handle: u32) -> std::os::unix::io::RawFd {
+        let socket = &ctx.sockets[&handle];
+        let addresses = (socket.local_addr.clone(), socket.peer_addr.clone());
+        (0..1024).find(|fd| {
+            let fd = unsafe{std::os::unix::io::BorrowedFd::borrow_raw(*fd)};
+            let socket = socket2::SockRef::from(&fd);
+            let addr = |addr: std::io::Result<socket2::SockAddr>| addr.ok().and_then(|addr| addr.as_socket()).map(|addr| addr.to_string());
+            (addr(socket.local_addr()), addr(socket.peer_addr())) == addresses
+        }).expect("no socket for the handle")
+    }
+
+    #[test]
+    fn tcp_options_are_set_on_the_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = TcpOptions{nodelay: true, keepalive_ms: 7000, recv_buffer_size: 1 << 16, linger_secs: 3, ..TcpOptions::default()};
+        run(async {
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            options.apply(&stream).unwrap();
+            let socket = socket2::SockRef::from(&stream);
+            assert!(socket.tcp_nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            #[cfg(not(windows))]
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), std::time::Duration::from_secs(7));
+            // the OS may round the buffer up, but not down
+            assert!(socket.recv_buffer_size().unwrap() >= 1 << 16);
+            assert_eq!(socket.linger().unwrap(), Some(std::time::Duration::from_secs(3)));
+        });
+
+        // and through a handle, both when it opens and later on
+        let mut ctx = PollnetContext::new();
+        ctx.tcp_options = TcpOptions{nodelay: true, ..TcpOptions::default()};
+        let client = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        let (_peer, _) = listener.accept().unwrap();
+        wait_open(&mut ctx, client);
+        ctx.set_tcp_options(client, TcpOptions{linger_secs: 3, ..TcpOptions::default()});
+        settle();
+        #[cfg(unix)]
+        {
+            let fd = handle_fd(&ctx, client);
+            let fd = unsafe{std::os::unix::io::BorrowedFd::borrow_raw(fd)};
+            let socket = socket2::SockRef::from(&fd);
+            assert!(socket.tcp_nodelay().unwrap());
+            assert_eq!(socket.linger().unwrap(), Some(std::time::Duration::from_secs(3)));
         }
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn tcp_half_close_keeps_the_read_side_open() {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ctx = PollnetContext::new();
+        let client = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        wait_open(&mut ctx, client);
+
+        ctx.send(client, "request".to_string());
+        ctx.shutdown_write(client);
+        let mut request = Vec::new();
+        peer.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+
+        // the peer can still answer, and its close then ends the handle
+        peer.write_all(b"response").unwrap();
+        assert_eq!(expect_event(&mut ctx, client, SocketResult::HASDATA), b"response");
+        drop(peer);
+        assert_eq!(next_event(&mut ctx, client), SocketResult::CLOSED);
+
+        // and the other way round, the handle can still send after an EOF
+        let client = ctx.open_tcp(listener.local_addr().unwrap().to_string(), None);
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        wait_open(&mut ctx, client);
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(next_event(&mut ctx, client), SocketResult::EOF);
+        ctx.send(client, "still here".to_string());
+        let mut answer = [0u8; 10];
+        peer.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"still here");
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn udp_handles_exchange_datagrams_and_report_their_source() {
+        let mut ctx = PollnetContext::new();
+        let receiver = ctx.open_udp("127.0.0.1:0".to_string(), None);
+        wait_open(&mut ctx, receiver);
+        let sender = ctx.open_udp("127.0.0.1:0".to_string(), Some(local_addr(&ctx, receiver)));
+        wait_open(&mut ctx, sender);
+        assert_eq!(ctx.sockets[&sender].peer_addr, ctx.sockets[&receiver].local_addr);
+
+        // each send is one datagram, even back to back
+        ctx.send(sender, "first".to_string());
+        ctx.send_binary(sender, vec![2; 1000]);
+        assert_eq!(expect_event(&mut ctx, receiver, SocketResult::HASDATA), b"first");
+        assert_eq!(ctx.sockets[&receiver].message_source, ctx.sockets[&sender].local_addr);
+        assert_eq!(expect_event(&mut ctx, receiver, SocketResult::HASDATA), vec![2; 1000]);
+
+        // an unconnected socket answers whoever it heard from
+        let source = ctx.sockets[&receiver].message_source.clone().unwrap();
+        ctx.send_to(receiver, source, b"reply".to_vec());
+        assert_eq!(expect_event(&mut ctx, sender, SocketResult::HASDATA), b"reply");
+        ctx.shutdown();
     }
 
-    fn remove_virtual_file(&mut self, handle: u32, filename: String) {
-        if let Some(sock) = self.sockets.get_mut(&handle) {
-            match sock.status {
-                SocketStatus::OPEN | SocketStatus::OPENING => {
-                    sock.tx.try_send(SocketMessage::FileRemove(filename)).unwrap_or_default()
-                },
-                _ => (),
-            };
-        }
+    #[test]
+    fn udp_multicast_members_get_group_datagrams_and_bad_groups_warn() {
+        let group = "239.255.77.1";
+        let mut ctx = PollnetContext::new();
+        let receiver = ctx.open_udp("0.0.0.0:0".to_string(), None);
+        wait_open(&mut ctx, receiver);
+        let port = local_addr(&ctx, receiver).rsplit(':').next().unwrap().to_string();
+        ctx.join_multicast(receiver, group.to_string(), String::new());
+        settle();
+        let sender = ctx.open_udp("0.0.0.0:0".to_string(), None);
+        wait_open(&mut ctx, sender);
+
+        ctx.send_to(sender, format!("{}:{}", group, port), b"to the group".to_vec());
+        assert_eq!(expect_event(&mut ctx, receiver, SocketResult::HASDATA), b"to the group");
+
+        // after leaving, only what's sent straight to the socket arrives
+        ctx.leave_multicast(receiver, group.to_string(), String::new());
+        settle();
+        ctx.send_to(sender, format!("{}:{}", group, port), b"missed".to_vec());
+        ctx.send_to(sender, format!("127.0.0.1:{}", port), b"direct".to_vec());
+        assert_eq!(expect_event(&mut ctx, receiver, SocketResult::HASDATA), b"direct");
+
+        // a failed join doesn't cost the socket, it just warns
+        ctx.join_multicast(receiver, "not a group".to_string(), String::new());
+        let warning = expect_event(&mut ctx, receiver, SocketResult::WARNING);
+        assert!(String::from_utf8_lossy(&warning).contains("Invalid multicast group"));
+        ctx.join_multicast(receiver, "10.1.2.3".to_string(), String::new());
+        expect_event(&mut ctx, receiver, SocketResult::WARNING);
+        ctx.send_to(sender, format!("127.0.0.1:{}", port), b"still open".to_vec());
+        assert_eq!(expect_event(&mut ctx, receiver, SocketResult::HASDATA), b"still open");
+        ctx.shutdown();
     }
 
-    fn update(&mut self, handle: u32, blocking: bool) -> SocketResult {
-        let sock = match self.sockets.get_mut(&handle) {
-            Some(sock) => sock,
-            None => return SocketResult::INVALIDHANDLE,
-        };
+    #[cfg(unix)]
+    #[test]
+    fn unix_peers_must_run_as_the_same_user() {
+        assert_eq!(check_peer_uid(1000, 1000), Ok(()));
+        assert_eq!(check_peer_uid(0, 1000), Err("peer uid 0 does not match uid 1000".to_string()));
+    }
 
-        match sock.status {
-            SocketStatus::OPEN | SocketStatus::OPENING => {
-                // This block is apparently impossible to move into a helper function
-                // for borrow checker "reasons"
-                let result = if blocking {
-                    sock.rx.recv().map_err(|_err| RecvError::Disconnected)
-                } else {
-                    sock.rx.try_recv().map_err(|err| match err {
-                        std::sync::mpsc::TryRecvError::Empty => RecvError::Empty,
-                        std::sync::mpsc::TryRecvError::Disconnected => RecvError::Disconnected,
-                    })
-                };
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_unix_sockets_accept_peers_of_the_same_user() {
+        let mut ctx = PollnetContext::new();
+        let path = format!("@pollnet-test-{}", std::process::id());
+        let server = ctx.listen_unix(path.clone());
+        expect_event(&mut ctx, server, SocketResult::OPENING);
 
-                match result {
-                    Ok(SocketMessage::Connect) => {
-                        sock.status = SocketStatus::OPEN;
-                        SocketResult::OPENING
-                    },
-                    Ok(SocketMessage::Disconnect) | Err(RecvError::Disconnected) => {
-                        sock.status = SocketStatus::CLOSED;
-                        SocketResult::CLOSED
-                    },
-                    Ok(SocketMessage::Message(msg)) => {
-                        sock.message = Some(msg.into_bytes());
-                        SocketResult::HASDATA
-                    },
-                    Ok(SocketMessage::BinaryMessage(msg)) => {
-                        sock.message = Some(msg);
-                        SocketResult::HASDATA
-                    },
-                    Ok(SocketMessage::Error(err)) => {
-                        sock.error = Some(err);
-                        sock.status = SocketStatus::ERROR;
-                        SocketResult::ERROR
-                    },
-                    Ok(SocketMessage::NewClient(conn)) => {
-                        // can't use self._next_handle() either for questionable reasons
-                        let new_handle = PollnetContext::_next_handle_that_satisfies_the_borrow_checker(&mut self.next_handle);
-                        sock.last_client_handle = new_handle;
-                        sock.message = Some(conn.id.into_bytes());
-                        let client_socket = Box::new(PollnetSocket{
-                            tx: conn.tx,
-                            rx: conn.rx,
-                            status: SocketStatus::OPEN, // assume client sockets start open?
-                            message: None,
-                            error: None,
-                            last_client_handle: 0,
-                        });
-                        self.sockets.insert(new_handle, client_socket);
-                        SocketResult::NEWCLIENT
-                    },
-                    Ok(_) => SocketResult::NODATA,
-                    Err(RecvError::Empty) => SocketResult::NODATA,
-                }
-            },
-            SocketStatus::CLOSED => SocketResult::CLOSED,
-            _ => SocketResult::ERROR
-        }
+        let client = ctx.open_unix(path);
+        wait_open(&mut ctx, client);
+        let accepted = accept_client(&mut ctx, server);
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
+        ctx.send(client, "hello".to_string());
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), b"hello");
+        ctx.shutdown();
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn processes_frame_stdin_and_report_their_exit() {
+        let mut ctx = PollnetContext::new();
+        let cat = ctx.spawn_process("cat".to_string(), Vec::new(), None);
+        wait_open(&mut ctx, cat);
+        ctx.set_framing(cat, Framing::Newline);
+        settle();
+        // each send gets its own newline, so cat echoes two lines back
+        ctx.send(cat, "one".to_string());
+        ctx.send(cat, "two".to_string());
+        assert_eq!(expect_event(&mut ctx, cat, SocketResult::HASDATA), b"one");
+        assert_eq!(ctx.sockets[&cat].message_source.as_deref(), Some("stdout"));
+        assert_eq!(expect_event(&mut ctx, cat, SocketResult::HASDATA), b"two");
+        ctx.shutdown_write(cat);
+        expect_event(&mut ctx, cat, SocketResult::CLOSED);
+        assert_eq!(ctx.sockets[&cat].exit_code, Some(0));
 
-    fn shutdown(&mut self) {
-        info!("Starting shutdown");
-        self.close_all();
-        info!("All sockets should be closed?");
-        if let Some(tx) = self.shutdown_tx.take() {
-            tx.send(0).unwrap_or_default();
-        }
-        if let Some(handle) = self.thread.take() {
-            handle.join().unwrap_or_default();
-        }
-        info!("Thread should be joined?");
+        let failing = ctx.spawn_process("sh".to_string(), list(&["-c", "echo oops >&2; exit 3"]), None);
+        wait_open(&mut ctx, failing);
+        assert_eq!(expect_event(&mut ctx, failing, SocketResult::HASDATA), b"oops\n");
+        assert_eq!(ctx.sockets[&failing].message_source.as_deref(), Some("stderr"));
+        expect_event(&mut ctx, failing, SocketResult::CLOSED);
+        assert_eq!(ctx.sockets[&failing].exit_code, Some(3));
+        ctx.shutdown();
     }
-}
 
-fn c_str_to_string(s: *const c_char) -> String {
-    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
-}
+    #[test]
+    fn timers_fire_once_or_pile_up_ticks_between_polls() {
+        let mut ctx = PollnetContext::new();
+        let once = ctx.timer(10, false);
+        wait_open(&mut ctx, once);
+        assert_eq!(expect_event(&mut ctx, once, SocketResult::HASDATA), b"1");
+        assert_eq!(next_event(&mut ctx, once), SocketResult::CLOSED);
 
-fn c_data_to_vec(data: *const u8, datasize: u32) -> Vec<u8> {
-    unsafe { std::slice::from_raw_parts(data, datasize as usize).to_vec() }
-}
+        let repeating = ctx.timer(10, true);
+        wait_open(&mut ctx, repeating);
+        thread::sleep(std::time::Duration::from_millis(100));
+        let ticks: u64 = String::from_utf8(expect_event(&mut ctx, repeating, SocketResult::HASDATA)).unwrap().parse().unwrap();
+        assert!(ticks > 1, "only {} ticks collected", ticks);
+        ctx.close(repeating);
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_init() -> *mut PollnetContext {
-    Box::into_raw(Box::new(PollnetContext::new()))
-}
+    #[test]
+    fn invalid_dns_servers_and_record_types_are_rejected() {
+        let mut ctx = PollnetContext::new();
+        let set_server = |ctx: &mut PollnetContext, addr: &str| {
+            let addr = std::ffi::CString::new(addr).unwrap();
+            pollnet_set_dns_server(ctx, addr.as_ptr())
+        };
+        assert_eq!(set_server(&mut ctx, "127.0.0.1"), 1);
+        assert_eq!(ctx.dns_server, Some("127.0.0.1:53".parse().unwrap()));
+        // a bad address leaves the configured server alone
+        assert_eq!(set_server(&mut ctx, "not a server"), 0);
+        assert_eq!(set_server(&mut ctx, "127.0.0.1:99999"), 0);
+        assert_eq!(ctx.dns_server, Some("127.0.0.1:53".parse().unwrap()));
+        assert_eq!(set_server(&mut ctx, "[::1]:5353"), 1);
+        assert_eq!(ctx.dns_server, Some("[::1]:5353".parse().unwrap()));
+        assert_eq!(set_server(&mut ctx, ""), 1);
+        assert_eq!(ctx.dns_server, None);
 
-#[no_mangle]
-pub extern fn pollnet_shutdown(ctx: *mut PollnetContext) {
-    info!("Requested ctx close!");
-    let ctx = unsafe{&mut *ctx};
-    ctx.shutdown();
+        let lookup = ctx.resolve("localhost".to_string(), "BOGUS".to_string());
+        assert_eq!(next_event(&mut ctx, lookup), SocketResult::ERROR);
+        assert_eq!(ctx.sockets[&lookup].error.as_deref(), Some("Unknown record type BOGUS"));
+        ctx.shutdown();
+    }
 
-    // take ownership and drop
-    let b = unsafe{ Box::from_raw(ctx) };
-    drop(b);
-    info!("Everything should be dead now!");
-}
+    #[cfg(unix)]
+    #[test]
+    fn getters_answer_minus_two_when_dest_is_too_small() {
+        let mut ctx = PollnetContext::new();
+        let echo = ctx.spawn_process("sh".to_string(), list(&["-c", "printf hello"]), None);
+        wait_open(&mut ctx, echo);
+        assert_eq!(next_event(&mut ctx, echo), SocketResult::HASDATA);
+        let mut small = [0u8; 4];
+        assert_eq!(pollnet_get_message_source(&mut ctx, echo, small.as_mut_ptr(), small.len() as u32), -2);
+        assert_eq!(pollnet_get_nanoid(small.as_mut_ptr(), small.len() as u32), -2);
+        // the message that didn't fit is still there for a bigger buffer
+        assert_eq!(pollnet_get(&mut ctx, echo, small.as_mut_ptr(), small.len() as u32), -2);
+        let mut dest = [0u8; 64];
+        assert_eq!(pollnet_get(&mut ctx, echo, dest.as_mut_ptr(), dest.len() as u32), 5);
+        assert_eq!(&dest[..5], b"hello");
+        assert_eq!(pollnet_get(&mut ctx, echo, dest.as_mut_ptr(), dest.len() as u32), 0);
 
-#[no_mangle]
-pub extern fn pollnet_open_ws(ctx: *mut PollnetContext, url: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let url = c_str_to_string(url);
-    ctx.open_ws(url)
-}
+        let missing = ctx.spawn_process("pollnet-no-such-program".to_string(), Vec::new(), None);
+        assert_eq!(next_event(&mut ctx, missing), SocketResult::ERROR);
+        assert_eq!(pollnet_get_error(&mut ctx, missing, small.as_mut_ptr(), small.len() as u32), -2);
+        assert!(pollnet_get_error(&mut ctx, missing, dest.as_mut_ptr(), dest.len() as u32) > 0);
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_listen_ws(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    ctx.listen_ws(addr)
-}
+    #[test]
+    fn interface_listings_that_dont_fit_answer_minus_two() {
+        let mut small = [0u8; 8];
+        assert_eq!(pollnet_get_interfaces(small.as_mut_ptr(), small.len() as u32), -2);
+        let mut dest = vec![0u8; 65536];
+        let size = pollnet_get_interfaces(dest.as_mut_ptr(), dest.len() as u32);
+        assert!(size > 0);
+        let listing = String::from_utf8(dest[..size as usize].to_vec()).unwrap();
+        let loopback = listing.lines().find(|line| line.contains("\t127.0.0.1\t")).unwrap();
+        let fields: Vec<&str> = loopback.split('\t').collect();
+        assert_eq!(fields.len(), 8);
+        assert_eq!(fields[2], "ipv4");
+        assert!(fields[7].split(',').any(|flag| flag == "loopback"));
+    }
 
-#[no_mangle]
-pub extern fn pollnet_open_tcp(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    ctx.open_tcp(addr)
-}
+    #[test]
+    fn ws_request_takes_header_lines_and_a_protocol_list() {
+        let request = build_ws_request("ws://localhost/", "X-Player: 7\n\n  X-Team:red  \nX-Player: 8", " chat, ,v2 ").unwrap();
+        let values = |name| request.headers().get_all(name).iter().map(|value| value.to_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(values("x-player"), list(&["7", "8"]));
+        assert_eq!(values("x-team"), list(&["red"]));
+        assert_eq!(values("sec-websocket-protocol"), list(&["chat, v2"]));
+        assert!(build_ws_request("ws://localhost/", "no colon here", "").is_err());
+        assert!(build_ws_request("ws://localhost/", "Bad Name: x", "").is_err());
+    }
 
-#[no_mangle]
-pub extern fn pollnet_listen_tcp(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    ctx.listen_tcp(addr)
-}
+    #[test]
+    fn ws_clients_send_custom_headers_and_agree_on_a_subprotocol() {
+        let mut ctx = PollnetContext::new();
+        let policy = WsPolicy{protocols: list(&["v2", "chat"]), ..WsPolicy::default()};
+        let (_server, client, accepted) = ws_pair(&mut ctx, "/", "X-Player: 7", "chat, v2", policy);
+        let request = ctx.sockets[&accepted].handshake.as_ref().unwrap();
+        assert!(request.headers.contains("x-player: 7\n"), "{}", request.headers);
+        assert_eq!(request.protocol.as_deref(), Some("v2"));
+        let response = ctx.sockets[&client].handshake.as_ref().unwrap();
+        assert_eq!(response.protocol.as_deref(), Some("v2"));
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_simple_http_get(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    ctx.open_http_get_simple(addr)
-}
+    #[test]
+    fn ws_messages_keep_their_type_and_closes_carry_code_and_reason() {
+        let mut ctx = PollnetContext::new();
+        let (_server, client, accepted) = ws_pair(&mut ctx, "/", "", "", WsPolicy::default());
+        ctx.send(client, "hello".to_string());
+        ctx.send_binary(client, vec![0, 159, 146, 150]);
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), b"hello");
+        assert!(matches!(ctx.sockets[&accepted].message_type, MessageType::TEXT));
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), [0, 159, 146, 150]);
+        assert!(matches!(ctx.sockets[&accepted].message_type, MessageType::BINARY));
 
-#[no_mangle]
-pub extern fn pollnet_simple_http_post(ctx: *mut PollnetContext, addr: *const c_char, content_type: *const c_char, bodydata: *const u8, bodysize: u32) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    let content_type = c_str_to_string(content_type);
-    let body = c_data_to_vec(bodydata, bodysize);
-    ctx.open_http_post_simple(addr, content_type, body)
-}
+        ctx.close_with_reason(accepted, 4001, "round over".to_string());
+        assert_eq!(expect_event(&mut ctx, client, SocketResult::CLOSED), b"round over");
+        let closed = &ctx.sockets[&client];
+        assert!(matches!(closed.message_type, MessageType::CLOSE));
+        assert_eq!((closed.close_code, closed.close_reason.as_deref()), (4001, Some("round over")));
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_serve_static_http(ctx: *mut PollnetContext, addr: *const c_char, serve_dir: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    let serve_dir = c_str_to_string(serve_dir);
-    ctx.serve_http(addr, Some(serve_dir))
-}
+    #[test]
+    fn ws_servers_see_the_path_query_and_headers_of_each_handshake() {
+        let mut ctx = PollnetContext::new();
+        let (_server, _client, accepted) = ws_pair(&mut ctx, "/rooms/7?token=abc&team=red", "Origin: https://game.example", "", WsPolicy::default());
+        let request = ctx.sockets[&accepted].handshake.as_ref().unwrap();
+        assert_eq!((request.path.as_str(), request.query.as_str()), ("/rooms/7", "token=abc&team=red"));
+        assert!(request.headers.contains("origin: https://game.example\n"), "{}", request.headers);
+        assert_eq!(request.protocol, None);
 
-#[no_mangle]
-pub extern fn pollnet_serve_http(ctx: *mut PollnetContext, addr: *const c_char) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    let addr = c_str_to_string(addr);
-    ctx.serve_http(addr, None)
-}
+        // and the same through the C getters
+        let mut dest = [0u8; 64];
+        let len = pollnet_get_handshake_path(&mut ctx, accepted, dest.as_mut_ptr(), dest.len() as u32);
+        assert_eq!(&dest[..len as usize], b"/rooms/7");
+        let len = pollnet_get_handshake_query(&mut ctx, accepted, dest.as_mut_ptr(), dest.len() as u32);
+        assert_eq!(&dest[..len as usize], b"token=abc&team=red");
+        assert_eq!(pollnet_get_handshake_protocol(&mut ctx, accepted, dest.as_mut_ptr(), dest.len() as u32), 0);
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_close(ctx: *mut PollnetContext, handle: u32) {
-    let ctx = unsafe{&mut *ctx};
-    ctx.close(handle)
-}
+    #[test]
+    fn ws_broadcasts_reach_the_whole_server_or_one_group() {
+        let mut ctx = PollnetContext::new();
+        let (server, first, first_accepted) = ws_pair(&mut ctx, "/", "", "", WsPolicy::default());
+        let mut clients = vec![(first, first_accepted)];
+        for _ in 0..2 {
+            let client = ctx.open_ws(format!("ws://{}/", local_addr(&ctx, server)), String::new(), String::new());
+            let accepted = accept_client(&mut ctx, server);
+            assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
+            wait_open(&mut ctx, client);
+            clients.push((client, accepted));
+        }
+        ctx.join_group(clients[0].1, "red".to_string());
+        ctx.join_group(clients[1].1, "red".to_string());
 
-#[no_mangle]
-pub extern fn pollnet_close_all(ctx: *mut PollnetContext) {
-    let ctx = unsafe{&mut *ctx};
-    ctx.close_all()
-}
+        ctx.broadcast(server, Some("red".to_string()), SocketMessage::Message("red only".to_string()));
+        ctx.broadcast(server, None, SocketMessage::BinaryMessage(b"everyone".to_vec()));
+        assert_eq!(expect_event(&mut ctx, clients[0].0, SocketResult::HASDATA), b"red only");
+        assert_eq!(expect_event(&mut ctx, clients[1].0, SocketResult::HASDATA), b"red only");
+        for (client, _) in &clients {
+            assert_eq!(expect_event(&mut ctx, *client, SocketResult::HASDATA), b"everyone");
+        }
 
-#[no_mangle]
-pub extern fn pollnet_status(ctx: *mut PollnetContext, handle: u32) -> SocketStatus {
-    let ctx = unsafe{&*ctx};
-    if let Some(socket) = ctx.sockets.get(&handle) {
-        socket.status
-    } else {
-        SocketStatus::INVALIDHANDLE
+        // clients that left the group, or the server, miss what's sent to it
+        ctx.leave_group(clients[0].1, "red");
+        ctx.close(clients[1].0);
+        assert_eq!(next_event(&mut ctx, clients[1].1), SocketResult::CLOSED);
+        ctx.broadcast(server, Some("red".to_string()), SocketMessage::Message("red again".to_string()));
+        ctx.broadcast(server, None, SocketMessage::Message("last".to_string()));
+        assert_eq!(expect_event(&mut ctx, clients[0].0, SocketResult::HASDATA), b"last");
+        assert_eq!(expect_event(&mut ctx, clients[2].0, SocketResult::HASDATA), b"last");
+        ctx.shutdown();
     }
-}
 
-#[no_mangle]
-pub extern fn pollnet_send(ctx: *mut PollnetContext, handle: u32, msg: *const c_char) {
-    let ctx = unsafe{&mut *ctx};
-    let msg = c_str_to_string(msg);
-    ctx.send(handle, msg)
-}
+    // A self-signed certificate for localhost and its PKCS#8 key, both PEM
+    fn self_signed_cert() -> (String, String) {
+        use openssl::{asn1::Asn1Time, bn::BigNum, ec, hash::MessageDigest, nid::Nid, pkey::PKey, x509};
+        let group = ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(ec::EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = x509::X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let alt_names = x509::extension::SubjectAlternativeName::new().dns("localhost").build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(alt_names).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = String::from_utf8(cert.build().to_pem().unwrap()).unwrap();
+        (cert, String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap())
+    }
 
-#[no_mangle]
-pub extern fn pollnet_send_binary(ctx: *mut PollnetContext, handle: u32, msg: *const u8, msgsize: u32) {
-    let ctx = unsafe{&mut *ctx};
-    let msg = c_data_to_vec(msg, msgsize);
-    ctx.send_binary(handle, msg)
-}
+    #[test]
+    fn tls_tcp_clients_talk_to_tls_listeners_they_trust() {
+        let (cert, key) = self_signed_cert();
+        let mut ctx = PollnetContext::new();
+        let server = ctx.listen_tcp("127.0.0.1:0".to_string(), Some((cert.clone(), key)));
+        expect_event(&mut ctx, server, SocketResult::OPENING);
+        let addr = local_addr(&ctx, server);
 
-#[no_mangle]
-pub extern fn pollnet_add_virtual_file(ctx: *mut PollnetContext, handle: u32, filename: *const c_char, filedata: *const u8, datasize: u32) {
-    let ctx = unsafe{&mut *ctx};
-    let filename = c_str_to_string(filename);
-    let filedata = c_data_to_vec(filedata, datasize);
-    ctx.add_virtual_file(handle, filename, filedata)
-}
+        // without the certificate in its CA list the client gives up
+        let tls = TlsOptions{server_name: Some("localhost".to_string()), ..TlsOptions::default()};
+        let untrusting = ctx.open_tcp(addr.clone(), Some(tls.clone()));
+        let mut result = next_event(&mut ctx, untrusting);
+        while result == SocketResult::OPENING {
+            result = next_event(&mut ctx, untrusting);
+        }
+        assert_eq!(result, SocketResult::ERROR);
+        let error = ctx.sockets[&untrusting].error.clone().unwrap_or_default();
+        assert!(error.contains("certificate"), "{}", error);
 
-#[no_mangle]
-pub extern fn pollnet_remove_virtual_file(ctx: *mut PollnetContext, handle: u32, filename: *const c_char) {
-    let ctx = unsafe{&mut *ctx};
-    let filename = c_str_to_string(filename);
-    ctx.remove_virtual_file(handle, filename)
-}
+        let client = ctx.open_tcp(addr, Some(TlsOptions{ca_pems: vec![cert], ..tls}));
+        // clients only get reported once TLS is up, so the failed one never was
+        let accepted = accept_client(&mut ctx, server);
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
+        wait_open(&mut ctx, client);
+        ctx.send(client, "over tls".to_string());
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), b"over tls");
+        ctx.send_binary(accepted, b"and back".to_vec());
+        assert_eq!(expect_event(&mut ctx, client, SocketResult::HASDATA), b"and back");
+        ctx.shutdown();
+    }
 
-#[no_mangle]
-pub extern fn pollnet_update(ctx: *mut PollnetContext, handle: u32) -> SocketResult {
-    let ctx = unsafe{&mut *ctx};
-    ctx.update(handle, false)
-}
+    #[test]
+    fn http_connections_over_the_cap_are_closed_on_accept() {
+        use std::io::{Read, Write};
+        let mut ctx = PollnetContext::new();
+        let server = ctx.serve_http("127.0.0.1:0".to_string(), None);
+        expect_event(&mut ctx, server, SocketResult::OPENING);
+        ctx.set_listener_limits(server, ListenerLimits{max_clients: 1, ..ListenerLimits::default()});
+        thread::sleep(std::time::Duration::from_millis(50));
+        let addr = local_addr(&ctx, server);
 
-#[no_mangle]
-pub extern fn pollnet_update_blocking(ctx: *mut PollnetContext, handle: u32) -> SocketResult {
-    let ctx = unsafe{&mut *ctx};
-    ctx.update(handle, true)
-}
+        let mut first = std::net::TcpStream::connect(&addr).unwrap();
+        first.write_all(b"GET /missing HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut response = [0u8; 12];
+        first.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 404");
 
-#[no_mangle]
-pub extern fn pollnet_get(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
-    let ctx = unsafe{&mut *ctx};
-    let socket = match ctx.sockets.get_mut(&handle) {
-        Some(socket) => socket,
-        None => return -1,
-    };
+        let mut second = std::net::TcpStream::connect(&addr).unwrap();
+        let rejection = expect_event(&mut ctx, server, SocketResult::REJECTED);
+        assert!(String::from_utf8_lossy(&rejection).ends_with("too many clients"));
+        // closed without an answer rather than served a 503
+        second.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        second.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_or_default();
+        assert_eq!(second.read(&mut response).unwrap_or(0), 0);
+        ctx.shutdown();
+    }
+    // Frames the way an independent peer writes them
+    fn raw_frame(first: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(idx, byte)| byte ^ mask[idx % 4]));
+            },
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
 
-    match socket.message.take() {
-        Some(msg) => {
-            let ncopy = msg.len();
-            if ncopy < (dest_size as usize) {
-                unsafe {
-                    std::ptr::copy_nonoverlapping(msg.as_ptr(), dest, ncopy);
-                }
-                ncopy as i32
-            } else {
-                0
-            }
-        },
-        None => 0,
+    // The first byte and the unmasked payload
+    fn read_raw_frame(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+        use std::io::Read;
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).unwrap();
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            },
+            len => len as usize,
+        };
+        let mut mask = [0u8; 4];
+        if head[1] & 0x80 != 0 {
+            stream.read_exact(&mut mask).unwrap();
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+        payload.iter_mut().enumerate().for_each(|(idx, byte)| *byte ^= mask[idx % 4]);
+        (head[0], payload)
     }
-}
 
-#[no_mangle]
-pub extern fn pollnet_get_connected_client_handle(ctx: *mut PollnetContext, handle: u32) -> u32 {
-    let ctx = unsafe{&mut *ctx};
-    match ctx.sockets.get_mut(&handle) {
-        Some(socket) => socket.last_client_handle,
-        None => 0,
+    fn read_http_head(stream: &mut std::net::TcpStream) -> String {
+        use std::io::Read;
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
     }
-}
 
-#[no_mangle]
-pub extern fn pollnet_get_error(ctx: *mut PollnetContext, handle: u32, dest: *mut u8, dest_size: u32) -> i32 {
-    let ctx = unsafe{&mut *ctx};
-    let socket = match ctx.sockets.get_mut(&handle) {
-        Some(socket) => socket,
-        None => return -1,
-    };
+    // One part of a compressed message, laid out as RFC 7692 has it
+    fn compress_part(compress: &mut flate2::Compress, data: &[u8], fin: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compress.compress_vec(data, &mut out, flate2::FlushCompress::Sync).unwrap();
+        assert!(out.ends_with(&DEFLATE_TAIL));
+        if fin {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        out
+    }
 
-    match socket.error.take() {
-        Some(msg) => {
-            let ncopy = msg.len();
-            if ncopy < (dest_size as usize) {
-                unsafe {
-                    std::ptr::copy_nonoverlapping(msg.as_ptr(), dest, ncopy);
-                }
-                ncopy as i32
-            } else {
-                0
-            }
-        },
-        None => 0,
+    fn inflate_message(decompress: &mut flate2::Decompress, payload: &[u8]) -> Vec<u8> {
+        let mut data = payload.to_vec();
+        data.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity(64 << 10);
+        decompress.decompress_vec(&data, &mut out, flate2::FlushDecompress::Sync).unwrap();
+        out
     }
-}
 
-static mut HACKSTATICCONTEXT: *mut PollnetContext = 0 as *mut PollnetContext;
+    #[test]
+    fn ws_deflate_reads_fragments_around_pings_and_keeps_context() {
+        use std::io::Write;
+        let mut ctx = PollnetContext::new();
+        ctx.ws_compression = WsCompression{enabled: true, window_bits: 15};
+        let server = ctx.listen_ws("127.0.0.1:0".to_string());
+        expect_event(&mut ctx, server, SocketResult::OPENING);
+        let mut peer = std::net::TcpStream::connect(local_addr(&ctx, server)).unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        peer.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n").unwrap();
+        let response = read_http_head(&mut peer);
+        assert!(response.starts_with("HTTP/1.1 101") && response.contains("permessage-deflate"), "{}", response);
+        let accepted = accept_client(&mut ctx, server);
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
 
-#[no_mangle]
-pub unsafe extern fn pollnet_get_or_init_static() -> *mut PollnetContext {
-    if HACKSTATICCONTEXT.is_null() {
-        warn!("INITIALIZING HACK STATIC CONTEXT");
-        HACKSTATICCONTEXT = Box::into_raw(Box::new(PollnetContext::new()))
+        let mask = Some([1, 2, 3, 4]);
+        let text = "the same old state ".repeat(50);
+        let (head, tail) = text.split_at(300);
+        let mut compress = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut out = raw_frame(0x41, &compress_part(&mut compress, head.as_bytes(), false), mask);
+        out.extend(raw_frame(0x89, b"still there?", mask));
+        out.extend(raw_frame(0x80, &compress_part(&mut compress, tail.as_bytes(), true), mask));
+        // the second time round the whole message refers back to the first
+        let again = compress_part(&mut compress, text.as_bytes(), true);
+        assert!(again.len() < 20);
+        out.extend(raw_frame(0xc1, &again, mask));
+        peer.write_all(&out).unwrap();
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), text.as_bytes());
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), text.as_bytes());
+        assert_eq!(read_raw_frame(&mut peer), (0x8a, b"still there?".to_vec()));
+
+        // the client didn't ask for no_context_takeover, so the server keeps its context too
+        let reply = "a reply with plenty of repetition ".repeat(20);
+        ctx.send(accepted, reply.clone());
+        ctx.send(accepted, reply.clone());
+        let mut decompress = flate2::Decompress::new(false);
+        let (first_byte, first) = read_raw_frame(&mut peer);
+        assert_eq!(first_byte, 0xc1);
+        assert_eq!(inflate_message(&mut decompress, &first), reply.as_bytes());
+        let (_, second) = read_raw_frame(&mut peer);
+        assert!(second.len() < first.len() / 2);
+        assert_eq!(inflate_message(&mut decompress, &second), reply.as_bytes());
+        ctx.shutdown();
     }
-    HACKSTATICCONTEXT
-}
 
+    #[test]
+    fn ws_deflate_clients_inflate_frames_right_behind_the_handshake() {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ctx = PollnetContext::new();
+        ctx.ws_compression = WsCompression{enabled: true, window_bits: 15};
+        let client = ctx.open_ws(format!("ws://{}/", listener.local_addr().unwrap()), String::new(), String::new());
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let request = read_http_head(&mut peer);
+        assert!(request.contains("permessage-deflate"), "{}", request);
+        let mut parsed = http::Request::builder().uri("/");
+        for (name, value) in request.lines().skip(1).filter_map(|line| line.split_once(':')) {
+            parsed = parsed.header(name.trim(), value.trim());
+        }
+        let answer = tungstenite::handshake::server::create_response(&parsed.body(()).unwrap()).unwrap();
 
-#[no_mangle]
-pub extern fn pollnet_get_nanoid(dest: *mut u8, dest_size: u32) -> i32 {
-    let id = nanoid::nanoid!();
-    if id.len() < (dest_size as usize) {
-        unsafe {
-            std::ptr::copy_nonoverlapping(id.as_ptr(), dest, id.len());
+        // the response and a compressed message go out in one write
+        let text = "sent right behind the handshake ".repeat(10);
+        let mut compress = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut out = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+            answer.headers()["Sec-WebSocket-Accept"].to_str().unwrap()).into_bytes();
+        out.extend(raw_frame(0xc1, &compress_part(&mut compress, text.as_bytes(), true), None));
+        peer.write_all(&out).unwrap();
+        wait_open(&mut ctx, client);
+        assert_eq!(expect_event(&mut ctx, client, SocketResult::HASDATA), text.as_bytes());
+
+        ctx.send_binary(client, vec![7; 1000]);
+        ctx.send_binary(client, vec![7; 1000]);
+        let mut decompress = flate2::Decompress::new(false);
+        for _ in 0..2 {
+            let (first_byte, payload) = read_raw_frame(&mut peer);
+            assert_eq!(first_byte, 0xc2);
+            assert!(payload.len() < 100);
+            assert_eq!(inflate_message(&mut decompress, &payload), vec![7; 1000]);
         }
-        id.len() as i32
-    } else {
-        0
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn ws_deflate_interoperates_with_soketto() {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        let mut ctx = PollnetContext::new();
+        ctx.ws_compression = WsCompression{enabled: true, window_bits: 15};
+        let server = ctx.listen_ws("127.0.0.1:0".to_string());
+        expect_event(&mut ctx, server, SocketResult::OPENING);
+        let addr = local_addr(&ctx, server);
+        let peer = thread::spawn(move || run(async move {
+            let socket = TcpStream::connect(&addr).await.unwrap();
+            let mut client = soketto::handshake::Client::new(socket.compat(), "localhost", "/");
+            client.add_extension(Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Client)));
+            let response = client.handshake().await.unwrap();
+            assert!(matches!(response, soketto::handshake::ServerResponse::Accepted{..}));
+            let extensions: Vec<_> = client.drain_extensions().collect();
+            assert!(extensions.iter().all(|extension| extension.is_enabled()));
+            let mut builder = client.into_builder();
+            builder.add_extensions(extensions);
+            let (mut sender, mut receiver) = builder.finish();
+            sender.send_text("hello ".repeat(100)).await.unwrap();
+            sender.send_binary(vec![1; 4000]).await.unwrap();
+            sender.flush().await.unwrap();
+            let mut replies = Vec::new();
+            for _ in 0..2 {
+                let mut data = Vec::new();
+                receiver.receive_data(&mut data).await.unwrap();
+                replies.push(data);
+            }
+            replies
+        }));
+        let accepted = accept_client(&mut ctx, server);
+        assert_eq!(next_event(&mut ctx, accepted), SocketResult::OPENING);
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), "hello ".repeat(100).as_bytes());
+        assert_eq!(expect_event(&mut ctx, accepted, SocketResult::HASDATA), vec![1; 4000]);
+        let reply = "goodbye ".repeat(100);
+        ctx.send(accepted, reply.clone());
+        ctx.send(accepted, reply.clone());
+        assert_eq!(peer.join().unwrap(), vec![reply.into_bytes(); 2]);
+        ctx.shutdown();
     }
-}
\ No newline at end of file
+
+}